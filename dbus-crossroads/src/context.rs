@@ -18,8 +18,11 @@ pub struct Context {
     reply: Option<dbus::Message>,
     send_extra: Vec<dbus::Message>,
     send_on_drop: Option<Dbg<Arc<dyn Sender + Send + Sync>>>,
+    error_hook: Option<Dbg<ErrorHook>>,
 }
 
+pub (crate) type ErrorHook = Arc<dyn Fn(&dbus::Message, &MethodErr) + Send + Sync + 'static>;
+
 impl Context {
     /// Creates a new Context.
     ///
@@ -36,6 +39,7 @@ impl Context {
             message: msg,
             reply: None,
             send_on_drop: None,
+            error_hook: None,
             send_extra: vec!(),
             has_error: false,
         })
@@ -80,6 +84,7 @@ impl Context {
     /// Replies to the incoming message with an error.
     pub (crate) fn reply_err(&mut self, err: MethodErr) {
         self.has_error = true;
+        if let Some(hook) = &self.error_hook { (hook.0)(&self.message, &err) }
         if !self.message.get_no_reply() {
             self.reply = Some(err.to_message(&self.message))
         };
@@ -139,6 +144,10 @@ impl Context {
     /// Returns true is "reply_err" has been called, or "check" ever returned an error
     pub fn has_error(&self) -> bool { self.has_error }
 
+    pub (crate) fn set_error_hook(&mut self, value: Option<ErrorHook>) {
+        self.error_hook = value.map(Dbg);
+    }
+
     pub (crate) fn set_send_on_drop(&mut self, value: Arc<dyn Sender + Send + Sync>) {
         self.send_on_drop = Some(Dbg(value));
     }
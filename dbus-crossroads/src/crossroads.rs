@@ -3,16 +3,18 @@ use std::sync::Arc;
 use dbus::channel::Sender;
 use std::future::Future;
 use std::marker::PhantomData;
-use crate::{Context, MethodErr, IfaceBuilder, stdimpl};
+use crate::{Context, MethodErr, IfaceBuilder, EmitsChangedSignal, stdimpl};
+use crate::context::ErrorHook;
 use crate::ifacedesc::Registry;
-use std::collections::{BTreeMap, HashSet};
-use std::any::Any;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::any::{Any, TypeId};
 use std::fmt;
 use crate::utils::Dbg;
 
 const INTROSPECTABLE: usize = 0;
 const PROPERTIES: usize = 1;
 const OBJECT_MANAGER: usize = 2;
+const PEER: usize = 3;
 
 /// Contains a reference to a registered interface.
 pub struct IfaceToken<T: Send + 'static>(usize, PhantomData<&'static T>);
@@ -37,6 +39,32 @@ impl<T: Send + 'static> fmt::Debug for IfaceToken<T> {
     }
 }
 
+/// Marker for the data type of interfaces registered with `Crossroads::register_shared`.
+///
+/// The handlers of such an interface work on a `D`, typically a trait object, rather than on the
+/// data of a specific path.
+pub struct Shared<D: ?Sized + 'static>(PhantomData<fn(&mut D)>);
+
+impl<D: ?Sized + 'static> fmt::Debug for Shared<D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "Shared") }
+}
+
+/// Information about a method call, given to the hook set with `Crossroads::set_trace_hook`.
+#[cfg(feature = "trace")]
+#[derive(Debug, Clone)]
+pub struct TraceInfo {
+    pub path: dbus::Path<'static>,
+    pub interface: Option<dbus::strings::Interface<'static>>,
+    pub member: dbus::strings::Member<'static>,
+    /// None when the method call is about to be dispatched, and the time spent dispatching
+    /// it when it has been handled.
+    pub elapsed: Option<std::time::Duration>,
+}
+
+#[cfg(feature = "trace")]
+type TraceHook = Box<dyn Fn(&TraceInfo) + Send + 'static>;
+
+type Cast<D> = Box<dyn Fn(&mut (dyn Any + Send)) -> Option<&mut D> + Send + 'static>;
 
 #[derive(Debug)]
 struct Object {
@@ -70,6 +98,13 @@ pub struct Crossroads {
     add_standard_ifaces: bool,
     async_support: Option<AsyncSupport>,
     object_manager_support: Option<Dbg<Arc<dyn Sender + Send + Sync + 'static>>>,
+    introspect_cache: HashMap<dbus::Path<'static>, String>,
+    error_hook: Option<Dbg<ErrorHook>>,
+    casts: HashMap<(TypeId, TypeId), Dbg<Box<dyn Any + Send + 'static>>>,
+    default_emits_changed: Option<EmitsChangedSignal>,
+    default_auto_emit: bool,
+    #[cfg(feature = "trace")]
+    trace_hook: Option<Dbg<TraceHook>>,
 }
 
 impl Crossroads {
@@ -81,36 +116,88 @@ impl Crossroads {
             add_standard_ifaces: true,
             async_support: None,
             object_manager_support: None,
+            introspect_cache: Default::default(),
+            error_hook: None,
+            casts: Default::default(),
+            default_emits_changed: None,
+            default_auto_emit: true,
+            #[cfg(feature = "trace")]
+            trace_hook: None,
         };
         let t0 = stdimpl::introspectable(&mut cr);
         let t1 = stdimpl::properties(&mut cr);
         let t2 = stdimpl::object_manager(&mut cr);
+        let t3 = stdimpl::peer(&mut cr);
         debug_assert_eq!(t0.0, INTROSPECTABLE);
         debug_assert_eq!(t1.0, PROPERTIES);
         debug_assert_eq!(t2.0, OBJECT_MANAGER);
+        debug_assert_eq!(t3.0, PEER);
 
         // Add the root path and make it introspectable. This helps D-Bus debug tools
         cr.insert("/", &[], ());
         cr
     }
 
-    /// If set to true (the default), will make paths implement the standard "Introspectable" and,
-    /// if the path has interfaces with properties, the "Properties" interfaces.
+    /// If set to true (the default), will make paths implement the standard "Introspectable" and
+    /// "Peer" interfaces and, if the path has interfaces with properties, the "Properties" interface.
     pub fn set_add_standard_ifaces(&mut self, enable: bool) {
         self.add_standard_ifaces = enable;
     }
 
+    /// Sets how properties of interfaces registered after this call emit PropertiesChanged,
+    /// unless a property says otherwise. None (the default) leaves properties without
+    /// an EmitsChangedSignal annotation, which means the same as `EmitsChangedSignal::True`.
+    pub fn set_default_emits_changed(&mut self, mode: Option<EmitsChangedSignal>) {
+        self.default_emits_changed = mode;
+    }
+
+    /// Sets whether properties of interfaces registered after this call emit PropertiesChanged
+    /// automatically when set, unless a property says otherwise. Defaults to true.
+    pub fn set_default_auto_emit(&mut self, enable: bool) {
+        self.default_auto_emit = enable;
+    }
+
     /// Registers a new interface into the interface registry. The closure receives an
     /// IfaceBuilder that you can add methods, signals and properties to.
     pub fn register<T, N, F>(&mut self, name: N, f: F) -> IfaceToken<T>
     where T: Send + 'static, N: Into<dbus::strings::Interface<'static>>,
     F: FnOnce(&mut IfaceBuilder<T>)
     {
-        let iface = IfaceBuilder::build(Some(name.into()), f);
+        let iface = IfaceBuilder::build(Some(name.into()), self.default_emits_changed, self.default_auto_emit, f);
         let x = self.registry.push(iface);
         IfaceToken(x, PhantomData)
     }
 
+    /// Registers a new interface that can be added to paths with different data types.
+    ///
+    /// Handlers added with `IfaceBuilder::shared_method` get the data of the path as a `D`.
+    /// Call `share` to get a token for each data type.
+    pub fn register_shared<D, N, F>(&mut self, name: N, f: F) -> IfaceToken<Shared<D>>
+    where D: ?Sized + 'static, N: Into<dbus::strings::Interface<'static>>,
+    F: FnOnce(&mut IfaceBuilder<Shared<D>>)
+    {
+        self.register(name, f)
+    }
+
+    /// Makes paths with data of type T available to the handlers of a shared interface,
+    /// and returns the token to use when inserting these paths.
+    pub fn share<T, D>(&mut self, token: IfaceToken<Shared<D>>, cast: fn(&mut T) -> &mut D) -> IfaceToken<T>
+    where T: Any + Send + 'static, D: ?Sized + 'static {
+        let c: Cast<D> = Box::new(move |data| data.downcast_mut::<T>().map(cast));
+        self.casts.insert((TypeId::of::<D>(), TypeId::of::<T>()), Dbg(Box::new(c)));
+        IfaceToken(token.0, PhantomData)
+    }
+
+    /// Access the data of a certain path, as made available to shared interfaces by `share`.
+    ///
+    /// Will return none both if the path was not found, and if the data's type was not shared as a D.
+    pub fn shared_data_mut<D: ?Sized + 'static>(&mut self, name: &dbus::Path<'static>) -> Option<&mut D> {
+        let obj = self.map.get_mut(name)?;
+        let cast = self.casts.get(&(TypeId::of::<D>(), (*obj.data).type_id()))?;
+        let cast: &Cast<D> = cast.0.downcast_ref()?;
+        cast(&mut *obj.data)
+    }
+
     /// Access the data of a certain path.
     ///
     /// Will return none both if the path was not found, and if the found data was of another type.
@@ -129,11 +216,13 @@ impl Crossroads {
         let mut ifaces: HashSet<usize> = std::iter::FromIterator::from_iter(ifaces);
         if self.add_standard_ifaces {
             ifaces.insert(INTROSPECTABLE);
+            ifaces.insert(PEER);
             if ifaces.iter().any(|u| self.registry().has_props(*u)) {
                 ifaces.insert(PROPERTIES);
             }
         }
         let name = name.into();
+        self.invalidate_introspection(&name);
         self.map.insert(name.clone(), Object { ifaces, data: Box::new(data)});
         if let Some(oms) = self.object_manager_support.as_ref() {
             stdimpl::object_manager_path_added(oms.0.clone(), &name, self);
@@ -145,6 +234,42 @@ impl Crossroads {
         self.map.get(name).map(|x| x.ifaces.contains(&token.0)).unwrap_or(false)
     }
 
+    /// Iterates over the names of the interfaces the path implements, in no particular order.
+    ///
+    /// This includes standard interfaces such as "org.freedesktop.DBus.Introspectable".
+    pub fn interfaces_at<'a>(&'a self, name: &dbus::Path<'static>) -> impl Iterator<Item = &'a dbus::strings::Interface<'static>> + 'a {
+        let reg = &self.registry;
+        self.map.get(name).into_iter().flat_map(move |x| x.ifaces.iter().filter_map(move |&t| reg.get_intf_name(t)))
+    }
+
+    /// Iterates over all inserted paths, in sorted order.
+    pub fn object_paths(&self) -> impl Iterator<Item = &dbus::Path<'static>> {
+        self.map.keys()
+    }
+
+    /// Creates a signal message for a signal declared with IfaceBuilder::signal.
+    ///
+    /// Returns an error if the path does not implement the interface, if the signal
+    /// is not declared, or if the arguments do not match the declared signal arguments.
+    pub fn emit_signal<D, A, N>(&self, path: &dbus::Path<'static>, token: IfaceToken<D>, name: N, args: A) -> Result<dbus::Message, MethodErr>
+    where D: Send + 'static, A: dbus::arg::AppendAll + dbus::arg::ArgAll, A::strs: Default,
+    N: Into<dbus::strings::Member<'static>> {
+        let obj = self.map.get(path).ok_or_else(|| MethodErr::no_path(path))?;
+        let iname = self.registry.get_intf_name(token.0).ok_or_else(|| MethodErr::no_interface(""))?;
+        if !obj.ifaces.contains(&token.0) { Err(MethodErr::no_interface(iname))? }
+        let name = name.into();
+        let sig = self.registry.signal_sig(token.0, &name)
+            .ok_or_else(|| MethodErr::failed(&format!("Unknown signal {}", name)))?;
+        let mut argsig = String::new();
+        A::strs_sig(Default::default(), |_, s| argsig.push_str(&s));
+        if argsig != sig {
+            Err(MethodErr::invalid_arg(&format!("Signal {} has signature '{}', not '{}'", name, sig, argsig)))?
+        }
+        let mut msg = dbus::Message::signal(path, iname, &name);
+        msg.append_all(args);
+        Ok(msg)
+    }
+
     /// Removes an existing path.
     ///
     /// Returns None if the path was not found.
@@ -156,6 +281,7 @@ impl Crossroads {
                 stdimpl::object_manager_path_removed(oms.0.clone(), &name, self);
             }
         }
+        self.invalidate_introspection(name);
         let x = self.map.remove(name)?;
         let r: Box<D> = x.data.downcast().ok()?;
         Some(*r)
@@ -169,8 +295,39 @@ impl Crossroads {
         self.registry.find_token(interface, &obj.ifaces)
     }
 
+    fn find_method_iface_token(&self,
+        path: &dbus::Path<'static>,
+        interface: Option<&dbus::strings::Interface<'static>>,
+        method: &dbus::strings::Member<'static>)
+    -> Result<usize, MethodErr> {
+        if interface.is_some() { return self.find_iface_token(path, interface) }
+        let obj = self.map.get(path).ok_or_else(|| MethodErr::no_path(path))?;
+        self.registry.find_method_token(method, &obj.ifaces)
+    }
+
     pub (crate) fn registry(&mut self) -> &mut Registry { &mut self.registry }
 
+    /// Returns the cached introspection data for the path, generating it if needed.
+    pub (crate) fn introspect_cached<F: FnOnce(&Crossroads) -> String>(&mut self, path: &dbus::Path<'static>, f: F) -> String {
+        if let Some(x) = self.introspect_cache.get(path) { return x.clone() }
+        let x = f(self);
+        self.introspect_cache.insert(path.clone(), x.clone());
+        x
+    }
+
+    /// Drops the cached introspection data for the path, and for its parents, since their
+    /// list of child nodes might have changed.
+    fn invalidate_introspection(&mut self, path: &dbus::Path<'static>) {
+        if self.introspect_cache.is_empty() { return }
+        self.introspect_cache.remove(path);
+        let mut p: &str = path;
+        while let Some(idx) = p.rfind('/') {
+            p = if idx == 0 { "/" } else { &p[..idx] };
+            self.introspect_cache.remove(&dbus::Path::from(p.to_string()));
+            if idx == 0 { break }
+        }
+    }
+
     pub (crate) fn registry_and_ifaces(&self, path: &dbus::Path<'static>)
     -> (&Registry, &HashSet<usize>) {
         let obj = self.map.get(path).unwrap();
@@ -205,7 +362,7 @@ impl Crossroads {
 
     fn handle_message_inner(&mut self, mut ctx: Context) -> Option<Context> {
         let (itoken, mut cb) = match ctx.check(|ctx| {
-            let itoken = self.find_iface_token(ctx.path(), ctx.interface())?;
+            let itoken = self.find_method_iface_token(ctx.path(), ctx.interface(), ctx.method())?;
             let cb = self.registry.take_method(itoken, ctx.method())?;
             Ok((itoken, cb))
         }) {
@@ -223,8 +380,26 @@ impl Crossroads {
     ///
     /// Returns Err if the message is not a method call.
     pub fn handle_message<S: dbus::channel::Sender>(&mut self, message: dbus::Message, conn: &S) -> Result<(), ()> {
-        let ctx = Context::new(message).ok_or(())?;
-        if let Some(mut ctx) = self.handle_message_inner(ctx) {
+        let mut ctx = Context::new(message).ok_or(())?;
+        ctx.set_error_hook(self.error_hook.as_ref().map(|x| x.0.clone()));
+        #[cfg(feature = "trace")]
+        let trace = self.trace_hook.as_ref().map(|hook| {
+            let info = TraceInfo {
+                path: ctx.path().clone(),
+                interface: ctx.interface().cloned(),
+                member: ctx.method().clone(),
+                elapsed: None,
+            };
+            (hook.0)(&info);
+            (info, std::time::Instant::now())
+        });
+        let ctx = self.handle_message_inner(ctx);
+        #[cfg(feature = "trace")]
+        if let (Some((mut info, start)), Some(hook)) = (trace, self.trace_hook.as_ref()) {
+            info.elapsed = Some(start.elapsed());
+            (hook.0)(&info);
+        }
+        if let Some(mut ctx) = ctx {
             let _ = ctx.flush_messages(conn);
         }
         Ok(())
@@ -236,6 +411,9 @@ impl Crossroads {
     /// The token representing the built-in implementation of "org.freedesktop.DBus.Properties".
     pub fn properties<T: Send + 'static>(&self) -> IfaceToken<T> { IfaceToken(PROPERTIES, PhantomData) }
 
+    /// The token representing the built-in implementation of "org.freedesktop.DBus.Peer".
+    pub fn peer<T: Send + 'static>(&self) -> IfaceToken<T> { IfaceToken(PEER, PhantomData) }
+
     /// The token representing the built-in implementation of "org.freedesktop.DBus.ObjectManager".
     ///
     /// You can add this to a path without enabling "set_object_manager_support", but no signals will
@@ -255,6 +433,26 @@ impl Crossroads {
         a.map(|x| (x.sender, x.spawner))
     }
 
+    /// Sets a callback that is called with the method call and the error, whenever a method call
+    /// fails. This includes errors returned from method handlers, as well as errors such as
+    /// unknown methods or invalid arguments.
+    ///
+    /// The callback can be used for logging, but cannot change or suppress the error reply.
+    pub fn set_error_hook<F>(&mut self, f: F)
+    where F: Fn(&dbus::Message, &MethodErr) + Send + Sync + 'static {
+        self.error_hook = Some(Dbg(Arc::new(f)));
+    }
+
+    /// Sets a callback that is called when a method call is about to be dispatched, and again
+    /// when it has been handled, together with the time spent.
+    ///
+    /// For async methods, the time spent only covers the part before the method was spawned.
+    #[cfg(feature = "trace")]
+    pub fn set_trace_hook<F>(&mut self, f: F)
+    where F: Fn(&TraceInfo) + Send + 'static {
+        self.trace_hook = Some(Dbg(Box::new(f)));
+    }
+
     /// Enables this crossroads instance to send signals when paths are added and removed.
     ///
     /// The added/removed path is a subpath of a path which implements an object manager instance.
@@ -1,7 +1,8 @@
 use std::future::Future;
 use std::marker::PhantomData;
-use crate::{Context, PropContext, MethodErr, Crossroads, utils::Dbg};
-use std::collections::{HashMap, HashSet};
+use crate::{Context, PropContext, MethodErr, Crossroads, Shared, utils::Dbg};
+use crate::info::{IfaceInfoSkeleton, MethodInfo, SignalInfo, PropInfo, ArgInfo, Access, EmitsChangedSignal};
+use std::collections::{HashMap, HashSet, BTreeMap};
 use std::fmt;
 use std::borrow::Cow;
 use dbus::arg;
@@ -23,6 +24,12 @@ impl Registry {
         Err(name.map(MethodErr::no_interface).unwrap_or_else(|| MethodErr::no_interface("")))
     }
 
+    /// Finds an interface implementing the method, for method calls without an interface.
+    pub fn find_method_token(&self, name: &dbus::strings::Member, tokens: &HashSet<usize>) -> Result<usize, MethodErr> {
+        tokens.iter().copied().filter(|&t| self.0[t].methods.contains_key(name)).min()
+            .ok_or_else(|| MethodErr::no_method(name))
+    }
+
     pub fn take_method(&mut self, t: usize, name: &dbus::strings::Member<'static>) -> Result<Callback, MethodErr> {
         let mdesc = self.0[t].methods.get_mut(name).ok_or_else(|| MethodErr::no_method(name))?;
         let cb = mdesc.cb.take();
@@ -43,9 +50,12 @@ impl Registry {
 
     pub fn take_prop(&mut self, t: usize, name: &str, is_set: bool) -> Result<PropCb, MethodErr> {
         let pdesc = self.0[t].properties.get_mut(name).ok_or_else(|| MethodErr::no_property(name))?;
-        let cb = if is_set { pdesc.set_cb.take() } else { pdesc.get_cb.take() };
-        let rw = if is_set { "writable" } else { "readable" };
-        let cb = cb.ok_or_else(|| MethodErr::failed(&format!("Property {} is not {}", name, rw)))?;
+        let cb = if is_set {
+            pdesc.set_cb.take().ok_or_else(|| MethodErr::ro_property(name))?
+        } else {
+            pdesc.get_cb.take().ok_or_else(|| MethodErr::from(("org.freedesktop.DBus.Error.PropertyWriteOnly",
+                format!("Property {} is write only", name))))?
+        };
         Ok(cb.0)
     }
 
@@ -57,6 +67,10 @@ impl Registry {
 
     pub fn has_props(&self, t: usize) -> bool { !self.0[t].properties.is_empty() }
 
+    pub fn auto_emit(&self, t: usize, name: &str) -> bool {
+        self.0[t].properties.get(name).map(|p| p.auto_emit).unwrap_or(true)
+    }
+
     pub fn find_annotation(&self, t: usize, annotation_name: &str, prop_name: Option<&str>) -> Option<&str> {
         let desc = &self.0[t];
         if let Some(prop_name) = prop_name {
@@ -70,60 +84,20 @@ impl Registry {
     }
 
     pub fn introspect(&self, ifaces: &HashSet<usize>) -> String {
-        let mut v: Vec<_> = ifaces.iter().filter_map(|&t| self.0[t].name.as_ref().map(|n| (n, t))).collect();
-        v.sort_unstable();
-        let mut r = String::new();
-        for (n, t) in v.into_iter() {
-            r += &format!("  <interface name=\"{}\">\n", n);
-            let desc = &self.0[t];
-
-            let mut v2: Vec<_> = desc.methods.keys().collect();
-            v2.sort_unstable();
-            for name in v2.into_iter() {
-                r += &format!("    <method name=\"{}\">\n", name);
-                let x = &desc.methods[name];
-                r += &x.input_args.introspect(Some("in"), "      ");
-                r += &x.output_args.introspect(Some("out"), "      ");
-                r += &x.annotations.introspect("      ");
-                r += "    </method>\n";
-            }
-
-            let mut v2: Vec<_> = desc.signals.keys().collect();
-            v2.sort_unstable();
-            for name in v2.into_iter() {
-                r += &format!("    <signal name=\"{}\">\n", name);
-                let x = &desc.signals[name];
-                r += &x.args.introspect(None, "      ");
-                r += &x.annotations.introspect("      ");
-                r += "    </signal>\n";
-            }
-
-            let mut v2: Vec<_> = desc.properties.keys().collect();
-            v2.sort_unstable();
-            for name in v2.into_iter() {
-                let x = &desc.properties[name];
-                let a = match (x.get_cb.is_some(), x.set_cb.is_some()) {
-                    (true, true) => "readwrite",
-                    (true, false) => "read",
-                    (false, true) => "write",
-                    _ => unreachable!(),
-                };
-                r += &format!("    <property name=\"{}\" type=\"{}\" access=\"{}\"", name, x.sig, a);
-                if x.annotations.is_empty() {
-                    r += "/>\n";
-                } else {
-                    r += &format!(">\n{}    </property>\n",  x.annotations.introspect("      "));
-                }
-            }
-            desc.annotations.introspect("    ");
-            r += "  </interface>\n";
-        };
-        r
+        let mut v: Vec<_> = ifaces.iter().filter_map(|&t| self.0[t].skeleton()).collect();
+        v.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        v.iter().map(|x| x.introspect()).collect()
     }
 
     pub fn get_intf_name(&self, t: usize) -> Option<&dbus::strings::Interface<'static>> {
         self.0.get(t)?.name.as_ref()
     }
+
+    /// The signature of all arguments of a signal, or None if the signal does not exist.
+    pub fn signal_sig(&self, t: usize, name: &dbus::strings::Member) -> Option<String> {
+        let desc = self.0.get(t)?.signals.get(name)?;
+        Some(desc.args.0.iter().map(|a| &*a.sig).collect())
+    }
 }
 
 pub type Callback = Box<dyn FnMut(Context, &mut Crossroads) -> Option<Context> + Send + 'static>;
@@ -136,7 +110,7 @@ impl fmt::Debug for CallbackDbg {
 }
 
 #[derive(Debug, Clone, Default)]
-pub struct Annotations(Option<HashMap<String, String>>);
+pub struct Annotations(Option<BTreeMap<String, String>>);
 
 impl Annotations {
     pub fn insert<K: Into<String>, V: Into<String>>(&mut self, k: K, v: V) {
@@ -149,19 +123,7 @@ impl Annotations {
         self.0.as_ref()?.get(key).map(|x| &**x)
     }
 
-    fn is_empty(&self) -> bool {
-        self.0.as_ref().map(|s| s.len()).unwrap_or(0) == 0
-    }
-
-    fn introspect(&self, prefix: &str) -> String {
-        let mut r = String::new();
-        if let Some(anns) = &self.0 {
-            for (k, v) in anns.iter() {
-                r += &format!("{}<annotation name=\"{}\" value=\"{}\"/>\n", prefix, k, v);
-            }
-        }
-        r
-    }
+    fn to_map(&self) -> BTreeMap<String, String> { self.0.clone().unwrap_or_default() }
 }
 
 #[derive(Debug, Clone)]
@@ -175,19 +137,12 @@ struct Argument {
 pub struct Arguments(Vec<Argument>);
 
 impl Arguments {
-    fn introspect(&self, dir: Option<&str>, prefix: &str) -> String {
-        let mut r = String::new();
-        for a in &self.0 {
-            r += &format!("{}<arg name=\"{}\" type=\"{}\"", prefix, a.name, a.sig);
-            if let Some(dir) = dir { r += &format!(" direction=\"{}\"", dir); }
-            if a.annotations.is_empty() {
-                r += "/>\n";
-            } else {
-                let inner_prefix = format!("{}  ", prefix);
-                r += &format!(">\n{}{}</arg>\n", a.annotations.introspect(&inner_prefix), prefix);
-            }
-        }
-        r
+    fn skeleton(&self) -> Vec<ArgInfo> {
+        self.0.iter().map(|a| ArgInfo {
+            name: a.name.clone().into_owned(),
+            sig: a.sig.clone(),
+            annotations: a.annotations.to_map(),
+        }).collect()
     }
 }
 
@@ -232,6 +187,7 @@ pub struct PropDesc {
     sig: dbus::Signature<'static>,
     get_cb: Option<Dbg<PropCb>>,
     set_cb: Option<Dbg<PropCb>>,
+    auto_emit: bool,
 }
 
 #[derive(Debug)]
@@ -243,6 +199,43 @@ pub struct IfaceDesc {
     properties: HashMap<String, PropDesc>,
 }
 
+impl IfaceDesc {
+    /// Describes this interface without handlers, or returns None if it has no name.
+    fn skeleton(&self) -> Option<IfaceInfoSkeleton> {
+        let mut r = IfaceInfoSkeleton::new(self.name.clone()?);
+        let mut v: Vec<_> = self.methods.iter().collect();
+        v.sort_unstable_by_key(|x| x.0);
+        r.methods = v.into_iter().map(|(name, x)| MethodInfo {
+            name: name.clone(),
+            input_args: x.input_args.skeleton(),
+            output_args: x.output_args.skeleton(),
+            annotations: x.annotations.to_map(),
+        }).collect();
+        let mut v: Vec<_> = self.signals.iter().collect();
+        v.sort_unstable_by_key(|x| x.0);
+        r.signals = v.into_iter().map(|(name, x)| SignalInfo {
+            name: name.clone(),
+            args: x.args.skeleton(),
+            annotations: x.annotations.to_map(),
+        }).collect();
+        let mut v: Vec<_> = self.properties.iter().collect();
+        v.sort_unstable_by_key(|x| x.0);
+        r.properties = v.into_iter().map(|(name, x)| PropInfo {
+            name: name.clone(),
+            sig: x.sig.clone(),
+            access: match (x.get_cb.is_some(), x.set_cb.is_some()) {
+                (true, true) => Access::ReadWrite,
+                (true, false) => Access::Read,
+                (false, true) => Access::Write,
+                _ => unreachable!(),
+            },
+            annotations: x.annotations.to_map(),
+        }).collect();
+        r.annotations = self.annotations.to_map();
+        Some(r)
+    }
+}
+
 fn build_argvec<A: arg::ArgAll>(a: A::strs) -> Arguments {
     let mut v = vec!();
     A::strs_sig(a, |name, sig| {
@@ -404,6 +397,14 @@ impl<T: std::marker::Send, A> PropBuilder<'_, T, A> {
     pub fn emits_changed_const(self) -> Self { self.annotate(EMITS_CHANGED, "const") }
     pub fn emits_changed_invalidates(self) -> Self { self.annotate(EMITS_CHANGED, "invalidates") }
     pub fn emits_changed_true(self) -> Self { self.annotate(EMITS_CHANGED, "true") }
+    /// Sets how this property emits PropertiesChanged, like the `emits_changed_*` methods.
+    pub fn emits_changed(self, e: EmitsChangedSignal) -> Self { self.annotate(EMITS_CHANGED, e.as_str()) }
+    /// Do not emit PropertiesChanged after this property is set, even if the set handler returns
+    /// a value. The EmitsChangedSignal annotation is kept, so PropertiesChanged can be emitted manually.
+    pub fn no_auto_emit(self) -> Self { self.0.auto_emit = false; self }
+    /// Emit PropertiesChanged after this property is set, even if `Crossroads::set_default_auto_emit`
+    /// turned this off for the interface.
+    pub fn auto_emit(self) -> Self { self.0.auto_emit = true; self }
 }
 
 /// Struct used to build an interface.
@@ -421,15 +422,27 @@ impl<T: std::marker::Send, A> PropBuilder<'_, T, A> {
 /// when using async methods.
 ///
 #[derive(Debug)]
-pub struct IfaceBuilder<T: Send + 'static>(IfaceDesc, PhantomData<&'static T>);
+pub struct IfaceBuilder<T: Send + 'static>(IfaceDesc, PhantomData<&'static T>, Option<EmitsChangedSignal>, bool);
 
 impl<T: Send + 'static> IfaceBuilder<T> {
+    /// Adds a property.
+    ///
+    /// If a default was set with `Crossroads::set_default_emits_changed`, the property is
+    /// annotated with it, unless one of the `emits_changed_*` methods is called. Likewise,
+    /// the property only emits PropertiesChanged automatically if `Crossroads::set_default_auto_emit`
+    /// was not turned off, unless `auto_emit` or `no_auto_emit` is called.
     pub fn property<A: arg::Arg, N: Into<String>>(&mut self, name: N) -> PropBuilder<T, A> {
-        PropBuilder(self.0.properties.entry(name.into()).or_insert(PropDesc {
-            annotations: Default::default(),
-            get_cb: None,
-            set_cb: None,
-            sig: A::signature(),
+        let (default_emits, default_auto_emit) = (self.2, self.3);
+        PropBuilder(self.0.properties.entry(name.into()).or_insert_with(|| {
+            let mut annotations = Annotations::default();
+            if let Some(e) = default_emits { annotations.insert(EMITS_CHANGED, e.as_str()); }
+            PropDesc {
+                annotations,
+                get_cb: None,
+                set_cb: None,
+                auto_emit: default_auto_emit,
+                sig: A::signature(),
+            }
         }), PhantomData)
     }
 
@@ -497,6 +510,40 @@ impl<T: Send + 'static> IfaceBuilder<T> {
         })
     }
 
+    /// Registers a method whose reply body is built at runtime, with the native marshaller.
+    ///
+    /// The output arguments, which are used for introspection data, are given by OA and
+    /// output_args, like for `method`. A reply body with another signature gives an
+    /// org.freedesktop.DBus.Error.Failed error instead.
+    #[cfg(feature = "native")]
+    pub fn method_with_cr_multibuf<IA, OA, N, CB>(&mut self, name: N, input_args: IA::strs, output_args: OA::strs, mut cb: CB) -> &mut MethodDesc
+    where IA: arg::ArgAll + arg::ReadAll, OA: arg::ArgAll,
+    N: Into<dbus::strings::Member<'static>>,
+    CB: FnMut(&mut Context, &mut Crossroads, IA) -> Result<dbus_native::marshalled::MultiBuf, MethodErr> + Send + 'static {
+        let output_args = build_argvec::<OA>(output_args);
+        let out_sig: String = output_args.0.iter().map(|a| &*a.sig).collect();
+        let boxed = Box::new(move |mut ctx: Context, cr: &mut Crossroads| {
+            let _ = ctx.check(|ctx| {
+                let ia = ctx.message().read_all()?;
+                let body = cb(ctx, cr, ia)?;
+                let body_sig = body.multi().signature();
+                if **body_sig != *out_sig {
+                    Err(MethodErr::failed(&format!("Reply has signature '{}', not '{}'", body_sig, out_sig)))?
+                }
+                let mut r = Ok(());
+                ctx.do_reply(|msg| r = crate::native::append_multibuf(msg, &body));
+                r
+            });
+            Some(ctx)
+        });
+        self.0.methods.entry(name.into()).or_insert(MethodDesc {
+            annotations: Default::default(),
+            input_args: build_argvec::<IA>(input_args),
+            output_args,
+            cb: Some(CallbackDbg(boxed)),
+        })
+    }
+
 
 /*
     pub fn method_with_cr_async<'x, IA, OA, N, R, CB>(&mut self, name: N, input_args: IA::strs, output_args: OA::strs, mut cb: CB) -> &mut MethodDesc
@@ -525,7 +572,7 @@ impl<T: Send + 'static> IfaceBuilder<T> {
     }
     pub fn deprecated(self) -> Self { self.annotate(DEPRECATED, "true") }
 
-    pub (crate) fn build<F>(name: Option<dbus::strings::Interface<'static>>, f: F) -> IfaceDesc
+    pub (crate) fn build<F>(name: Option<dbus::strings::Interface<'static>>, default_emits: Option<EmitsChangedSignal>, default_auto_emit: bool, f: F) -> IfaceDesc
     where F: FnOnce(&mut IfaceBuilder<T>) {
         let mut b = IfaceBuilder(IfaceDesc {
             name,
@@ -533,8 +580,23 @@ impl<T: Send + 'static> IfaceBuilder<T> {
             methods: Default::default(),
             signals: Default::default(),
             properties: Default::default(),
-        }, PhantomData);
+        }, PhantomData, default_emits, default_auto_emit);
         f(&mut b);
         b.0
     }
 }
+
+impl<D: ?Sized + 'static> IfaceBuilder<Shared<D>> {
+    /// Like `method`, but the callback gets the data of the path as a `D`.
+    ///
+    /// The data type of the path must have been made available with `Crossroads::share`.
+    pub fn shared_method<IA, OA, N, CB>(&mut self, name: N, input_args: IA::strs, output_args: OA::strs, mut cb: CB) -> &mut MethodDesc
+    where IA: arg::ArgAll + arg::ReadAll, OA: arg::ArgAll + arg::AppendAll,
+    N: Into<dbus::strings::Member<'static>>,
+    CB: FnMut(&mut Context, &mut D, IA) -> Result<OA, MethodErr> + Send + 'static {
+        self.method_with_cr(name, input_args, output_args, move |ctx, cr, ia| {
+            let data = cr.shared_data_mut(ctx.path()).ok_or_else(|| MethodErr::no_path(ctx.path()))?;
+            cb(ctx, data, ia)
+        })
+    }
+}
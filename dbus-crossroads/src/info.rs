@@ -0,0 +1,354 @@
+//! Descriptions of interfaces without handlers, as used for introspection.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use dbus::strings::{Interface, Member};
+use crate::ifacedesc::EMITS_CHANGED;
+
+/// Whether a property can be read, written, or both.
+#[derive(Copy, Clone, PartialEq, Eq, Ord, PartialOrd, Debug)]
+pub enum Access {
+    /// The Property can only be read (Get).
+    Read,
+    /// The Property can be read or written.
+    ReadWrite,
+    /// The Property can only be written (Set).
+    Write,
+}
+
+impl Access {
+    /// The value of the "access" attribute in introspection data.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Access::Read => "read",
+            Access::ReadWrite => "readwrite",
+            Access::Write => "write",
+        }
+    }
+
+    fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "read" => Some(Access::Read),
+            "readwrite" => Some(Access::ReadWrite),
+            "write" => Some(Access::Write),
+            _ => None,
+        }
+    }
+}
+
+/// Whether, and how, a PropertiesChanged signal is emitted when a property changes.
+#[derive(Copy, Clone, PartialEq, Eq, Ord, PartialOrd, Debug)]
+pub enum EmitsChangedSignal {
+    /// The Property emits a signal that includes the new value.
+    True,
+    /// The Property emits a signal that does not include the new value.
+    Invalidates,
+    /// The Property cannot be changed.
+    Const,
+    /// The Property does not emit a signal when changed.
+    False,
+}
+
+impl EmitsChangedSignal {
+    /// The value of the EmitsChangedSignal annotation.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EmitsChangedSignal::True => "true",
+            EmitsChangedSignal::Invalidates => "invalidates",
+            EmitsChangedSignal::Const => "const",
+            EmitsChangedSignal::False => "false",
+        }
+    }
+
+    fn from_annotations(a: &BTreeMap<String, String>) -> Option<Self> {
+        match a.get(EMITS_CHANGED).map(|s| &**s) {
+            Some("true") => Some(EmitsChangedSignal::True),
+            Some("false") => Some(EmitsChangedSignal::False),
+            Some("invalidates") => Some(EmitsChangedSignal::Invalidates),
+            Some("const") => Some(EmitsChangedSignal::Const),
+            _ => None,
+        }
+    }
+}
+
+/// An argument of a method or signal.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ArgInfo {
+    pub name: String,
+    pub sig: dbus::Signature<'static>,
+    pub annotations: BTreeMap<String, String>,
+}
+
+/// A method, without a handler.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct MethodInfo {
+    pub name: Member<'static>,
+    pub input_args: Vec<ArgInfo>,
+    pub output_args: Vec<ArgInfo>,
+    pub annotations: BTreeMap<String, String>,
+}
+
+/// A signal.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SignalInfo {
+    pub name: Member<'static>,
+    pub args: Vec<ArgInfo>,
+    pub annotations: BTreeMap<String, String>,
+}
+
+/// A property, without get and set handlers.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PropInfo {
+    pub name: String,
+    pub sig: dbus::Signature<'static>,
+    pub access: Access,
+    pub annotations: BTreeMap<String, String>,
+}
+
+/// An interface with its methods, signals and properties, but without handlers.
+///
+/// This can be generated from registered interfaces, or parsed from the introspection
+/// data of a service, e g to build a dynamic proxy.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct IfaceInfoSkeleton {
+    pub name: Interface<'static>,
+    pub methods: Vec<MethodInfo>,
+    pub signals: Vec<SignalInfo>,
+    pub properties: Vec<PropInfo>,
+    pub annotations: BTreeMap<String, String>,
+}
+
+fn introspect_annotations(a: &BTreeMap<String, String>, prefix: &str) -> String {
+    let mut r = String::new();
+    for (k, v) in a.iter() {
+        r += &format!("{}<annotation name=\"{}\" value=\"{}\"/>\n", prefix, k, v);
+    }
+    r
+}
+
+fn introspect_args(args: &[ArgInfo], dir: Option<&str>, prefix: &str) -> String {
+    let mut r = String::new();
+    for a in args {
+        r += &format!("{}<arg name=\"{}\" type=\"{}\"", prefix, a.name, a.sig);
+        if let Some(dir) = dir { r += &format!(" direction=\"{}\"", dir); }
+        if a.annotations.is_empty() {
+            r += "/>\n";
+        } else {
+            let inner_prefix = format!("{}  ", prefix);
+            r += &format!(">\n{}{}</arg>\n", introspect_annotations(&a.annotations, &inner_prefix), prefix);
+        }
+    }
+    r
+}
+
+impl IfaceInfoSkeleton {
+    /// Creates an interface without any methods, signals, properties or annotations.
+    pub fn new(name: Interface<'static>) -> Self {
+        IfaceInfoSkeleton {
+            name,
+            methods: vec!(),
+            signals: vec!(),
+            properties: vec!(),
+            annotations: Default::default(),
+        }
+    }
+
+    /// Looks up a method by name.
+    pub fn method(&self, name: &str) -> Option<&MethodInfo> { self.methods.iter().find(|m| &*m.name == name) }
+
+    /// Looks up a method by name, for modification.
+    pub fn method_mut(&mut self, name: &str) -> Option<&mut MethodInfo> { self.methods.iter_mut().find(|m| &*m.name == name) }
+
+    /// Looks up a signal by name.
+    pub fn signal(&self, name: &str) -> Option<&SignalInfo> { self.signals.iter().find(|s| &*s.name == name) }
+
+    /// Looks up a signal by name, for modification.
+    pub fn signal_mut(&mut self, name: &str) -> Option<&mut SignalInfo> { self.signals.iter_mut().find(|s| &*s.name == name) }
+
+    /// Looks up a property by name.
+    pub fn prop(&self, name: &str) -> Option<&PropInfo> { self.properties.iter().find(|p| p.name == name) }
+
+    /// Looks up a property by name, for modification.
+    pub fn prop_mut(&mut self, name: &str) -> Option<&mut PropInfo> { self.properties.iter_mut().find(|p| p.name == name) }
+
+    /// How PropertiesChanged is emitted for a property, as given by its annotations,
+    /// or the annotations of the interface.
+    pub fn emits_changed(&self, prop: &PropInfo) -> EmitsChangedSignal {
+        EmitsChangedSignal::from_annotations(&prop.annotations)
+            .or_else(|| EmitsChangedSignal::from_annotations(&self.annotations))
+            .unwrap_or(EmitsChangedSignal::True)
+    }
+
+    /// Generates the introspection data for this interface.
+    ///
+    /// Methods, signals and properties are written in the order they are stored.
+    pub fn introspect(&self) -> String {
+        let mut r = String::new();
+        self.introspect_xml(&mut r);
+        r
+    }
+
+    /// Like introspect, but appends the introspection data to r.
+    pub fn introspect_xml(&self, r: &mut String) {
+        *r += &format!("  <interface name=\"{}\">\n", self.name);
+        for m in &self.methods {
+            *r += &format!("    <method name=\"{}\">\n", m.name);
+            *r += &introspect_args(&m.input_args, Some("in"), "      ");
+            *r += &introspect_args(&m.output_args, Some("out"), "      ");
+            *r += &introspect_annotations(&m.annotations, "      ");
+            *r += "    </method>\n";
+        }
+        for s in &self.signals {
+            *r += &format!("    <signal name=\"{}\">\n", s.name);
+            *r += &introspect_args(&s.args, None, "      ");
+            *r += &introspect_annotations(&s.annotations, "      ");
+            *r += "    </signal>\n";
+        }
+        for p in &self.properties {
+            *r += &format!("    <property name=\"{}\" type=\"{}\" access=\"{}\"", p.name, p.sig, p.access.as_str());
+            if p.annotations.is_empty() {
+                *r += "/>\n";
+            } else {
+                *r += &format!(">\n{}    </property>\n", introspect_annotations(&p.annotations, "      "));
+            }
+        }
+        *r += &introspect_annotations(&self.annotations, "    ");
+        *r += "  </interface>\n";
+    }
+}
+
+/// Error returned when introspection data could not be parsed.
+#[derive(Clone, Debug)]
+pub enum IntrospectError {
+    /// The data is not well-formed XML.
+    Xml(String),
+    /// The data is well-formed XML, but not valid introspection data.
+    Invalid(String),
+}
+
+impl fmt::Display for IntrospectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IntrospectError::Xml(s) => write!(f, "Invalid XML: {}", s),
+            IntrospectError::Invalid(s) => write!(f, "Invalid introspection data: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for IntrospectError {}
+
+#[cfg(feature = "introspect-parse")]
+mod parse {
+    use super::*;
+    use xml::reader::{EventReader, XmlEvent};
+    use xml::attribute::OwnedAttribute;
+
+    enum Current {
+        Method(MethodInfo),
+        Signal(SignalInfo),
+        Prop(PropInfo),
+    }
+
+    fn invalid<T>(s: String) -> Result<T, IntrospectError> { Err(IntrospectError::Invalid(s)) }
+
+    fn attr<'a>(attrs: &'a [OwnedAttribute], elem: &str, name: &str) -> Result<&'a str, IntrospectError> {
+        match attrs.iter().find(|a| a.name.local_name == name) {
+            Some(a) => Ok(&a.value),
+            None => invalid(format!("Missing attribute '{}' on element '{}'", name, elem)),
+        }
+    }
+
+    fn signature(s: &str) -> Result<dbus::Signature<'static>, IntrospectError> {
+        dbus::Signature::new(s).map_err(IntrospectError::Invalid)
+    }
+
+    impl IfaceInfoSkeleton {
+        /// Parses introspection data into interface skeletons.
+        ///
+        /// Child nodes are ignored.
+        pub fn from_introspect_xml(xml: &str) -> Result<Vec<IfaceInfoSkeleton>, IntrospectError> {
+            let mut r = vec!();
+            let mut iface: Option<IfaceInfoSkeleton> = None;
+            let mut member: Option<Current> = None;
+            let mut arg: Option<(ArgInfo, bool)> = None;
+            for e in EventReader::from_str(xml) {
+                match e.map_err(|e| IntrospectError::Xml(e.to_string()))? {
+                    XmlEvent::StartElement { name, attributes, .. } => {
+                        let elem = &*name.local_name;
+                        let a = |n| attr(&attributes, elem, n);
+                        match (elem, &mut iface, &mut member, &mut arg) {
+                            // The root node, or a child node
+                            ("node", None, None, None) => {},
+                            ("interface", None, None, None) => {
+                                iface = Some(IfaceInfoSkeleton::new(Interface::new(a("name")?).map_err(IntrospectError::Invalid)?));
+                            },
+                            ("method", Some(_), None, None) => member = Some(Current::Method(MethodInfo {
+                                name: Member::new(a("name")?).map_err(IntrospectError::Invalid)?,
+                                input_args: vec!(),
+                                output_args: vec!(),
+                                annotations: Default::default(),
+                            })),
+                            ("signal", Some(_), None, None) => member = Some(Current::Signal(SignalInfo {
+                                name: Member::new(a("name")?).map_err(IntrospectError::Invalid)?,
+                                args: vec!(),
+                                annotations: Default::default(),
+                            })),
+                            ("property", Some(_), None, None) => {
+                                let access = a("access")?;
+                                member = Some(Current::Prop(PropInfo {
+                                    name: a("name")?.into(),
+                                    sig: signature(a("type")?)?,
+                                    access: Access::from_str(access).map_or_else(|| invalid(format!("Invalid access '{}'", access)), Ok)?,
+                                    annotations: Default::default(),
+                                }))
+                            },
+                            ("arg", Some(_), Some(m), None) => {
+                                let dir = attributes.iter().find(|a| a.name.local_name == "direction").map(|a| &*a.value);
+                                let is_out = match (m, dir) {
+                                    (Current::Method(_), None) | (Current::Method(_), Some("in")) => false,
+                                    (Current::Method(_), Some("out")) | (Current::Signal(_), None) | (Current::Signal(_), Some("out")) => true,
+                                    _ => invalid(format!("Unexpected argument direction {:?}", dir))?,
+                                };
+                                let name = attributes.iter().find(|a| a.name.local_name == "name").map(|a| a.value.clone());
+                                arg = Some((ArgInfo {
+                                    name: name.unwrap_or_default(),
+                                    sig: signature(a("type")?)?,
+                                    annotations: Default::default(),
+                                }, is_out));
+                            },
+                            ("annotation", Some(i), m, arg) => {
+                                let anns = match (m, arg) {
+                                    (_, Some((arg, _))) => &mut arg.annotations,
+                                    (Some(Current::Method(m)), None) => &mut m.annotations,
+                                    (Some(Current::Signal(s)), None) => &mut s.annotations,
+                                    (Some(Current::Prop(p)), None) => &mut p.annotations,
+                                    (None, None) => &mut i.annotations,
+                                };
+                                anns.insert(a("name")?.into(), a("value")?.into());
+                            },
+                            (elem, _, _, _) => invalid(format!("Unexpected element '{}'", elem))?,
+                        }
+                    },
+                    XmlEvent::EndElement { name } => match &*name.local_name {
+                        "interface" => r.extend(iface.take()),
+                        "method" | "signal" | "property" => match (member.take(), &mut iface) {
+                            (Some(Current::Method(m)), Some(i)) => i.methods.push(m),
+                            (Some(Current::Signal(s)), Some(i)) => i.signals.push(s),
+                            (Some(Current::Prop(p)), Some(i)) => i.properties.push(p),
+                            _ => {},
+                        },
+                        "arg" => match (arg.take(), &mut member) {
+                            (Some((a, false)), Some(Current::Method(m))) => m.input_args.push(a),
+                            (Some((a, true)), Some(Current::Method(m))) => m.output_args.push(a),
+                            (Some((a, _)), Some(Current::Signal(s))) => s.args.push(a),
+                            _ => {},
+                        },
+                        _ => {},
+                    },
+                    _ => {},
+                }
+            }
+            Ok(r)
+        }
+    }
+}
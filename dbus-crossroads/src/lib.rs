@@ -11,15 +11,23 @@ mod context;
 mod crossroads;
 mod ifacedesc;
 mod stdimpl;
+mod info;
+
+#[cfg(feature = "native")]
+pub mod native;
 
 pub use dbus::MethodErr as MethodErr;
 
 pub use context::Context;
 pub use stdimpl::PropContext;
-pub use crossroads::{Crossroads, IfaceToken};
+pub use crossroads::{Crossroads, IfaceToken, Shared};
+#[cfg(feature = "trace")]
+pub use crossroads::TraceInfo;
 
 pub use ifacedesc::{MethodDesc, SignalDesc, IfaceBuilder, PropBuilder};
 
+pub use info::{IfaceInfoSkeleton, MethodInfo, SignalInfo, PropInfo, ArgInfo, Access, EmitsChangedSignal, IntrospectError};
+
 #[cfg(test)]
 mod test;
 
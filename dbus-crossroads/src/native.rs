@@ -0,0 +1,42 @@
+//! Experimental glue between the native (pure Rust) marshaller in the dbus-native crate
+//! and the argument traits used by method handlers.
+
+use std::num::NonZeroU32;
+use dbus::arg::{ReadAll, IterAppend};
+use dbus_native::marshalled::{Multi, MultiBuf};
+use dbus_native::types::DemarshalError;
+use crate::MethodErr;
+
+fn native_err(e: DemarshalError) -> MethodErr { MethodErr::invalid_arg(&e) }
+
+// The body might come from the caller or from ourselves, so native_err is passed in
+// to turn errors into the right kind of MethodErr.
+fn to_dbus_message(m: &Multi, native_err: fn(DemarshalError) -> MethodErr) -> Result<dbus::Message, MethodErr> {
+    for x in m.iter() { x.map_err(native_err)?; }
+    let serial = NonZeroU32::new(1).unwrap();
+    let mut msg = dbus_native::message::Message::new_method_return(serial);
+    msg.set_body_multi(*m).map_err(native_err)?;
+    let data = msg.marshal(serial, false).map_err(native_err)?;
+    Ok(dbus::Message::demarshal(&data)?)
+}
+
+/// Reads a natively marshalled message body into method input arguments.
+pub fn read_all_from_multi<A: ReadAll>(m: &Multi) -> Result<A, MethodErr> {
+    Ok(to_dbus_message(m, native_err)?.read_all()?)
+}
+
+/// Appends a natively marshalled body to a message, e g a method reply.
+///
+/// Nothing is appended if the body could not be converted. Since the body is built by
+/// the method handler, this gives an org.freedesktop.DBus.Error.Failed error.
+pub fn append_multibuf(msg: &mut dbus::Message, body: &MultiBuf) -> Result<(), MethodErr> {
+    let src = to_dbus_message(&body.multi(), |e| MethodErr::failed(&e))?;
+    let mut iter = src.iter_init();
+    let mut ia = IterAppend::new(msg);
+    while let Some(a) = iter.get_refarg() {
+        a.append(&mut ia);
+        iter.next();
+    }
+    Ok(())
+}
+
@@ -29,12 +29,22 @@ r##"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//E
 pub fn introspectable(cr: &mut Crossroads) -> IfaceToken<()> {
     cr.register("org.freedesktop.DBus.Introspectable", |b| {
         b.method_with_cr("Introspect", (), ("xml_data",), |ctx, cr, _: ()| {
-            Ok((introspect(cr, ctx.path()),))
+            Ok((cr.introspect_cached(ctx.path(), |cr| introspect(cr, ctx.path())),))
         });
     })
 }
 
 
+pub fn peer(cr: &mut Crossroads) -> IfaceToken<()> {
+    cr.register("org.freedesktop.DBus.Peer", |b| {
+        b.method_with_cr("Ping", (), (), |_, _, _: ()| Ok(()));
+        b.method_with_cr("GetMachineId", (), ("machine_uuid",), |_, _, _: ()| {
+            let id = dbus_native_channel::machineid::read_machine_id().map_err(|e| MethodErr::failed(&e))?;
+            Ok((id,))
+        });
+    })
+}
+
 fn make_emits_message<V: dbus::arg::Arg + dbus::arg::Append>(prop_name: &str, emits_changed: &str, ctx: &Context, v: &V) -> Option<dbus::Message> {
     let arr = [prop_name];
     let (d, i) = match emits_changed {
@@ -241,9 +251,11 @@ fn set(mut ctx: Context, cr: &mut Crossroads, (interface_name, property_name, _v
         Ok(p) => p,
         Err(_) => return Some(ctx),
     };
+    let auto_emit = cr.registry().auto_emit(propctx.iface_token, &propctx.name);
     let ann = cr.registry()
         .find_annotation(propctx.iface_token, EMITS_CHANGED, Some(&propctx.name));
     propctx.emits_changed = match ann {
+        _ if !auto_emit => Some("false"),
         Some("const") => Some("const"),
         Some("false") => Some("false"),
         Some("invalidates") => Some("invalidates"),
@@ -390,31 +402,43 @@ fn object_manager_parents<F: FnMut(dbus::Path<'static>, &mut Crossroads)>(name:
     }
 }
 
+/// Builds an InterfacesAdded signal, making sure the object path is valid first.
+pub (crate) fn interfaces_added_message(parent: &dbus::Path<'static>, object: &str, interfaces: IfacePropMap) -> Result<dbus::Message, MethodErr> {
+    let object = dbus::Path::new(object).map_err(|e| MethodErr::invalid_arg(&e))?;
+    let x = dbus::blocking::stdintf::org_freedesktop_dbus::ObjectManagerInterfacesAdded { object, interfaces };
+    Ok(dbus::message::SignalArgs::to_emit_message(&x, parent))
+}
+
 pub fn object_manager_path_added(sender: Arc<dyn Sender + Send + Sync>, name: &dbus::Path<'static>, cr: &mut Crossroads) {
     object_manager_parents(name, cr, |parent, cr| {
         let n = name.clone();
         let s = sender.clone();
         get_all_for_path(&name, cr, move |ictx| {
-            let x = dbus::blocking::stdintf::org_freedesktop_dbus::ObjectManagerInterfacesAdded {
-                object: n,
-                interfaces: std::mem::replace(&mut ictx.ifaces, HashMap::new()),
-            };
-            let _ = s.send(dbus::message::SignalArgs::to_emit_message(&x, &parent));
+            let interfaces = std::mem::replace(&mut ictx.ifaces, HashMap::new());
+            if let Ok(msg) = interfaces_added_message(&parent, &n, interfaces) {
+                let _ = s.send(msg);
+            }
         });
     });
 }
 
+/// Builds an InterfacesRemoved signal, listing the interfaces registered at the object path.
+pub (crate) fn interfaces_removed_message(parent: &dbus::Path<'static>, object: &dbus::Path<'static>, cr: &Crossroads) -> dbus::Message {
+    let (reg, ifaces) = cr.registry_and_ifaces(object);
+    let mut interfaces: Vec<String> = ifaces.iter()
+        .filter_map(|iface| reg.get_intf_name(*iface))
+        .map(|iface| String::from(&**iface))
+        .collect();
+    interfaces.sort_unstable();
+    let x = dbus::blocking::stdintf::org_freedesktop_dbus::ObjectManagerInterfacesRemoved {
+        object: object.clone(),
+        interfaces,
+    };
+    dbus::message::SignalArgs::to_emit_message(&x, parent)
+}
+
 pub fn object_manager_path_removed(sender: Arc<dyn Sender + Send + Sync>, name: &dbus::Path<'static>, cr: &mut Crossroads) {
     object_manager_parents(name, cr, |parent, cr| {
-        let (reg, ifaces) = cr.registry_and_ifaces(&name);
-
-        let x = dbus::blocking::stdintf::org_freedesktop_dbus::ObjectManagerInterfacesRemoved {
-            object: name.clone(),
-            interfaces: ifaces.into_iter()
-                .filter_map(|iface| reg.get_intf_name(*iface))
-                .map(|iface| String::from(&**iface))
-                .collect(),
-        };
-        let _ = sender.send(dbus::message::SignalArgs::to_emit_message(&x, &parent));
+        let _ = sender.send(interfaces_removed_message(&parent, name, cr));
     });
 }
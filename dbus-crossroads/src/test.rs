@@ -112,6 +112,13 @@ const INTROSPECT: &str = r###"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS O
       <arg name="xml_data" type="s" direction="out"/>
     </method>
   </interface>
+  <interface name="org.freedesktop.DBus.Peer">
+    <method name="GetMachineId">
+      <arg name="machine_uuid" type="s" direction="out"/>
+    </method>
+    <method name="Ping">
+    </method>
+  </interface>
   <interface name="org.freedesktop.DBus.Properties">
     <method name="Get">
       <arg name="interface_name" type="s" direction="in"/>
@@ -167,6 +174,61 @@ fn introspect() {
     assert_eq!(INTROSPECT, xml_data);
 }
 
+#[test]
+fn introspect_cache() {
+    let mut cr = Crossroads::new();
+    cr.insert("/com/example", &[], ());
+
+    fn introspect_helper(cr: &mut Crossroads, path: &str) -> String {
+        let msg = Message::new_method_call("com.example.dbusrs.crossroads.score", path,
+            "org.freedesktop.DBus.Introspectable", "Introspect").unwrap();
+        let r = dispatch_helper(cr, msg);
+        r.read1::<&str>().unwrap().to_string()
+    }
+
+    let x1 = introspect_helper(&mut cr, "/com/example");
+    let x2 = introspect_helper(&mut cr, "/com/example");
+    assert_eq!(x1, x2);
+    assert!(!x1.contains("sample_object0"));
+
+    let token = cr.register("com.example.SampleInterface0", |b: &mut IfaceBuilder<()>| {
+        b.method("Ping", (), (), |_, _, _: ()| Ok(()));
+    });
+    cr.insert("/com/example/sample_object0", &[token], ());
+    let x3 = introspect_helper(&mut cr, "/com/example");
+    assert!(x3.contains("<node name=\"sample_object0\"/>"));
+
+    cr.insert("/com/example", &[token], ());
+    let x4 = introspect_helper(&mut cr, "/com/example");
+    assert!(x4.contains("com.example.SampleInterface0"));
+    assert_eq!(x4, introspect_helper(&mut cr, "/com/example"));
+
+    cr.remove::<()>(&"/com/example/sample_object0".into());
+    assert!(!introspect_helper(&mut cr, "/com/example").contains("sample_object0"));
+}
+
+#[test]
+fn emit_signal() {
+    let mut cr = Crossroads::new();
+    let token = cr.register("com.example.dbusrs.crossroads.score", |b: &mut IfaceBuilder<()>| {
+        b.signal::<(String, i32), _>("Changed", ("name", "value"));
+    });
+    cr.insert("/score", &[token], ());
+    let path = "/score".into();
+
+    let msg = cr.emit_signal(&path, token, "Changed", ("Hello", 5i32)).unwrap();
+    assert_eq!(msg.msg_type(), dbus::message::MessageType::Signal);
+    assert_eq!(&*msg.interface().unwrap(), "com.example.dbusrs.crossroads.score");
+    assert_eq!(&*msg.member().unwrap(), "Changed");
+    let (name, value): (&str, i32) = msg.read2().unwrap();
+    assert_eq!(name, "Hello");
+    assert_eq!(value, 5);
+
+    assert!(cr.emit_signal(&path, token, "Changed", (5i32,)).is_err());
+    assert!(cr.emit_signal(&path, token, "Unknown", ("Hello", 5i32)).is_err());
+    assert!(cr.emit_signal(&"/missing".into(), token, "Changed", ("Hello", 5i32)).is_err());
+}
+
 #[test]
 fn object_manager() {
     struct Apple { radius: u32, weight: u32 };
@@ -211,6 +273,73 @@ fn object_manager() {
     assert_eq!(v.0.as_u64().unwrap(), 20);
 }
 
+#[test]
+fn interfaces_added_invalid_path() {
+    let parent: dbus::Path<'static> = "/list".into();
+    assert!(crate::stdimpl::interfaces_added_message(&parent, "/list/grannysmith", HashMap::new()).is_ok());
+    assert!(crate::stdimpl::interfaces_added_message(&parent, "/list//grannysmith", HashMap::new()).is_err());
+    assert!(crate::stdimpl::interfaces_added_message(&parent, "list/grannysmith", HashMap::new()).is_err());
+}
+
+#[test]
+fn interfaces_removed() {
+    let mut cr = Crossroads::new();
+    let removed = std::sync::Arc::new(std::sync::Mutex::new(vec!()));
+    cr.set_object_manager_support(Some(removed.clone()));
+
+    let weight_token = cr.register::<u32, _, _>("com.example.dbusrs.weight", |b| {
+        b.property("Weight").get(|_, w| { Ok(*w) });
+    });
+    let radius_token = cr.register::<u32, _, _>("com.example.dbusrs.radius", |b| {
+        b.method("Grow", (), (), |_, _, _: ()| Ok(()));
+    });
+    cr.insert("/list", &[cr.object_manager()], ());
+    cr.insert("/list/grannysmith", &[weight_token, radius_token], 20u32);
+    removed.lock().unwrap().clear();
+
+    assert_eq!(cr.remove::<u32>(&"/list/grannysmith".into()), Some(20));
+    let v: Vec<_> = removed.lock().unwrap().drain(..).collect();
+    assert_eq!(v.len(), 1);
+
+    use dbus::blocking::stdintf::org_freedesktop_dbus::ObjectManagerInterfacesRemoved as OMIR;
+    use dbus::message::SignalArgs;
+    assert_eq!(&*v[0].path().unwrap(), "/list");
+    let mut i = v[0].iter_init();
+    assert_eq!(&*i.signature(), "o");
+    assert!(i.next());
+    assert_eq!(&*i.signature(), "as");
+    let omir = OMIR::from_message(&v[0]).unwrap();
+    assert_eq!(&*omir.object, "/list/grannysmith");
+    assert!(omir.interfaces.iter().any(|i| i == "com.example.dbusrs.weight"));
+    assert!(omir.interfaces.iter().any(|i| i == "com.example.dbusrs.radius"));
+    let mut sorted = omir.interfaces.clone();
+    sorted.sort();
+    assert_eq!(sorted, omir.interfaces);
+}
+
+#[test]
+fn unknown_errors() {
+    let mut cr = Crossroads::new();
+    let iface = cr.register("com.example.dbusrs.crossroads.score", |b: &mut IfaceBuilder<()>| {
+        b.method("Ping", (), (), |_, _, _: ()| Ok(()));
+    });
+    cr.insert("/exists", &[iface], ());
+
+    fn error_name(cr: &mut Crossroads, path: &str, iface: &str, method: &str) -> String {
+        let msg = Message::new_method_call("com.example.dbusrs.crossroads.score", path, iface, method).unwrap();
+        let mut r = dispatch_helper2(cr, msg);
+        assert_eq!(r.len(), 1);
+        r[0].as_result().unwrap_err().name().unwrap().to_string()
+    }
+
+    assert_eq!(error_name(&mut cr, "/missing", "com.example.dbusrs.crossroads.score", "Ping"),
+        "org.freedesktop.DBus.Error.UnknownObject");
+    assert_eq!(error_name(&mut cr, "/exists", "com.example.dbusrs.crossroads.missing", "Ping"),
+        "org.freedesktop.DBus.Error.UnknownInterface");
+    assert_eq!(error_name(&mut cr, "/exists", "com.example.dbusrs.crossroads.score", "Pong"),
+        "org.freedesktop.DBus.Error.UnknownMethod");
+}
+
 #[test]
 fn object_manager_root() {
     let bus = dbus::blocking::Connection::new_session().unwrap();
@@ -237,3 +366,678 @@ fn object_manager_root() {
     dispatch_helper(&mut shared_cr.lock().unwrap(), msg);
     service_thread.join().unwrap();
 }
+
+#[cfg(feature = "native")]
+#[test]
+fn native_read_inputs() {
+    use dbus_native::marshalled::MultiBuf;
+    use dbus_native::strings::{DBusStr, StringLike};
+    let mut b = MultiBuf::new();
+    b.append(&-5i32).unwrap();
+    b.append(DBusStr::new("Hello").unwrap()).unwrap();
+    let (a, s): (i32, String) = crate::native::read_all_from_multi(&b.multi()).unwrap();
+    assert_eq!(a, -5);
+    assert_eq!(&*s, "Hello");
+
+    let r: Result<(String, i32), _> = crate::native::read_all_from_multi(&b.multi());
+    assert!(r.is_err());
+}
+
+#[cfg(feature = "native")]
+#[test]
+fn native_multibuf_reply() {
+    use dbus_native::marshalled::{Marshal, MultiBuf};
+    use dbus_native::strings::{DBusStr, SignatureSingle, StringLike};
+    use std::borrow::Cow;
+    let mut cr = Crossroads::new();
+    let iface = cr.register("com.example.dbusrs.crossroads.native", |b: &mut IfaceBuilder<()>| {
+        b.method_with_cr_multibuf::<_, (String, i32), _, _>("Dynamic", ("count",), ("greeting", "doubled"), |_, _, (count,): (i32,)| {
+            let mut r = MultiBuf::new();
+            r.append(DBusStr::new("Hello").unwrap()).unwrap();
+            r.append(&(count * 2)).unwrap();
+            Ok(r)
+        });
+        b.method_with_cr_multibuf::<_, (), _, _>("Fail", (), (), |_, _, _: ()| {
+            Err(MethodErr::failed("Nope"))
+        });
+        b.method_with_cr_multibuf::<_, (i32,), _, _>("WrongReply", (), ("x",), |_, _, _: ()| {
+            let mut r = MultiBuf::new();
+            r.append(&5u8).unwrap();
+            Ok(r)
+        });
+        b.method_with_cr_multibuf::<_, (String,), _, _>("BadReply", (), ("x",), |_, _, _: ()| {
+            // A string length without the string data
+            struct Truncated;
+            impl Marshal for Truncated {
+                fn signature(&self) -> Cow<'_, SignatureSingle> { SignatureSingle::new_unchecked("s").into() }
+                fn append_data_to(&self, v: &mut Vec<u8>) { v.extend_from_slice(&[1, 0, 0, 0]) }
+            }
+            let mut r = MultiBuf::new();
+            r.append(&Truncated).unwrap();
+            Ok(r)
+        });
+    });
+    cr.insert("/", &[iface], ());
+
+    let mut msg = Message::new_method_call("com.example.dbusrs.crossroads.native", "/",
+        "com.example.dbusrs.crossroads.native", "Dynamic").unwrap();
+    msg = msg.append1(21i32);
+    let r = dispatch_helper(&mut cr, msg);
+    let mut iter = r.iter_init();
+    assert_eq!(&*iter.signature(), "s");
+    iter.next();
+    assert_eq!(&*iter.signature(), "i");
+    let (s, i): (String, i32) = r.read2().unwrap();
+    assert_eq!((&*s, i), ("Hello", 42));
+
+    let msg = Message::new_method_call("com.example.dbusrs.crossroads.native", "/",
+        "com.example.dbusrs.crossroads.native", "Fail").unwrap();
+    let mut r = dispatch_helper2(&mut cr, msg);
+    assert_eq!(r[0].as_result().unwrap_err().name(), Some("org.freedesktop.DBus.Error.Failed"));
+
+    // Replies that cannot be sent are the fault of the handler, not the caller.
+    for name in &["WrongReply", "BadReply"] {
+        let msg = Message::new_method_call("com.example.dbusrs.crossroads.native", "/",
+            "com.example.dbusrs.crossroads.native", *name).unwrap();
+        let mut r = dispatch_helper2(&mut cr, msg);
+        assert_eq!(r.len(), 1);
+        assert_eq!(r[0].as_result().unwrap_err().name(), Some("org.freedesktop.DBus.Error.Failed"));
+    }
+
+    let msg = Message::new_method_call("com.example.dbusrs.crossroads.native", "/",
+        "org.freedesktop.DBus.Introspectable", "Introspect").unwrap();
+    let r = dispatch_helper(&mut cr, msg);
+    let xml_data: &str = r.read1().unwrap();
+    assert!(xml_data.contains(r#"<method name="Dynamic">
+      <arg name="count" type="i" direction="in"/>
+      <arg name="greeting" type="s" direction="out"/>
+      <arg name="doubled" type="i" direction="out"/>
+    </method>"#));
+}
+
+#[cfg(feature = "introspect-parse")]
+#[test]
+fn introspect_roundtrip() {
+    use crate::{IfaceInfoSkeleton, Access, EmitsChangedSignal};
+    let mut cr = Crossroads::new();
+    let token = cr.register("com.example.dbusrs.crossroads.roundtrip", |b: &mut IfaceBuilder<()>| {
+        b.method("Frobate", ("foo", "bar"), ("baz",), |_, _, _: (i32, String)| {
+            let x: HashMap<u32, String> = Default::default();
+            Ok((x,))
+        }).deprecated().annotate("com.example.Hint", "fast");
+        b.method("Empty", (), (), |_, _, _: ()| Ok(()));
+        b.signal::<(bool, Vec<String>), _>("Changed", ("new_value", "names")).deprecated();
+        b.property("Const").get(|_, _| Ok(5u8)).emits_changed_const();
+        b.property("Name").get(|_, _| Ok(String::new())).set(|_, _, _| Ok(None));
+        b.property("Secret").set(|_, _, _: u64| Ok(None)).emits_changed_invalidates();
+    });
+    cr.insert("/", &[token], ());
+
+    let msg = Message::new_method_call("com.example.dbusrs.crossroads.roundtrip", "/",
+        "org.freedesktop.DBus.Introspectable", "Introspect").unwrap();
+    let r = dispatch_helper(&mut cr, msg);
+    let xml_data: &str = r.read1().unwrap();
+
+    let ifaces = IfaceInfoSkeleton::from_introspect_xml(xml_data).unwrap();
+    let names: Vec<_> = ifaces.iter().map(|i| &*i.name).collect();
+    assert_eq!(names, vec!("com.example.dbusrs.crossroads.roundtrip",
+        "org.freedesktop.DBus.Introspectable", "org.freedesktop.DBus.Peer", "org.freedesktop.DBus.Properties"));
+    let iface = &ifaces[0];
+    let methods: Vec<_> = iface.methods.iter().map(|m| &*m.name).collect();
+    assert_eq!(methods, vec!("Empty", "Frobate"));
+    let frobate = &iface.methods[1];
+    let args: Vec<_> = frobate.input_args.iter().map(|a| (&*a.name, &*a.sig)).collect();
+    assert_eq!(args, vec!(("foo", "i"), ("bar", "s")));
+    assert_eq!(&*frobate.output_args[0].sig, "a{us}");
+    assert_eq!(frobate.annotations.get("com.example.Hint").map(|x| &**x), Some("fast"));
+    assert_eq!(&*iface.signals[0].args[1].sig, "as");
+    let props: Vec<_> = iface.properties.iter().map(|p| (&*p.name, p.access, iface.emits_changed(p))).collect();
+    assert_eq!(props, vec!(
+        ("Const", Access::Read, EmitsChangedSignal::Const),
+        ("Name", Access::ReadWrite, EmitsChangedSignal::True),
+        ("Secret", Access::Write, EmitsChangedSignal::Invalidates),
+    ));
+
+    // Regenerating gives back the same XML, and parsing that gives back the same structure.
+    let regenerated: String = ifaces.iter().map(|i| i.introspect()).collect();
+    assert!(xml_data.contains(&regenerated));
+    let reparsed = IfaceInfoSkeleton::from_introspect_xml(&format!("<node>\n{}</node>", regenerated)).unwrap();
+    assert_eq!(ifaces, reparsed);
+
+    let xml = r#"<node><interface name="a.b"><property name="x" type="i" access="read"/>
+        <annotation name="org.freedesktop.DBus.Property.EmitsChangedSignal" value="false"/></interface></node>"#;
+    let iface = &IfaceInfoSkeleton::from_introspect_xml(xml).unwrap()[0];
+    assert_eq!(iface.emits_changed(&iface.properties[0]), EmitsChangedSignal::False);
+    assert!(iface.introspect().contains("    <annotation name=\"org.freedesktop.DBus.Property.EmitsChangedSignal\" value=\"false\"/>\n  </interface>"));
+
+    assert!(IfaceInfoSkeleton::from_introspect_xml("<node><interface name=\"a.b\"><property name=\"x\" type=\"i\" access=\"rw\"/></interface></node>").is_err());
+    assert!(IfaceInfoSkeleton::from_introspect_xml("<node><interface name=\"a.b\">").is_err());
+}
+
+#[test]
+fn error_hook() {
+    use std::sync::{Arc, Mutex};
+    let mut cr = Crossroads::new();
+    let iface = cr.register("com.example.dbusrs.crossroads.fail", |b: &mut IfaceBuilder<()>| {
+        b.method("Fail", (), (), |_, _, _: ()| -> Result<(), MethodErr> {
+            Err(MethodErr::failed("Not today"))
+        });
+        b.method("Succeed", (), (), |_, _, _: ()| Ok(()));
+    });
+    cr.insert("/", &[iface], ());
+    let seen = Arc::new(Mutex::new(vec!()));
+    let seen2 = seen.clone();
+    cr.set_error_hook(move |msg, err| {
+        seen2.lock().unwrap().push((msg.member().unwrap().to_string(), err.errorname().to_string(), err.description().to_string()));
+    });
+
+    let msg = Message::new_method_call("com.example.dbusrs.crossroads.fail", "/", "com.example.dbusrs.crossroads.fail", "Fail").unwrap();
+    let r = dispatch_helper2(&mut cr, msg);
+    assert_eq!(r.len(), 1);
+    assert_eq!(r[0].msg_type(), dbus::message::MessageType::Error);
+    assert_eq!(&*seen.lock().unwrap(), &[("Fail".to_string(), "org.freedesktop.DBus.Error.Failed".to_string(), "Not today".to_string())]);
+
+    let msg = Message::new_method_call("com.example.dbusrs.crossroads.fail", "/", "com.example.dbusrs.crossroads.fail", "Succeed").unwrap();
+    dispatch_helper(&mut cr, msg);
+    assert_eq!(seen.lock().unwrap().len(), 1);
+
+    let msg = Message::new_method_call("com.example.dbusrs.crossroads.fail", "/", "com.example.dbusrs.crossroads.fail", "Unknown").unwrap();
+    let r = dispatch_helper2(&mut cr, msg);
+    assert_eq!(r[0].msg_type(), dbus::message::MessageType::Error);
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen.len(), 2);
+    assert_eq!((&*seen[1].0, &*seen[1].1), ("Unknown", "org.freedesktop.DBus.Error.UnknownMethod"));
+}
+
+#[test]
+fn many_methods() {
+    let names: Vec<String> = (0..100).map(|i| format!("Method{}", i)).collect();
+    let mut cr = Crossroads::new();
+    let iface = cr.register("com.example.dbusrs.crossroads.many", |b: &mut IfaceBuilder<()>| {
+        for (i, name) in names.iter().enumerate() {
+            b.method(name.clone(), (), ("index",), move |_, _, _: ()| Ok((i as u32,)));
+        }
+    });
+    cr.insert("/", &[iface], ());
+
+    // Every method must be found by name, and match the result of a linear search
+    // through the list of names.
+    for name in names.iter().rev() {
+        let expected = names.iter().position(|x| x == name).unwrap() as u32;
+        let msg = Message::new_method_call("com.example.dbusrs.crossroads.many", "/", "com.example.dbusrs.crossroads.many", &**name).unwrap();
+        let r = dispatch_helper(&mut cr, msg);
+        assert_eq!(r.read1::<u32>().unwrap(), expected);
+    }
+}
+
+#[test]
+fn shared_iface() {
+    trait Diag: Send {
+        fn status(&mut self) -> String;
+    }
+    struct Disk(u32);
+    struct Fan { rpm: u32, calls: u32 }
+    impl Diag for Disk {
+        fn status(&mut self) -> String { format!("disk {}% full", self.0) }
+    }
+    impl Diag for Fan {
+        fn status(&mut self) -> String { self.calls += 1; format!("fan at {} rpm", self.rpm) }
+    }
+
+    let mut cr = Crossroads::new();
+    let diag = cr.register_shared("com.example.dbusrs.crossroads.diag", |b: &mut IfaceBuilder<Shared<dyn Diag>>| {
+        b.shared_method("Status", (), ("status",), |_, d, _: ()| Ok((d.status(),)));
+    });
+    let disk_diag = cr.share(diag, |d: &mut Disk| -> &mut dyn Diag { d });
+    let fan_diag = cr.share(diag, |f: &mut Fan| -> &mut dyn Diag { f });
+    let fan_iface = cr.register("com.example.dbusrs.crossroads.fan", |b: &mut IfaceBuilder<Fan>| {
+        b.method("Rpm", (), ("rpm",), |_, f, _: ()| Ok((f.rpm,)));
+    });
+    cr.insert("/disk", &[disk_diag], Disk(75));
+    cr.insert("/fan", &[fan_diag, fan_iface], Fan { rpm: 1200, calls: 0 });
+
+    let msg = Message::new_method_call("com.example.dbusrs.crossroads.diag", "/disk", "com.example.dbusrs.crossroads.diag", "Status").unwrap();
+    let r = dispatch_helper(&mut cr, msg);
+    assert_eq!(r.read1::<&str>().unwrap(), "disk 75% full");
+
+    let msg = Message::new_method_call("com.example.dbusrs.crossroads.diag", "/fan", "com.example.dbusrs.crossroads.diag", "Status").unwrap();
+    let r = dispatch_helper(&mut cr, msg);
+    assert_eq!(r.read1::<&str>().unwrap(), "fan at 1200 rpm");
+    assert_eq!(cr.data_mut::<Fan>(&"/fan".into()).unwrap().calls, 1);
+
+    let msg = Message::new_method_call("com.example.dbusrs.crossroads.diag", "/fan", "com.example.dbusrs.crossroads.fan", "Rpm").unwrap();
+    let r = dispatch_helper(&mut cr, msg);
+    assert_eq!(r.read1::<u32>().unwrap(), 1200);
+
+    // Both paths list the shared interface in their introspection data
+    let msg = Message::new_method_call("com.example.dbusrs.crossroads.diag", "/disk", "org.freedesktop.DBus.Introspectable", "Introspect").unwrap();
+    let r = dispatch_helper(&mut cr, msg);
+    assert!(r.read1::<&str>().unwrap().contains("<method name=\"Status\">"));
+}
+
+#[cfg(feature = "trace")]
+#[test]
+fn trace_hook() {
+    use std::sync::{Arc, Mutex};
+    let mut cr = Crossroads::new();
+    let iface = cr.register("com.example.dbusrs.crossroads.slow", |b: &mut IfaceBuilder<()>| {
+        b.method("Sleep", (), (), |_, _, _: ()| {
+            std::thread::sleep(std::time::Duration::from_millis(5));
+            Ok(())
+        });
+    });
+    cr.insert("/sleepy", &[iface], ());
+    let seen = Arc::new(Mutex::new(vec!()));
+    let seen2 = seen.clone();
+    cr.set_trace_hook(move |info| seen2.lock().unwrap().push(info.clone()));
+
+    let msg = Message::new_method_call("com.example.dbusrs.crossroads.slow", "/sleepy", "com.example.dbusrs.crossroads.slow", "Sleep").unwrap();
+    dispatch_helper(&mut cr, msg);
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen.len(), 2);
+    for info in seen.iter() {
+        assert_eq!(&*info.path, "/sleepy");
+        assert_eq!(info.interface.as_deref(), Some("com.example.dbusrs.crossroads.slow"));
+        assert_eq!(&*info.member, "Sleep");
+    }
+    assert!(seen[0].elapsed.is_none());
+    assert!(seen[1].elapsed.unwrap() >= std::time::Duration::from_millis(5));
+}
+
+#[test]
+fn default_emits_changed() {
+    let mut cr = Crossroads::new();
+    cr.set_default_emits_changed(Some(crate::EmitsChangedSignal::Invalidates));
+    let token = cr.register("com.example.dbusrs.crossroads.defaultemits", |b: &mut IfaceBuilder<u16>| {
+        b.property("Level").get(|_, x| Ok(*x)).set(|_, x, v| { *x = v; Ok(Some(v)) });
+        b.property("Limit").get(|_, x| Ok(*x)).set(|_, x, v| { *x = v; Ok(Some(v)) }).emits_changed_true();
+    });
+    cr.insert("/", &[token], 3);
+
+    let msg = Message::new_method_call("com.example.dbusrs.crossroads.defaultemits", "/",
+        "org.freedesktop.DBus.Introspectable", "Introspect").unwrap();
+    let r = dispatch_helper(&mut cr, msg);
+    let xml_data: &str = r.read1().unwrap();
+    assert!(xml_data.contains(r#"<property name="Level" type="q" access="readwrite">
+      <annotation name="org.freedesktop.DBus.Property.EmitsChangedSignal" value="invalidates"/>"#));
+    assert!(xml_data.contains(r#"<property name="Limit" type="q" access="readwrite">
+      <annotation name="org.freedesktop.DBus.Property.EmitsChangedSignal" value="true"/>"#));
+
+    use dbus::blocking::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged as PPC;
+    use dbus::message::SignalArgs;
+    let msg = Message::call_with_args("com.example.dbusrs.crossroads.defaultemits", "/",
+        "org.freedesktop.DBus.Properties", "Set", ("com.example.dbusrs.crossroads.defaultemits", "Level", Variant(7u16)));
+    let r = dispatch_helper2(&mut cr, msg);
+    let ppc = r.iter().find_map(PPC::from_message).unwrap();
+    assert!(ppc.changed_properties.is_empty());
+    assert_eq!(ppc.invalidated_properties, vec!("Level".to_string()));
+
+    let msg = Message::call_with_args("com.example.dbusrs.crossroads.defaultemits", "/",
+        "org.freedesktop.DBus.Properties", "Set", ("com.example.dbusrs.crossroads.defaultemits", "Limit", Variant(9u16)));
+    let r = dispatch_helper2(&mut cr, msg);
+    let ppc = r.iter().find_map(PPC::from_message).unwrap();
+    assert_eq!(ppc.changed_properties.get("Limit").unwrap().0.as_u64(), Some(9));
+}
+
+#[test]
+fn default_auto_emit() {
+    let mut cr = Crossroads::new();
+    cr.set_default_auto_emit(false);
+    let iface = cr.register("com.example.dbusrs.crossroads.defaultbatch", |b: &mut IfaceBuilder<u16>| {
+        b.property("Batched").get(|_, v| Ok(*v)).set(|_, v, n| { *v = n; Ok(Some(n)) });
+        b.property("Immediate").get(|_, v| Ok(*v)).set(|_, v, n| { *v = n; Ok(Some(n)) }).auto_emit();
+    });
+    cr.insert("/", &[iface], 0u16);
+    let token = cr.find_iface_token(&"/".into(), Some(&"com.example.dbusrs.crossroads.defaultbatch".into())).unwrap();
+    assert!(!cr.registry().auto_emit(token, "Batched"));
+    assert!(cr.registry().auto_emit(token, "Immediate"));
+
+    let set = |cr: &mut Crossroads, name: &str| {
+        let msg = Message::call_with_args("com.example.dbusrs.crossroads.defaultbatch", "/",
+            "org.freedesktop.DBus.Properties", "Set", ("com.example.dbusrs.crossroads.defaultbatch", name, Variant(4u16)));
+        dispatch_helper2(cr, msg)
+    };
+    let r = set(&mut cr, "Batched");
+    assert_eq!(r.len(), 1);
+    assert_eq!(r[0].msg_type(), dbus::message::MessageType::MethodReturn);
+    assert_eq!(cr.data_mut::<u16>(&"/".into()), Some(&mut 4));
+
+    let r = set(&mut cr, "Immediate");
+    assert_eq!(r.len(), 2);
+}
+
+#[test]
+fn introspect_xml_from_info() {
+    use crate::{IfaceInfoSkeleton, MethodInfo, SignalInfo, PropInfo, ArgInfo, Access};
+    let arg = |name: &str, sig: &str| ArgInfo { name: name.into(), sig: dbus::Signature::new(sig).unwrap(), annotations: Default::default() };
+    let mut iface = IfaceInfoSkeleton::new("com.example.dbusrs.Info".into());
+    iface.methods.push(MethodInfo {
+        name: "Lookup".into(),
+        input_args: vec!(arg("key", "s")),
+        output_args: vec!(arg("values", "a{sv}")),
+        annotations: Default::default(),
+    });
+    iface.signals.push(SignalInfo {
+        name: "Changed".into(),
+        args: vec!(arg("keys", "as")),
+        annotations: [("org.freedesktop.DBus.Deprecated".to_string(), "true".to_string())].iter().cloned().collect(),
+    });
+    iface.properties.push(PropInfo {
+        name: "Level".into(),
+        sig: dbus::Signature::new("(iu)").unwrap(),
+        access: Access::ReadWrite,
+        annotations: Default::default(),
+    });
+    let mut xml = String::from("<node>\n");
+    iface.introspect_xml(&mut xml);
+    assert_eq!(xml, r#"<node>
+  <interface name="com.example.dbusrs.Info">
+    <method name="Lookup">
+      <arg name="key" type="s" direction="in"/>
+      <arg name="values" type="a{sv}" direction="out"/>
+    </method>
+    <signal name="Changed">
+      <arg name="keys" type="as"/>
+      <annotation name="org.freedesktop.DBus.Deprecated" value="true"/>
+    </signal>
+    <property name="Level" type="(iu)" access="readwrite"/>
+  </interface>
+"#);
+    assert_eq!(iface.introspect(), xml["<node>\n".len()..]);
+}
+
+#[test]
+fn properties_get_set_getall() {
+    struct Lamp { brightness: u8, model: String }
+    let mut cr = Crossroads::new();
+    let iface = cr.register("com.example.dbusrs.crossroads.lamp", |b: &mut IfaceBuilder<Lamp>| {
+        b.property("Brightness")
+            .get(|_, lamp| Ok(lamp.brightness))
+            .set(|_, lamp, val| { lamp.brightness = val; Ok(Some(val)) });
+        b.property("Model").get(|_, lamp| Ok(lamp.model.clone()));
+    });
+    cr.insert("/lamp", &[iface], Lamp { brightness: 10, model: "L-100".into() });
+
+    fn call<A: dbus::arg::AppendAll>(cr: &mut Crossroads, method: &str, args: A) -> Message {
+        let mut msg = Message::new_method_call("com.example.dbusrs.crossroads.lamp", "/lamp",
+            "org.freedesktop.DBus.Properties", method).unwrap();
+        args.append(&mut dbus::arg::IterAppend::new(&mut msg));
+        let r = dispatch_helper2(cr, msg);
+        r.into_iter().find(|m| m.msg_type() != dbus::message::MessageType::Signal).unwrap()
+    }
+    const LAMP: &str = "com.example.dbusrs.crossroads.lamp";
+
+    let r = call(&mut cr, "Get", (LAMP, "Model"));
+    let q: Variant<String> = r.read1().unwrap();
+    assert_eq!(q.0, "L-100");
+
+    let r = call(&mut cr, "Set", (LAMP, "Brightness", Variant(55u8)));
+    assert_eq!(r.msg_type(), dbus::message::MessageType::MethodReturn);
+    let r = call(&mut cr, "Get", (LAMP, "Brightness"));
+    let q: Variant<u8> = r.read1().unwrap();
+    assert_eq!(q.0, 55);
+
+    let r = call(&mut cr, "GetAll", (LAMP,));
+    let q: PropMap = r.read1().unwrap();
+    assert_eq!(q.len(), 2);
+    assert_eq!(q.get("Brightness").unwrap().0.as_u64(), Some(55));
+    assert_eq!(q.get("Model").unwrap().0.as_str(), Some("L-100"));
+
+    let mut r = call(&mut cr, "Set", (LAMP, "Model", Variant("L-200")));
+    assert_eq!(r.as_result().unwrap_err().name(), Some("org.freedesktop.DBus.Error.PropertyReadOnly"));
+    let mut r = call(&mut cr, "Get", (LAMP, "Color"));
+    assert_eq!(r.as_result().unwrap_err().name(), Some("org.freedesktop.DBus.Error.UnknownProperty"));
+    let mut r = call(&mut cr, "Set", (LAMP, "Color", Variant(1u8)));
+    assert_eq!(r.as_result().unwrap_err().name(), Some("org.freedesktop.DBus.Error.UnknownProperty"));
+}
+
+#[test]
+fn peer_ping() {
+    let mut cr = Crossroads::new();
+    let iface = cr.register("com.example.dbusrs.crossroads.score", |b: &mut IfaceBuilder<()>| {
+        b.method("Ping", (), (), |_, _, _: ()| Ok(()));
+    });
+    cr.insert("/score", &[iface], ());
+    let msg = Message::new_method_call("com.example.dbusrs.crossroads.score", "/score", "org.freedesktop.DBus.Peer", "Ping").unwrap();
+    let r = dispatch_helper(&mut cr, msg);
+    assert_eq!(r.msg_type(), dbus::message::MessageType::MethodReturn);
+    assert!(r.get_items().is_empty());
+
+    cr.set_add_standard_ifaces(false);
+    cr.insert("/nopeer", &[iface], ());
+    let msg = Message::new_method_call("com.example.dbusrs.crossroads.score", "/nopeer", "org.freedesktop.DBus.Peer", "Ping").unwrap();
+    let mut r = dispatch_helper2(&mut cr, msg);
+    assert_eq!(r[0].as_result().unwrap_err().name(), Some("org.freedesktop.DBus.Error.UnknownInterface"));
+}
+
+#[test]
+fn peer_machine_id() {
+    let mut cr = Crossroads::new();
+    let msg = Message::new_method_call("com.example.dbusrs.crossroads.score", "/", "org.freedesktop.DBus.Peer", "GetMachineId").unwrap();
+    let r = dispatch_helper(&mut cr, msg);
+    let id: String = r.read1().unwrap();
+    assert_eq!(id.len(), 32);
+    assert!(id.bytes().all(|b| b.is_ascii_hexdigit()));
+}
+
+#[test]
+fn object_manager_two_children() {
+    struct Fruit { name: &'static str, weight: u32 }
+
+    let mut cr = Crossroads::new();
+    let fruit_token = cr.register::<Fruit, _, _>("com.example.dbusrs.fruit", |b| {
+        b.property("Name").get(|_, fruit| Ok(fruit.name.to_string()));
+        b.property("Weight").get(|_, fruit| Ok(fruit.weight));
+    });
+    cr.insert("/basket", &[cr.object_manager()], ());
+    cr.insert("/basket/apple", &[fruit_token], Fruit { name: "apple", weight: 150 });
+    cr.insert("/basket/pear", &[fruit_token], Fruit { name: "pear", weight: 180 });
+    cr.insert("/basketball", &[fruit_token], Fruit { name: "ball", weight: 600 });
+
+    let msg = Message::new_method_call("com.example.dbusrs.crossroads.score", "/basket",
+        "org.freedesktop.DBus.ObjectManager", "GetManagedObjects").unwrap();
+    let r = dispatch_helper(&mut cr, msg);
+    assert_eq!(&*r.iter_init().signature(), "a{oa{sa{sv}}}");
+
+    let mo: HashMap<dbus::Path<'static>, HashMap<String, PropMap>> = r.read1().unwrap();
+    assert_eq!(mo.len(), 2);
+    for (path, name, weight) in &[("/basket/apple", "apple", 150), ("/basket/pear", "pear", 180)] {
+        let ifaces = &mo[&dbus::Path::from(*path)];
+        assert_eq!(ifaces.len(), 1);
+        let props = &ifaces["com.example.dbusrs.fruit"];
+        assert_eq!(props.len(), 2);
+        assert_eq!(props["Name"].0.as_str(), Some(*name));
+        assert_eq!(props["Weight"].0.as_u64(), Some(*weight));
+    }
+}
+
+#[test]
+fn interfaces_added_empty() {
+    let mut cr = Crossroads::new();
+    let added = std::sync::Arc::new(std::sync::Mutex::new(vec!()));
+    cr.set_object_manager_support(Some(added.clone()));
+    cr.insert("/list", &[cr.object_manager()], ());
+    added.lock().unwrap().clear();
+
+    cr.insert("/list/empty", &[], ());
+    let v: Vec<_> = added.lock().unwrap().drain(..).collect();
+    assert_eq!(v.len(), 1);
+
+    use dbus::blocking::stdintf::org_freedesktop_dbus::ObjectManagerInterfacesAdded as OMIA;
+    use dbus::message::SignalArgs;
+    assert_eq!(&*v[0].path().unwrap(), "/list");
+    let mut i = v[0].iter_init();
+    assert_eq!(&*i.signature(), "o");
+    assert!(i.next());
+    assert_eq!(&*i.signature(), "a{sa{sv}}");
+    let omia = OMIA::from_message(&v[0]).unwrap();
+    assert_eq!(&*omia.object, "/list/empty");
+    assert!(omia.interfaces.is_empty());
+
+    assert_eq!(cr.remove::<()>(&"/list/empty".into()), Some(()));
+    let v: Vec<_> = added.lock().unwrap().drain(..).collect();
+    assert_eq!(v.len(), 1);
+    assert_eq!(&*v[0].member().unwrap(), "InterfacesRemoved");
+}
+
+#[test]
+fn write_only_property() {
+    let mut cr = Crossroads::new();
+    let iface = cr.register("com.example.dbusrs.crossroads.trigger", |b: &mut IfaceBuilder<u32>| {
+        b.property::<bool, _>("Fire").set(|_, count, _| { *count += 1; Ok(None) });
+        b.property("Count").get(|_, count| Ok(*count));
+    });
+    cr.insert("/trigger", &[iface], 0u32);
+
+    let msg = Message::new_method_call("com.example.dbusrs.crossroads.trigger", "/trigger",
+        "org.freedesktop.DBus.Introspectable", "Introspect").unwrap();
+    let r = dispatch_helper(&mut cr, msg);
+    let xml: &str = r.read1().unwrap();
+    assert!(xml.contains(r#"<property name="Fire" type="b" access="write"/>"#));
+
+    let msg = Message::call_with_args("com.example.dbusrs.crossroads.trigger", "/trigger",
+        "org.freedesktop.DBus.Properties", "Set", ("com.example.dbusrs.crossroads.trigger", "Fire", Variant(true)));
+    dispatch_helper(&mut cr, msg);
+    assert_eq!(cr.data_mut::<u32>(&"/trigger".into()), Some(&mut 1));
+
+    let msg = Message::call_with_args("com.example.dbusrs.crossroads.trigger", "/trigger",
+        "org.freedesktop.DBus.Properties", "Get", ("com.example.dbusrs.crossroads.trigger", "Fire"));
+    let mut r = dispatch_helper2(&mut cr, msg);
+    assert_eq!(r[0].as_result().unwrap_err().name(), Some("org.freedesktop.DBus.Error.PropertyWriteOnly"));
+
+    let msg = Message::call_with_args("com.example.dbusrs.crossroads.trigger", "/trigger",
+        "org.freedesktop.DBus.Properties", "GetAll", ("com.example.dbusrs.crossroads.trigger",));
+    let r = dispatch_helper(&mut cr, msg);
+    let q: PropMap = r.read1().unwrap();
+    assert_eq!(q.len(), 1);
+    assert_eq!(q["Count"].0.as_u64(), Some(1));
+}
+
+#[test]
+fn mutable_counter_property() {
+    let mut cr = Crossroads::new();
+    let iface = cr.register("com.example.dbusrs.crossroads.counter", |b: &mut IfaceBuilder<u64>| {
+        b.property("Reads").get(|_, count| { *count += 1; Ok(*count) });
+        b.property("Start").get(|_, count| Ok(*count)).set(|_, count, v| { *count = v; Ok(None) });
+    });
+    cr.insert("/counter", &[iface], 0u64);
+
+    fn get_reads(cr: &mut Crossroads) -> u64 {
+        let msg = Message::call_with_args("com.example.dbusrs.crossroads.counter", "/counter",
+            "org.freedesktop.DBus.Properties", "Get", ("com.example.dbusrs.crossroads.counter", "Reads"));
+        let r = dispatch_helper(cr, msg);
+        let v: Variant<u64> = r.read1().unwrap();
+        v.0
+    }
+    assert_eq!(get_reads(&mut cr), 1);
+    assert_eq!(get_reads(&mut cr), 2);
+
+    let msg = Message::call_with_args("com.example.dbusrs.crossroads.counter", "/counter",
+        "org.freedesktop.DBus.Properties", "Set", ("com.example.dbusrs.crossroads.counter", "Start", Variant(40u64)));
+    dispatch_helper(&mut cr, msg);
+    assert_eq!(get_reads(&mut cr), 41);
+    assert_eq!(cr.data_mut::<u64>(&"/counter".into()), Some(&mut 41));
+}
+
+#[test]
+fn emits_changed_builder() {
+    let mut cr = Crossroads::new();
+    let iface = cr.register("com.example.dbusrs.crossroads.emits", |b: &mut IfaceBuilder<u16>| {
+        b.property("Quiet").get(|_, v| Ok(*v)).set(|_, v, n| { *v = n; Ok(Some(n)) })
+            .emits_changed(EmitsChangedSignal::False);
+        b.property("Version").get(|_, _| Ok(3u16)).emits_changed(EmitsChangedSignal::Const);
+    });
+    cr.insert("/", &[iface], 0u16);
+
+    let msg = Message::new_method_call("com.example.dbusrs.crossroads.emits", "/",
+        "org.freedesktop.DBus.Introspectable", "Introspect").unwrap();
+    let r = dispatch_helper(&mut cr, msg);
+    let xml: &str = r.read1().unwrap();
+    assert!(xml.contains(r#"<property name="Quiet" type="q" access="readwrite">
+      <annotation name="org.freedesktop.DBus.Property.EmitsChangedSignal" value="false"/>"#));
+    assert!(xml.contains(r#"<property name="Version" type="q" access="read">
+      <annotation name="org.freedesktop.DBus.Property.EmitsChangedSignal" value="const"/>"#));
+
+    let msg = Message::call_with_args("com.example.dbusrs.crossroads.emits", "/",
+        "org.freedesktop.DBus.Properties", "Set", ("com.example.dbusrs.crossroads.emits", "Quiet", Variant(4u16)));
+    let r = dispatch_helper2(&mut cr, msg);
+    assert_eq!(r.len(), 1);
+    assert_eq!(r[0].msg_type(), dbus::message::MessageType::MethodReturn);
+}
+
+#[test]
+fn no_auto_emit() {
+    let mut cr = Crossroads::new();
+    let iface = cr.register("com.example.dbusrs.crossroads.batch", |b: &mut IfaceBuilder<u16>| {
+        b.property("Batched").get(|_, v| Ok(*v)).set(|_, v, n| { *v = n; Ok(Some(n)) }).no_auto_emit();
+        b.property("Immediate").get(|_, v| Ok(*v)).set(|_, v, n| { *v = n; Ok(Some(n)) });
+    });
+    cr.insert("/", &[iface], 0u16);
+    let token = cr.find_iface_token(&"/".into(), Some(&"com.example.dbusrs.crossroads.batch".into())).unwrap();
+    assert!(!cr.registry().auto_emit(token, "Batched"));
+    assert!(cr.registry().auto_emit(token, "Immediate"));
+
+    let set = |cr: &mut Crossroads, name: &str| {
+        let msg = Message::call_with_args("com.example.dbusrs.crossroads.batch", "/",
+            "org.freedesktop.DBus.Properties", "Set", ("com.example.dbusrs.crossroads.batch", name, Variant(4u16)));
+        dispatch_helper2(cr, msg)
+    };
+    let r = set(&mut cr, "Batched");
+    assert_eq!(r.len(), 1);
+    assert_eq!(r[0].msg_type(), dbus::message::MessageType::MethodReturn);
+    assert_eq!(cr.data_mut::<u16>(&"/".into()), Some(&mut 4));
+
+    let r = set(&mut cr, "Immediate");
+    assert_eq!(r.len(), 2);
+}
+
+#[test]
+fn iface_info_lookup() {
+    use crate::{IfaceInfoSkeleton, MethodInfo, SignalInfo, PropInfo, Access};
+    let mut iface = IfaceInfoSkeleton::new("com.example.dbusrs.Lookup".into());
+    iface.methods.push(MethodInfo { name: "Find".into(), input_args: vec!(), output_args: vec!(), annotations: Default::default() });
+    iface.signals.push(SignalInfo { name: "Found".into(), args: vec!(), annotations: Default::default() });
+    iface.properties.push(PropInfo {
+        name: "Count".into(),
+        sig: dbus::Signature::new("u").unwrap(),
+        access: Access::Read,
+        annotations: Default::default(),
+    });
+
+    assert_eq!(&*iface.method("Find").unwrap().name, "Find");
+    assert!(iface.method("Found").is_none());
+    assert_eq!(&*iface.signal("Found").unwrap().name, "Found");
+    assert!(iface.signal("Find").is_none());
+    assert_eq!(iface.prop("Count").unwrap().access, Access::Read);
+    assert!(iface.prop("Total").is_none());
+
+    iface.prop_mut("Count").unwrap().access = Access::ReadWrite;
+    assert_eq!(iface.prop("Count").unwrap().access, Access::ReadWrite);
+    iface.method_mut("Find").unwrap().annotations.insert("org.freedesktop.DBus.Deprecated".into(), "true".into());
+    assert_eq!(iface.method("Find").unwrap().annotations.len(), 1);
+    assert!(iface.signal_mut("Lost").is_none());
+    assert!(iface.method_mut("Lost").is_none());
+    assert!(iface.prop_mut("Lost").is_none());
+}
+
+#[test]
+fn enumerate_paths_and_interfaces() {
+    let mut cr = Crossroads::new();
+    let weight = cr.register("com.example.dbusrs.weight", |b: &mut IfaceBuilder<()>| {
+        b.method("Weigh", (), (), |_, _, _: ()| Ok(()));
+    });
+    let radius = cr.register("com.example.dbusrs.radius", |b: &mut IfaceBuilder<()>| {
+        b.method("Measure", (), (), |_, _, _: ()| Ok(()));
+    });
+    cr.insert("/fruit", &[weight, radius], ());
+    cr.insert("/empty", &[], ());
+
+    let mut names: Vec<&str> = cr.interfaces_at(&"/fruit".into()).map(|i| &**i).collect();
+    names.sort_unstable();
+    assert_eq!(names, vec!["com.example.dbusrs.radius", "com.example.dbusrs.weight",
+        "org.freedesktop.DBus.Introspectable", "org.freedesktop.DBus.Peer"]);
+    assert_eq!(cr.interfaces_at(&"/missing".into()).count(), 0);
+
+    let paths: Vec<&str> = cr.object_paths().map(|p| &**p).collect();
+    assert_eq!(paths, vec!["/", "/empty", "/fruit"]);
+}
@@ -25,7 +25,9 @@ impl Authentication {
         if !s.ends_with("\r\n") { Err("D-Bus authentication error (no newline)")? };
         let s = s.trim();
         match old_state {
-            Authentication::Error | Authentication::Begin(_) => Err("D-Bus invalid authentication state")?,
+            Authentication::Error => Err("D-Bus invalid authentication state")?,
+            // After BEGIN, only D-Bus messages may be sent over the stream.
+            Authentication::Begin(_) => Err("D-Bus authentication already completed")?,
             Authentication::WaitingForOK(b) => if s.starts_with("OK ") || s == "OK" {
                 if b {
                     *self = Authentication::WaitingForAgreeUnixFD;
@@ -49,6 +51,14 @@ impl Authentication {
         }
     }
 
+    /// Whether the handshake has completed, i e, the last reply to send is BEGIN.
+    ///
+    /// After this, no more authentication data must be handled.
+    pub fn is_complete(&self) -> bool { matches!(self, Authentication::Begin(_)) }
+
+    /// Whether the handshake has completed, and the server agreed to passing unix file descriptors.
+    pub fn unix_fd_negotiated(&self) -> bool { *self == Authentication::Begin(true) }
+
     pub fn blocking<R: std::io::BufRead, W: std::io::Write>(r: &mut R, w: &mut W, do_unix_fd: bool) -> Result<bool, Box<dyn std::error::Error>> {
         let (mut a, s) = Authentication::new(do_unix_fd);
         w.write_all(s.as_bytes())?;
@@ -67,6 +77,34 @@ impl Authentication {
     }
 }
 
+#[test]
+fn handshake_begin() {
+    let mut reader = std::io::Cursor::new(&b"OK 1234deadbeef\r\nAGREE_UNIX_FD\r\n"[..]);
+    let mut writer = vec!();
+    assert!(Authentication::blocking(&mut reader, &mut writer, true).unwrap());
+    assert!(writer.ends_with(b"\r\nNEGOTIATE_UNIX_FD\r\nBEGIN\r\n"));
+
+    let (mut a, _) = Authentication::new(true);
+    assert!(!a.is_complete());
+    assert_eq!(a.handle(b"OK 1234deadbeef\r\n").unwrap(), "NEGOTIATE_UNIX_FD\r\n");
+    assert!(!a.is_complete());
+    assert_eq!(a.handle(b"AGREE_UNIX_FD\r\n").unwrap(), "BEGIN\r\n");
+    assert!(a.is_complete());
+    assert!(a.unix_fd_negotiated());
+    assert!(a.handle(b"OK 1234deadbeef\r\n").is_err());
+    assert!(!a.is_complete());
+
+    let (mut a, _) = Authentication::new(true);
+    a.handle(b"OK 1234deadbeef\r\n").unwrap();
+    assert_eq!(a.handle(b"ERROR\r\n").unwrap(), "BEGIN\r\n");
+    assert!(a.is_complete());
+    assert!(!a.unix_fd_negotiated());
+
+    let (mut a, _) = Authentication::new(false);
+    assert_eq!(a.handle(b"OK 1234deadbeef\r\n").unwrap(), "BEGIN\r\n");
+    assert!(a.is_complete());
+    assert!(!a.unix_fd_negotiated());
+}
 
 #[test]
 fn session_auth() {
@@ -0,0 +1,29 @@
+//! Sending and receiving file descriptors over a unix socket, as ancillary data (SCM_RIGHTS).
+
+use std::io;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixStream;
+
+/// Sends data, and file descriptors if fds is not empty.
+///
+/// Returns the number of bytes sent, which might be less than data.len(). The file descriptors
+/// are sent along with the first byte, so they should not be sent again when sending the rest.
+pub fn send_with_fds(sock: &UnixStream, data: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+    crate::sys::send_with_fds(sock, data, fds)
+}
+
+// Space for this many file descriptors is reserved when receiving. This is the
+// maximum number of file descriptors per sendmsg call on Linux (SCM_MAX_FD).
+const MAX_RECV_FDS: usize = 253;
+
+/// Receives data, and appends any file descriptors received to fds.
+///
+/// Received file descriptors have the close-on-exec flag set, and are owned by the caller.
+///
+/// Returns the number of bytes received, and whether file descriptors were truncated, i e the
+/// sender sent more than there was room for. The kernel closes the ones that did not fit.
+/// The data and the file descriptors that did fit are received as usual even then, so that
+/// the caller can close the file descriptors, or tell which message they belong to.
+pub fn recv_with_fds(sock: &UnixStream, buf: &mut [u8], fds: &mut Vec<RawFd>) -> io::Result<(usize, bool)> {
+    crate::sys::recv_with_fds(sock, buf, fds, MAX_RECV_FDS)
+}
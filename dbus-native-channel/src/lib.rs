@@ -8,5 +8,7 @@ pub mod address;
 
 pub mod authentication;
 
+pub mod fdpassing;
+
 #[allow(unsafe_code)]
 mod sys;
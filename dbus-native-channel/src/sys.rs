@@ -1,4 +1,6 @@
+use std::io;
 use std::os::unix::net::UnixStream;
+use std::os::unix::io::{AsRawFd, RawFd};
 
 pub fn getuid() -> u32 {
     let x = unsafe { libc::getuid() };
@@ -32,3 +34,79 @@ pub fn connect_blocking(addr: &libc::sockaddr_un) -> Result<UnixStream, Box<dyn
     let u = unsafe { UnixStream::from_raw_fd(fd) };
     Ok(u)
 }
+
+fn cmsg_buffer(fd_count: usize) -> (Vec<u64>, usize) {
+    let space = unsafe { libc::CMSG_SPACE((fd_count * std::mem::size_of::<RawFd>()) as u32) } as usize;
+    // u64 rather than u8, so that the buffer is aligned for a cmsghdr
+    (vec![0u64; space / 8 + 1], space)
+}
+
+pub fn send_with_fds(sock: &UnixStream, data: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+    let mut iov = libc::iovec { iov_base: data.as_ptr() as *mut libc::c_void, iov_len: data.len() };
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    let (mut cmsg_buf, space) = cmsg_buffer(fds.len());
+    if !fds.is_empty() {
+        msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = space as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of_val(fds) as u32) as _;
+            std::ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut RawFd, fds.len());
+        }
+    }
+    let r = unsafe { libc::sendmsg(sock.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) };
+    if r < 0 { Err(io::Error::last_os_error()) } else { Ok(r as usize) }
+}
+
+pub fn recv_with_fds(sock: &UnixStream, buf: &mut [u8], fds: &mut Vec<RawFd>, max_fds: usize) -> io::Result<(usize, bool)> {
+    let mut iov = libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len() };
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    let (mut cmsg_buf, space) = cmsg_buffer(max_fds);
+    msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+    let r = unsafe { libc::recvmsg(sock.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if r < 0 { return Err(io::Error::last_os_error()) }
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                for i in 0..len / std::mem::size_of::<RawFd>() {
+                    fds.push(std::ptr::read_unaligned(data.add(i)));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    Ok((r as usize, msg.msg_flags & libc::MSG_CTRUNC != 0))
+}
+
+#[test]
+fn recv_truncated_fds() {
+    let (a, b) = UnixStream::pair().unwrap();
+    let (x, y) = UnixStream::pair().unwrap();
+    send_with_fds(&a, b"hi", &[x.as_raw_fd(), y.as_raw_fd(), x.as_raw_fd()]).unwrap();
+    let mut buf = [0u8; 16];
+    let mut fds = vec!();
+    let (n, truncated) = recv_with_fds(&b, &mut buf, &mut fds, 1).unwrap();
+    // The data is received, along with the file descriptors that fit.
+    assert_eq!(&buf[..n], b"hi");
+    assert!(truncated);
+    assert!(!fds.is_empty() && fds.len() < 3);
+    for fd in fds { unsafe { libc::close(fd); } }
+
+    send_with_fds(&a, b"ok", &[x.as_raw_fd()]).unwrap();
+    let mut fds = vec!();
+    let (n, truncated) = recv_with_fds(&b, &mut buf, &mut fds, 1).unwrap();
+    assert_eq!(&buf[..n], b"ok");
+    assert!(!truncated);
+    assert_eq!(fds.len(), 1);
+    unsafe { libc::close(fds[0]); }
+}
@@ -0,0 +1,54 @@
+//! Compares Multi::iter with Multi::iter_indexed, on a struct with 100 variable length fields.
+//!
+//! Run with "cargo bench -p dbus-native --bench multi_iter".
+
+use dbus_native::marshalled::{MultiBuf, StructBuf, VariantBuf, Parsed};
+use dbus_native::strings::{DBusStr, StringLike};
+use std::time::{Duration, Instant};
+
+const FIELDS: usize = 100;
+
+fn bench<F: FnMut() -> usize>(name: &str, mut f: F) {
+    // Warm up, and find a number of rounds that takes a reasonable time
+    let mut rounds = 1;
+    while { let t = Instant::now(); for _ in 0..rounds { f(); } t.elapsed() } < Duration::from_millis(100) {
+        rounds *= 2;
+    }
+    let t = Instant::now();
+    let mut check = 0;
+    for _ in 0..rounds { check += f(); }
+    let per_round = t.elapsed() / rounds;
+    println!("{:<40} {:>10?} per round (checksum {})", name, per_round, check / rounds as usize);
+}
+
+fn main() {
+    let mut fields = MultiBuf::new();
+    for i in 0..FIELDS {
+        if i % 2 == 0 {
+            fields.append(DBusStr::new(&"x".repeat(i)).unwrap()).unwrap();
+        } else {
+            fields.append(&VariantBuf::new(&vec!(i as u32; i)).unwrap()).unwrap();
+        }
+    }
+    let mut body = MultiBuf::new();
+    body.append(&StructBuf::new(fields).unwrap()).unwrap();
+    let body = body.multi();
+    let s = match body.iter().next().unwrap().unwrap().parse().unwrap() {
+        Parsed::Struct(s) => s,
+        _ => unreachable!(),
+    };
+
+    bench("sequential, iter", || {
+        s.iter().map(|x| x.unwrap().position()).sum()
+    });
+    bench("sequential, iter_indexed", || {
+        s.iter_indexed().unwrap().map(|x| x.position()).sum()
+    });
+    bench("every index, iter().nth", || {
+        (0..FIELDS).map(|i| s.iter().nth(i).unwrap().unwrap().position()).sum()
+    });
+    bench("every index, iter_indexed().get", || {
+        let idx = s.iter_indexed().unwrap();
+        (0..FIELDS).map(|i| idx.get(i).unwrap().position()).sum()
+    });
+}
@@ -5,7 +5,7 @@
 ///
 /// No stability guarantees for this crate.
 
-pub use dbus_native_channel::{machineid, address, authentication};
+pub use dbus_native_channel::{machineid, address, authentication, fdpassing};
 
 pub mod message;
 
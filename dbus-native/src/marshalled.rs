@@ -7,17 +7,100 @@ const IS_BIG_ENDIAN: bool = true;
 
 const ARRAY_MAX_LEN: usize = 67108864;
 
+/// The maximum number of nested containers (arrays, structs, dict entries and variants combined).
+const MAX_DEPTH: u8 = 32;
+
+fn nested_depth(depth: u8) -> Result<u8, DemarshalError> {
+    if depth >= MAX_DEPTH { Err(DemarshalError::RecursionLimit) } else { Ok(depth + 1) }
+}
+
 use std::str::from_utf8;
 use std::mem;
+use std::fmt;
 use dbus_strings::{SignatureMulti, SignatureMultiBuf, SignatureSingle, SignatureSingleBuf, StringLike, DBusStr};
-use std::convert::TryInto;
-use crate::types::DemarshalError;
+use std::convert::{TryFrom, TryInto};
+use std::sync::Mutex;
+use std::collections::HashMap;
+use std::borrow::Cow;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use crate::types::{DemarshalError, DemarshalErrorAt, MarshalError};
+
+/// Controls how forgiving the parser is towards data that does not strictly follow
+/// the D-Bus specification.
+///
+/// Every field enables one check; the default is lenient, i e, all checks disabled.
+#[derive(Clone, Debug, Copy, Default, PartialEq, Eq)]
+pub struct ParseStrictness {
+    /// Padding bytes must be zero.
+    pub check_padding: bool,
+    /// Strings and signatures must be followed by a nul byte.
+    pub check_nul: bool,
+    /// A dictionary must not contain the same key twice.
+    pub check_dict_keys: bool,
+    /// A message header must not contain the same header field twice.
+    pub check_header_fields: bool,
+    /// A message body must not contain data after its last value.
+    pub check_trailing_data: bool,
+    /// A signal must have an interface header field.
+    pub check_signal_interface: bool,
+    /// A message must have a sender header field.
+    ///
+    /// Messages routed through a bus always have one, but on peer-to-peer connections
+    /// (i e, without a bus daemon), the sender is usually absent.
+    pub check_sender: bool,
+    /// The maximum number of fields in a message header, or None for no limit.
+    pub max_header_fields: Option<u32>,
+    /// The maximum size in bytes of the header fields array, or None for the limit
+    /// in the D-Bus specification (64 MiB) only.
+    pub max_header_size: Option<u32>,
+    /// The number of file descriptors sent along with the data, or None to not check
+    /// UnixFd values. A UnixFd value is an index into these file descriptors, so it must be
+    /// less than this number.
+    ///
+    /// When demarshalling a message, this is set from its UNIX_FDS header field, or to zero
+    /// if the message has no such field.
+    pub max_unix_fds: Option<u32>,
+}
+
+impl ParseStrictness {
+    /// All checks enabled, except check_sender, which only makes sense on bus connections.
+    pub fn strict() -> Self {
+        ParseStrictness {
+            check_padding: true,
+            check_nul: true,
+            check_dict_keys: true,
+            check_header_fields: true,
+            check_trailing_data: true,
+            check_signal_interface: true,
+            check_sender: false,
+            max_header_fields: None,
+            max_header_size: None,
+            max_unix_fds: None,
+        }
+    }
+
+    /// All checks disabled, and no limits besides the ones in the D-Bus specification.
+    pub fn lenient() -> Self { Default::default() }
+
+    fn padding(&self, data: &[u8]) -> Result<(), DemarshalError> {
+        if self.check_padding && data.iter().any(|&b| b != 0) { Err(DemarshalError::InvalidProtocol)? }
+        Ok(())
+    }
+
+    fn nul(&self, data: &[u8], pos: usize) -> Result<(), DemarshalError> {
+        if self.check_nul && data.get(pos) != Some(&0) { Err(DemarshalError::InvalidString)? }
+        Ok(())
+    }
+}
 
 #[derive(Clone, Debug, Copy)]
 pub struct Multi<'a> {
     sig: &'a SignatureMulti,
     data: &'a [u8],
+    start_pos: usize,
     is_big_endian: bool,
+    strictness: ParseStrictness,
+    depth: u8,
 }
 
 #[derive(Clone, Debug, Copy)]
@@ -26,31 +109,137 @@ pub struct MultiIter<'a> {
     start_pos: usize,
 }
 
+/// The values of a Multi, with the boundaries of all values computed up front.
+///
+/// Returned from Multi::iter_indexed.
+#[derive(Clone, Debug)]
+pub struct MultiIndexed<'a> {
+    values: Vec<Single<'a>>,
+    next: usize,
+}
+
 #[derive(Clone, Debug, Copy)]
 pub struct Single<'a> {
     sig: &'a SignatureSingle,
     data: &'a [u8],
     start_pos: usize,
     is_big_endian: bool,
+    strictness: ParseStrictness,
+    depth: u8,
 }
 
 impl<'a> Multi<'a> {
     pub fn new(sig: &'a SignatureMulti, data: &'a [u8], is_big_endian: bool) -> Self {
-        Multi { sig, data, is_big_endian }
+        Multi { sig, data, start_pos: 0, is_big_endian, strictness: Default::default(), depth: 0 }
     }
 
-    fn get_real_length(&self) -> Result<usize, DemarshalError> {
+    /// Sets which checks to perform when parsing the values.
+    pub fn with_strictness(mut self, strictness: ParseStrictness) -> Self {
+        self.strictness = strictness;
+        self
+    }
+
+    pub (crate) fn get_real_length(&self) -> Result<usize, DemarshalError> {
         let x = self.data.len();
         let mut iter = self.iter();
         while let Some(r) = iter.next() { r?; }
         Ok(x - iter.inner.data.len())
     }
 
+    /// Creates a Multi borrowing from data received through the bytes crate.
+    #[cfg(feature = "bytes")]
+    pub fn from_bytes(sig: &'a SignatureMulti, data: &'a bytes::Bytes, is_big_endian: bool) -> Self {
+        Self::new(sig, data, is_big_endian)
+    }
+
     pub fn iter(&self) -> MultiIter<'a> {
-        MultiIter { inner: *self, start_pos: 0 }
+        MultiIter { inner: *self, start_pos: self.start_pos }
+    }
+
+    /// Finds the boundaries of all values at once, so that they can be accessed by index
+    /// without walking over the values before them.
+    ///
+    /// Returns the first error found, if any value is invalid.
+    pub fn iter_indexed(&self) -> Result<MultiIndexed<'a>, DemarshalError> {
+        let values = self.iter().collect::<Result<_, _>>()?;
+        Ok(MultiIndexed { values, next: 0 })
+    }
+
+    /// The signature of all values.
+    pub fn signature(&self) -> &'a SignatureMulti { self.sig }
+
+    /// The marshalled data of all values.
+    pub fn raw_data(&self) -> &'a [u8] { self.data }
+
+    /// Parses all values, stopping at the first error.
+    pub fn to_vec(&self) -> Result<Vec<Parsed<'a>>, DemarshalError> {
+        self.iter().map(|x| x?.parse()).collect()
+    }
+
+    /// Reads exactly N values, all of type T, e g the fields of a struct like "(dddd)".
+    ///
+    /// Fails if there are more or fewer than N values, or if any of them is not a T.
+    pub fn read_array<T: Demarshal<'a>, const N: usize>(&self) -> Result<[T; N], DemarshalError> {
+        let v: Vec<T> = self.iter().map(|x| T::demarshal(&x?)).collect::<Result<_, _>>()?;
+        v.try_into().map_err(|_| DemarshalError::wrong_type(None, Some(self.sig)))
+    }
+
+    /// Copies all values into a new buffer in little endian byte order, swapping bytes and
+    /// adjusting padding as needed.
+    pub fn to_owned_le(&self) -> Result<MultiBuf, DemarshalError> {
+        let mut r = MultiBuf::with_endianness(false);
+        for x in self.iter() {
+            let x = x?;
+            r.push_sig(x.sig);
+            x.append_ordered(&mut r.data, r.swap_bytes)?;
+        }
+        Ok(r)
+    }
+
+    /// Compares all values with the values of another Multi, see Single::value_eq.
+    pub fn body_eq(&self, other: &Multi) -> Result<bool, DemarshalError> {
+        if self.sig != other.sig { return Ok(false) }
+        for (a, b) in self.iter().zip(other.iter()) {
+            if !a?.value_eq(&b?)? { return Ok(false) }
+        }
+        Ok(true)
+    }
+
+    /// The byte order of the underlying data.
+    pub fn is_big_endian(&self) -> bool { self.is_big_endian }
+}
+
+impl MultiIter<'_> {
+    /// The offset of the next value, counted from the start of the outermost buffer.
+    ///
+    /// If next() returned an error, this is the offset of the value that failed.
+    pub fn position(&self) -> usize { self.start_pos }
+}
+
+impl<'a> MultiIndexed<'a> {
+    /// The value at the given index, regardless of how far the iteration has come.
+    pub fn get(&self, index: usize) -> Option<Single<'a>> { self.values.get(index).copied() }
+
+    /// The total number of values, including the ones already iterated over.
+    pub fn total_len(&self) -> usize { self.values.len() }
+}
+
+impl<'a> Iterator for MultiIndexed<'a> {
+    type Item = Single<'a>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let r = self.values.get(self.next).copied();
+        if r.is_some() { self.next += 1; }
+        r
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let n = self.values.len() - self.next;
+        (n, Some(n))
     }
 }
 
+impl ExactSizeIterator for MultiIndexed<'_> {}
+
 impl<'a> Iterator for MultiIter<'a> {
     type Item = Result<Single<'a>, DemarshalError>;
     fn next(&mut self) -> Option<Self::Item> {
@@ -60,12 +249,18 @@ impl<'a> Iterator for MultiIter<'a> {
                 data: self.inner.data,
                 start_pos: self.start_pos,
                 is_big_endian: self.inner.is_big_endian,
+                strictness: self.inner.strictness,
+                depth: self.inner.depth,
             };
-            let mut len = s.get_real_length()?;
+            let real_len = s.get_real_length()?;
+            let mut len = real_len;
             if rest.len() > 0 {
                 len = align_up(len + self.start_pos, align_of(rest.as_bytes()[0])) - self.start_pos;
             }
-            if len > self.inner.data.len() { Err(DemarshalError::NotEnoughData)? }
+            if len > self.inner.data.len() {
+                Err(DemarshalError::not_enough_data(self.start_pos, len, self.inner.data.len()))?
+            }
+            self.inner.strictness.padding(&self.inner.data[real_len..len])?;
             let (fdata, rdata) = self.inner.data.split_at(len);
             s.data = fdata;
             self.inner.data = rdata;
@@ -80,6 +275,11 @@ pub fn align_up(pos: usize, align: usize) -> usize {
     (pos + align - 1) & !(align - 1)
 }
 
+/// Like align_up, but returns None instead of overflowing.
+fn checked_align_up(pos: usize, align: usize) -> Option<usize> {
+    Some(pos.checked_add(align - 1)? & !(align - 1))
+}
+
 pub fn align_of(c: u8) -> usize {
     match c {
         b'y' | b'g' | b'v' => 1,
@@ -91,68 +291,101 @@ pub fn align_of(c: u8) -> usize {
     }
 }
 
+/// The size of a fixed size type, or None if the size depends on the value.
+pub fn fixed_size_of(c: u8) -> Option<usize> {
+    match c {
+        b'y' => Some(1),
+        b'n' | b'q' => Some(2),
+        b'i' | b'u' | b'b' | b'h' => Some(4),
+        b'x' | b't' | b'd' => Some(8),
+        _ => None,
+    }
+}
+
 impl<'a> Single<'a> {
+    fn not_enough_data(&self, offset: usize, needed: usize) -> DemarshalError {
+        DemarshalError::not_enough_data(self.start_pos + offset, needed, self.data.len().saturating_sub(offset))
+    }
+
+    // The read_* helpers read from the start of self.data, i e the value's own slice,
+    // which is already aligned. They do not use start_pos, which is only used for error reporting.
     fn read_f64(&self) -> Result<f64, DemarshalError> {
-        let x: [u8; 8] = self.data[0..8].try_into().map_err(|_| DemarshalError::NotEnoughData)?;
+        let x: [u8; 8] = self.data.get(0..8).and_then(|x| x.try_into().ok()).ok_or_else(|| self.not_enough_data(0, 8))?;
         Ok(if self.is_big_endian { f64::from_be_bytes(x) } else { f64::from_le_bytes(x) })
     }
 
     fn read8(&self) -> Result<u64, DemarshalError> {
-        let x: [u8; 8] = self.data[0..8].try_into().map_err(|_| DemarshalError::NotEnoughData)?;
+        let x: [u8; 8] = self.data.get(0..8).and_then(|x| x.try_into().ok()).ok_or_else(|| self.not_enough_data(0, 8))?;
         Ok(if self.is_big_endian { u64::from_be_bytes(x) } else { u64::from_le_bytes(x) })
     }
 
     fn read4(&self) -> Result<u32, DemarshalError> {
-        let x: [u8; 4] = self.data[0..4].try_into().map_err(|_| DemarshalError::NotEnoughData)?;
+        let x: [u8; 4] = self.data.get(0..4).and_then(|x| x.try_into().ok()).ok_or_else(|| self.not_enough_data(0, 4))?;
         Ok(if self.is_big_endian { u32::from_be_bytes(x) } else { u32::from_le_bytes(x) })
     }
 
     fn read2(&self) -> Result<u16, DemarshalError> {
-        let x: [u8; 2] = self.data[0..1].try_into().map_err(|_| DemarshalError::NotEnoughData)?;
+        let x: [u8; 2] = self.data.get(0..2).and_then(|x| x.try_into().ok()).ok_or_else(|| self.not_enough_data(0, 2))?;
         Ok(if self.is_big_endian { u16::from_be_bytes(x) } else { u16::from_le_bytes(x) })
     }
 
     fn read1(&self) -> Result<u8, DemarshalError> {
-        self.data.get(0).ok_or(DemarshalError::NotEnoughData).map(|x| *x)
+        self.data.first().copied().ok_or_else(|| self.not_enough_data(0, 1))
     }
 
     fn read_sig(&self) -> Result<&'a SignatureMulti, DemarshalError> {
         let siglen = self.read1()? as usize;
-        let sig = self.data.get(1..siglen+1).ok_or(DemarshalError::NotEnoughData)?;
+        let sig = self.data.get(1..siglen+1).ok_or_else(|| self.not_enough_data(0, siglen+1))?;
+        self.strictness.nul(self.data, siglen+1)?;
         from_utf8(sig).ok().and_then(|s| SignatureMulti::new(s).ok()).ok_or(DemarshalError::InvalidString)
     }
 
     fn read_str<T: StringLike+ ?Sized>(&self) -> Result<&'a T, DemarshalError> {
         let len = self.read4()? as usize;
-        let s = self.data.get(4..len+4).ok_or(DemarshalError::NotEnoughData)?;
+        let s = self.data.get(4..len+4).ok_or_else(|| self.not_enough_data(0, len+4))?;
+        self.strictness.nul(self.data, len+4)?;
         from_utf8(s).ok().and_then(|s| T::new(s).ok()).ok_or(DemarshalError::InvalidString)
     }
 
     fn inner_variant(&self) -> Result<Single<'a>, DemarshalError> {
+        let depth = nested_depth(self.depth)?;
         let siglen = self.read1()? as usize;
-        let sig = self.data.get(1..siglen+1).ok_or(DemarshalError::NotEnoughData)?;
+        let sig = self.data.get(1..siglen+1).ok_or_else(|| self.not_enough_data(0, siglen+1))?;
         let sig = from_utf8(sig).ok().and_then(|s| SignatureSingle::new(s).ok()).ok_or(DemarshalError::InvalidString)?;
-        let data_start = align_up(self.start_pos + siglen+2, align_of(sig.as_bytes()[0])) - self.start_pos;
-        Ok(Single {
+        self.strictness.nul(self.data, siglen+1)?;
+        let data_start = self.start_pos.checked_add(siglen + 2)
+            .and_then(|x| checked_align_up(x, align_of(sig.as_bytes()[0])))
+            .ok_or(DemarshalError::NumberTooBig)? - self.start_pos;
+        self.strictness.padding(self.data.get(siglen+2..data_start).unwrap_or(&[]))?;
+        let mut inner = Single {
             sig,
             start_pos: self.start_pos + data_start,
-            data: self.data.get(data_start..).ok_or(DemarshalError::NotEnoughData)?,
+            data: self.data.get(data_start..).ok_or_else(|| self.not_enough_data(0, data_start))?,
             is_big_endian: self.is_big_endian,
-        })
+            strictness: self.strictness,
+            depth,
+        };
+        // The inner value must fit within the data of the variant.
+        let len = inner.get_real_length()?;
+        inner.data = inner.data.get(..len).ok_or_else(|| inner.not_enough_data(0, len))?;
+        Ok(inner)
     }
 
-    fn inner_struct(&self) -> Multi<'a> {
+    fn inner_struct(&self) -> Result<Multi<'a>, DemarshalError> {
         let s: &str = self.sig;
         let (s0, s) = s.split_at(1);
         let (s, s9) = s.split_at(s.len() - 1);
 
         debug_assert_eq!(s0, "(");
         debug_assert_eq!(s9, ")");
-        Multi {
+        Ok(Multi {
             sig: SignatureMulti::new_unchecked(s),
             data: self.data,
+            start_pos: self.start_pos,
             is_big_endian: self.is_big_endian,
-        }
+            strictness: self.strictness,
+            depth: nested_depth(self.depth)?,
+        })
     }
 
     fn get_real_length(&self) -> Result<usize, DemarshalError> {
@@ -165,46 +398,66 @@ impl<'a> Single<'a> {
             b'g' => self.read1()? as usize + 1 + 1,
             b'a' => {
                 let x = self.read4()? as usize;
-                if x > 67108864 { Err(DemarshalError::NumberTooBig)? };
-                x + 4
+                if x > ARRAY_MAX_LEN { Err(DemarshalError::NumberTooBig)? };
+                // Elements with 8 byte alignment might need padding after the length.
+                align_up(self.start_pos + 4, align_of(self.sig.as_bytes()[1])) - self.start_pos + x
             },
             b'v' => {
                 let x = self.inner_variant()?;
-                x.get_real_length()? + (self.data.len() - x.data.len())
+                x.data.len() + (x.start_pos - self.start_pos)
             },
-            b'(' => self.inner_struct().get_real_length()?,
+            b'(' => self.inner_struct()?.get_real_length()?,
             c => panic!("Unexpected byte in type signature: {}", c)
         })
     }
 
     fn parse_array(&self) -> Result<Parsed<'a>, DemarshalError> {
         let x = self.read4()? as usize;
-        if x > 67108864 { Err(DemarshalError::NumberTooBig)? };
+        if x > ARRAY_MAX_LEN { Err(DemarshalError::NumberTooBig)? };
+        let depth = nested_depth(self.depth)?;
         Ok(if self.sig.as_bytes()[1] == b'{' {
             let inner_sig = SignatureMulti::new_unchecked(&self.sig[2..self.sig.len()-1]);
             let (key_sig, value_sig) = inner_sig.single().unwrap();
             let (value_sig, _) = value_sig.single().unwrap();
             let data_start = align_up(self.start_pos + 4, align_of(b'{')) - self.start_pos;
-            if data_start + x > self.data.len() { Err(DemarshalError::NotEnoughData)? };
-            Parsed::Dict(Dict {
+            if data_start + x > self.data.len() { Err(self.not_enough_data(data_start, x))? };
+            self.strictness.padding(&self.data[4..data_start])?;
+            let dict = Dict {
                 outer_sig: self.sig,
                 key_sig, value_sig,
                 is_big_endian: self.is_big_endian,
                 data: &self.data[data_start..data_start + x],
-            })
+                all_data: &self.data[data_start..data_start + x],
+                start_pos: self.start_pos + data_start,
+                strictness: self.strictness,
+                depth,
+            };
+            if self.strictness.check_dict_keys { dict.check_unique_keys()?; }
+            Parsed::Dict(dict)
         } else {
             let inner_sig = SignatureSingle::new_unchecked(&self.sig[1..]);
+            if let Some(size) = fixed_size_of(inner_sig.as_bytes()[0]) {
+                if x & (size - 1) != 0 { Err(DemarshalError::MisalignedArrayLength)? }
+            }
             let data_start = align_up(self.start_pos + 4, align_of(inner_sig.as_bytes()[0])) - self.start_pos;
-            if data_start + x > self.data.len() { Err(DemarshalError::NotEnoughData)? };
+            if data_start + x > self.data.len() { Err(self.not_enough_data(data_start, x))? };
+            self.strictness.padding(&self.data[4..data_start])?;
             Parsed::Array(Array {
                 data: &self.data[data_start..data_start + x],
+                all_data: &self.data[data_start..data_start + x],
                 start_pos: data_start + self.start_pos,
                 is_big_endian: self.is_big_endian,
                 inner_sig,
+                strictness: self.strictness,
+                depth,
             })
         })
     }
 
+    /// Parses the value.
+    ///
+    /// Strings, object paths and signatures are validated, and then borrowed directly
+    /// from the underlying data, without copying.
     pub fn parse(&self) -> Result<Parsed<'a>, DemarshalError> {
         Ok(match self.sig.as_bytes()[0] {
             b'y' => Parsed::Byte(self.read1()?),
@@ -217,7 +470,13 @@ impl<'a> Single<'a> {
                 1 => true,
                 _ => Err(DemarshalError::InvalidBoolean)?
             }),
-            b'h' => Parsed::UnixFd(self.read4()? as usize),
+            b'h' => {
+                let index = self.read4()?;
+                if let Some(count) = self.strictness.max_unix_fds {
+                    if index >= count { Err(DemarshalError::UnixFdOutOfRange { index, count })? }
+                }
+                Parsed::UnixFd(index as usize)
+            },
             b'x' => Parsed::Int64(self.read8()? as i64),
             b't' => Parsed::UInt64(self.read8()?),
             b'd' => Parsed::Double(self.read_f64()?),
@@ -225,14 +484,255 @@ impl<'a> Single<'a> {
             b's' => Parsed::String(self.read_str()?),
             b'o' => Parsed::ObjectPath(self.read_str()?),
             b'v' => Parsed::Variant(self.inner_variant()?),
-            b'(' => Parsed::Struct(self.inner_struct()),
+            b'(' => Parsed::Struct(self.inner_struct()?),
             b'a' => self.parse_array()?,
             c => panic!("Unexpected byte in type signature: {}", c)
         })
     }
 
+    /// Copies the value to the end of a buffer, in native byte order, and returns its signature.
+    ///
+    /// The value is padded to start at an 8 byte boundary. The copy can later be read with
+    /// Single::new, with the signature, the buffer from that boundary, and native byte order.
+    pub fn read_into(&self, out: &mut Vec<u8>) -> Result<SignatureSingleBuf, DemarshalError> {
+        align_buf(out, 8);
+        self.append_native(out)?;
+        Ok(self.sig.into())
+    }
+
+    fn append_native(&self, v: &mut Vec<u8>) -> Result<(), DemarshalError> {
+        self.append_ordered(v, false)
+    }
+
+    // Like append_native, but if swap is true, the bytes of all numbers (including the
+    // lengths of strings and arrays) are swapped, i e written in the non-native byte order.
+    fn append_ordered(&self, v: &mut Vec<u8>, swap: bool) -> Result<(), DemarshalError> {
+        fn swap_at(v: &mut [u8], pos: usize, n: usize, swap: bool) {
+            if swap { v[pos..pos+n].reverse() }
+        }
+        fn append_num<T: Marshal>(x: T, n: usize, v: &mut Vec<u8>, swap: bool) {
+            x.append_data_to(v);
+            let pos = v.len() - n;
+            swap_at(v, pos, n, swap);
+        }
+        fn append_str(x: &DBusStr, v: &mut Vec<u8>, swap: bool) {
+            align_buf(v, 4);
+            let pos = v.len();
+            x.append_data_to(v);
+            swap_at(v, pos, 4, swap);
+        }
+        match self.parse()? {
+            Parsed::Byte(x) => x.append_data_to(v),
+            Parsed::Int16(x) => append_num(x, 2, v, swap),
+            Parsed::UInt16(x) => append_num(x, 2, v, swap),
+            Parsed::Int32(x) => append_num(x, 4, v, swap),
+            Parsed::UInt32(x) => append_num(x, 4, v, swap),
+            Parsed::Boolean(x) => append_num(x, 4, v, swap),
+            Parsed::UnixFd(x) => append_num(x as u32, 4, v, swap),
+            Parsed::Int64(x) => append_num(x, 8, v, swap),
+            Parsed::UInt64(x) => append_num(x, 8, v, swap),
+            Parsed::Double(x) => append_num(x, 8, v, swap),
+            Parsed::Signature(x) => x.append_data_to(v),
+            Parsed::String(x) => append_str(x, v, swap),
+            Parsed::ObjectPath(x) => append_str(x.as_dbus_str(), v, swap),
+            Parsed::Variant(x) => {
+                x.sig.append_data_to(v);
+                x.append_ordered(v, swap)?;
+            },
+            Parsed::Struct(x) => {
+                align_buf(v, 8);
+                for y in x.iter() { y?.append_ordered(v, swap)?; }
+            },
+            Parsed::Array(x) => {
+                align_buf(v, 4);
+                let len_pos = v.len();
+                0u32.append_data_to(v);
+                align_buf(v, align_of(x.inner_sig.as_bytes()[0]));
+                let start = v.len();
+                for y in x { y?.append_ordered(v, swap)?; }
+                let slen = (v.len() - start) as u32;
+                v[len_pos..len_pos+4].copy_from_slice(&slen.to_ne_bytes());
+                swap_at(v, len_pos, 4, swap);
+            },
+            Parsed::Dict(x) => {
+                align_buf(v, 4);
+                let len_pos = v.len();
+                0u32.append_data_to(v);
+                align_buf(v, 8);
+                let start = v.len();
+                for y in x {
+                    let (key, value) = y?;
+                    align_buf(v, 8);
+                    key.append_ordered(v, swap)?;
+                    value.append_ordered(v, swap)?;
+                }
+                let slen = (v.len() - start) as u32;
+                v[len_pos..len_pos+4].copy_from_slice(&slen.to_ne_bytes());
+                swap_at(v, len_pos, 4, swap);
+            },
+        }
+        Ok(())
+    }
+
+    // Parses the value, and all values inside it.
+    fn validate(&self) -> Result<(), DemarshalError> {
+        match self.parse()? {
+            Parsed::Variant(x) => x.validate()?,
+            Parsed::Struct(x) => for y in x.iter() { y?.validate()?; },
+            Parsed::Array(x) => for y in x { y?.validate()?; },
+            Parsed::Dict(x) => for y in x {
+                let (key, value) = y?;
+                key.validate()?;
+                value.validate()?;
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+
+    /// Like parse, but on error, also tells where in the buffer the error occurred.
+    pub fn parse_at(&self) -> Result<Parsed<'a>, DemarshalErrorAt> {
+        self.parse().map_err(|error| {
+            let offset = match error {
+                DemarshalError::NotEnoughData { at, .. } => at,
+                _ => self.start_pos,
+            };
+            DemarshalErrorAt { error, offset }
+        })
+    }
+
+    /// Compares the value with another value, which might have a different byte order.
+    ///
+    /// Doubles are compared bitwise, rather than with IEEE-754 comparison. This means that
+    /// a NaN is equal to a NaN with the same bit pattern, and that 0.0 is not equal to -0.0.
+    /// Arrays and dictionaries are equal only if their elements come in the same order.
+    pub fn value_eq(&self, other: &Single) -> Result<bool, DemarshalError> {
+        fn basic_bits(p: &Parsed) -> Option<u64> {
+            Some(match *p {
+                Parsed::Boolean(x) => x as u64,
+                Parsed::Byte(x) => x as u64,
+                Parsed::Int16(x) => x as u16 as u64,
+                Parsed::UInt16(x) => x as u64,
+                Parsed::Int32(x) => x as u32 as u64,
+                Parsed::UInt32(x) => x as u64,
+                Parsed::Int64(x) => x as u64,
+                Parsed::UInt64(x) => x,
+                Parsed::Double(x) => x.to_bits(),
+                Parsed::UnixFd(x) => x as u64,
+                _ => return None,
+            })
+        }
+        if self.sig != other.sig { return Ok(false) }
+        Ok(match (self.parse()?, other.parse()?) {
+            (Parsed::Array(mut a), Parsed::Array(mut b)) => loop {
+                match (a.next(), b.next()) {
+                    (None, None) => break true,
+                    (Some(x), Some(y)) => if !x?.value_eq(&y?)? { break false },
+                    _ => break false,
+                }
+            },
+            (Parsed::Dict(mut a), Parsed::Dict(mut b)) => loop {
+                match (a.next(), b.next()) {
+                    (None, None) => break true,
+                    (Some(x), Some(y)) => {
+                        let ((xk, xv), (yk, yv)) = (x?, y?);
+                        if !xk.value_eq(&yk)? || !xv.value_eq(&yv)? { break false }
+                    },
+                    _ => break false,
+                }
+            },
+            (Parsed::Struct(a), Parsed::Struct(b)) => a.body_eq(&b)?,
+            (Parsed::Variant(a), Parsed::Variant(b)) => a.value_eq(&b)?,
+            (a, b) => match (a.as_dbus_str(), b.as_dbus_str()) {
+                (Ok(a), Ok(b)) => a == b,
+                _ => basic_bits(&a) == basic_bits(&b),
+            },
+        })
+    }
+
+    /// The offset of this value, counted from the start of the outermost buffer.
+    pub fn position(&self) -> usize { self.start_pos }
+
+    /// Reads an IP address, encoded either as "ay" or as "(iay)", see IpAddrEncoding.
+    pub fn read_ip_addr(&self) -> Result<IpAddr, DemarshalError> {
+        fn from_bytes(family: Option<i32>, a: Array) -> Result<IpAddr, DemarshalError> {
+            if &**a.inner_sig != "y" { Err(DemarshalError::wrong_type(Some("ay"), Some(&format!("a{}", a.inner_sig))))? }
+            match (family, a.data.len()) {
+                (None, 4) | (Some(AF_INET), 4) => {
+                    let x: [u8; 4] = a.data.try_into().unwrap();
+                    Ok(Ipv4Addr::from(x).into())
+                },
+                (None, 16) | (Some(AF_INET6), 16) => {
+                    let x: [u8; 16] = a.data.try_into().unwrap();
+                    Ok(Ipv6Addr::from(x).into())
+                },
+                _ => Err(DemarshalError::InvalidProtocol),
+            }
+        }
+        match self.parse()? {
+            Parsed::Array(a) => from_bytes(None, a),
+            Parsed::Struct(m) => {
+                if &**m.signature() != "iay" { Err(DemarshalError::wrong_type(Some("(iay)"), Some(&format!("({})", m.signature()))))? }
+                let mut iter = m.iter();
+                let family = match iter.next().unwrap()?.parse()? {
+                    Parsed::Int32(x) => x,
+                    _ => unreachable!(),
+                };
+                match iter.next().unwrap()?.parse()? {
+                    Parsed::Array(a) => from_bytes(Some(family), a),
+                    _ => unreachable!(),
+                }
+            },
+            _ => Err(DemarshalError::wrong_type(None, Some(self.sig))),
+        }
+    }
+
+    /// Reads any integer type and converts it to T, for interoperability with services
+    /// that send a different integer type than expected (e g, "i" instead of "u").
+    ///
+    /// Returns an error if the value is not an integer, or if it does not fit in T.
+    pub fn get_numeric_lossy<T: TryFrom<i128>>(&self) -> Result<T, DemarshalError> {
+        let x: i128 = match self.parse()? {
+            Parsed::Byte(x) => x.into(),
+            Parsed::Int16(x) => x.into(),
+            Parsed::UInt16(x) => x.into(),
+            Parsed::Int32(x) => x.into(),
+            Parsed::UInt32(x) => x.into(),
+            Parsed::Int64(x) => x.into(),
+            Parsed::UInt64(x) => x.into(),
+            _ => Err(DemarshalError::wrong_type(None, Some(self.sig)))?,
+        };
+        T::try_from(x).map_err(|_| DemarshalError::NumberTooBig)
+    }
+
     pub fn new(sig: &'a SignatureSingle, data: &'a [u8], start_pos: usize, is_big_endian: bool) -> Self {
-        Single { sig, data, start_pos, is_big_endian }
+        Single { sig, data, start_pos, is_big_endian, strictness: Default::default(), depth: 0 }
+    }
+
+    /// Sets which checks to perform when parsing the value.
+    pub fn with_strictness(mut self, strictness: ParseStrictness) -> Self {
+        self.strictness = strictness;
+        self
+    }
+
+    /// The byte order of the underlying data.
+    ///
+    /// Raw bytes can only be copied verbatim to a destination with the same byte order.
+    pub fn is_big_endian(&self) -> bool { self.is_big_endian }
+
+    /// The signature of the value.
+    pub fn signature(&self) -> &'a SignatureSingle { self.sig }
+
+    /// Reads a signature value ("g") and iterates over its complete types.
+    pub fn read_signature_components(&self) -> Result<impl Iterator<Item = &'a SignatureSingle>, DemarshalError> {
+        if self.sig.as_bytes() != b"g" { Err(DemarshalError::wrong_type(Some("g"), Some(self.sig)))? }
+        Ok(self.read_sig()?.iter_singles())
+    }
+
+    /// The marshalled data of the value, without any data that follows it.
+    pub fn raw_data(&self) -> Result<&'a [u8], DemarshalError> {
+        let len = self.get_real_length()?;
+        self.data.get(..len).ok_or_else(|| self.not_enough_data(0, len))
     }
 }
 
@@ -241,8 +741,78 @@ impl<'a> Single<'a> {
 pub struct Array<'a> {
     inner_sig: &'a SignatureSingle,
     data: &'a [u8],
+    // The data of all elements, including the ones already iterated over.
+    all_data: &'a [u8],
     start_pos: usize,
     is_big_endian: bool,
+    strictness: ParseStrictness,
+    depth: u8,
+}
+
+impl<'a> Array<'a> {
+    /// Restarts the iteration from the first element.
+    pub fn reset(&mut self) {
+        self.start_pos -= self.all_data.len() - self.data.len();
+        self.data = self.all_data;
+    }
+
+    /// Returns the remaining bytes directly, without copying, if this is an array of bytes.
+    ///
+    /// Bytes need no alignment, so the marshalled data is the array contents.
+    pub fn as_byte_slice(&self) -> Option<&'a [u8]> {
+        if self.inner_sig.as_bytes() == b"y" { Some(self.data) } else { None }
+    }
+
+    /// Returns the element at the given index, counted from the current position.
+    ///
+    /// For elements of fixed size (e g "u" or "d"), the element is found directly,
+    /// otherwise this iterates over the elements before it. Unlike Iterator::nth,
+    /// this does not advance the iteration.
+    pub fn nth_fast(&self, index: usize) -> Result<Option<Single<'a>>, DemarshalError> {
+        let size = match fixed_size_of(self.inner_sig.as_bytes()[0]) {
+            Some(size) => size,
+            None => {
+                let mut iter = *self;
+                return iter.nth(index).transpose();
+            },
+        };
+        // Fixed size elements are as large as their alignment, so there is no padding between them.
+        let pos = match index.checked_mul(size) {
+            Some(pos) if pos < self.data.len() => pos,
+            _ => return Ok(None),
+        };
+        let data = self.data.get(pos..pos+size)
+            .ok_or_else(|| DemarshalError::not_enough_data(self.start_pos + pos, size, self.data.len() - pos))?;
+        Ok(Some(Single {
+            sig: self.inner_sig,
+            data,
+            start_pos: self.start_pos + pos,
+            is_big_endian: self.is_big_endian,
+            strictness: self.strictness,
+            depth: self.depth,
+        }))
+    }
+
+    /// Copies the remaining elements of an array of numbers, such as "au" or "ad", into a Vec.
+    ///
+    /// Returns WrongType if the elements are not of type T.
+    pub fn copy_to_vec<T: FixedNumber>(&self) -> Result<Vec<T>, DemarshalError> {
+        if self.inner_sig.as_bytes() != [T::SIG] {
+            let expected = [b'a', T::SIG];
+            Err(DemarshalError::wrong_type(from_utf8(&expected).ok(), Some(&format!("a{}", self.inner_sig))))?
+        }
+        let chunks = self.data.chunks_exact(T::SIZE);
+        if !chunks.remainder().is_empty() { Err(DemarshalError::MisalignedArrayLength)? }
+        Ok(chunks.map(|x| T::from_bytes(x, self.is_big_endian)).collect())
+    }
+
+    /// The number of remaining elements, if the elements have a fixed size (e g "u" or "d").
+    ///
+    /// For elements of variable size, this returns None, since the elements need to be
+    /// iterated over to be counted.
+    pub fn fixed_len(&self) -> Option<usize> {
+        fixed_size_of(self.inner_sig.as_bytes()[0]).map(|size| self.data.len() / size)
+    }
 }
 
 impl<'a> Iterator for Array<'a> {
@@ -254,21 +824,37 @@ impl<'a> Iterator for Array<'a> {
             data: self.data,
             start_pos: self.start_pos,
             sig: self.inner_sig,
+            strictness: self.strictness,
+            depth: self.depth,
         };
-        let mut len = match s.get_real_length() {
+        let len = match s.get_real_length() {
             Ok(len) if len <= self.data.len() => len,
-            _ => return Some(Err(DemarshalError::NotEnoughData)),
+            Ok(len) => return Some(Err(DemarshalError::not_enough_data(self.start_pos, len, self.data.len()))),
+            Err(e) => return Some(Err(e)),
         };
         s.data = &s.data[0..len];
-        if len < s.data.len() {
-            len = align_up(len + self.start_pos, align_of(self.inner_sig.as_bytes()[0])) - self.start_pos;
-            self.start_pos += len;
-            self.data = &self.data[len..];
+        if len < self.data.len() {
+            let next = align_up(len + self.start_pos, align_of(self.inner_sig.as_bytes()[0])) - self.start_pos;
+            let padding = match self.data.get(len..next) {
+                Some(p) => p,
+                None => return Some(Err(DemarshalError::not_enough_data(self.start_pos, next, self.data.len()))),
+            };
+            if let Err(e) = self.strictness.padding(padding) { return Some(Err(e)) };
+            self.start_pos += next;
+            self.data = &self.data[next..];
         } else {
+            self.start_pos += len;
             self.data = &[];
         }
         Some(Ok(s))
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self.fixed_len() {
+            Some(n) => (n, Some(n)),
+            None => (0, None),
+        }
+    }
 }
 
 impl<'a> Iterator for Dict<'a> {
@@ -276,19 +862,28 @@ impl<'a> Iterator for Dict<'a> {
     fn next(&mut self) -> Option<Self::Item> {
         if self.data.len() == 0 { return None; }
         let mut mi = MultiIter {
-            start_pos: 0,
+            start_pos: self.start_pos,
             inner: Multi {
                 sig: SignatureMulti::new_unchecked(&self.outer_sig[2..self.outer_sig.len()-1]),
                 data: self.data,
+                start_pos: self.start_pos,
                 is_big_endian: self.is_big_endian,
+                strictness: self.strictness,
+                depth: self.depth,
             }
         };
         match (mi.next(), mi.next()) {
             (Some(Ok(k)), Some(Ok(v))) => {
                 let len = self.data.len() - mi.inner.data.len();
                 if len < self.data.len() {
-                    self.data = &self.data[align_up(len, 8)..];
+                    let next = align_up(len, 8);
+                    if let Err(e) = self.strictness.padding(self.data.get(len..next).unwrap_or(&[])) {
+                        return Some(Err(e));
+                    }
+                    self.data = &self.data[next..];
+                    self.start_pos += next;
                 } else {
+                    self.start_pos += len;
                     self.data = &[];
                 }
                 Some(Ok((k, v)))
@@ -296,61 +891,110 @@ impl<'a> Iterator for Dict<'a> {
             (Some(Err(k)), Some(_)) => Some(Err(k)),
             (Some(_), Some(Err(v))) => Some(Err(v)),
             _ => {
-                Some(Err(DemarshalError::NotEnoughData))
+                Some(Err(DemarshalError::InvalidProtocol))
             },
         }
     }
 }
 
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ArrayBuf {
     outer_sig: dbus_strings::SignatureSingleBuf,
     data: Vec<u8>,
 }
 
 impl ArrayBuf {
-    pub fn new(sig: &dbus_strings::SignatureSingle) -> Result<Self, DemarshalError> {
+    pub fn new(sig: &dbus_strings::SignatureSingle) -> Result<Self, MarshalError> {
         let mut x = String::with_capacity(sig.len() + 1);
         x.push_str("a");
         x.push_str(sig);
-        let x = SignatureSingle::new_owned(x).map_err(|_| DemarshalError::InvalidString)?;
+        let x = SignatureSingle::new_owned(x).map_err(|_| MarshalError::InvalidString)?;
         Ok(ArrayBuf { outer_sig: x, data: vec!() })
     }
 
-    fn verify_array_size(&mut self, old_len: usize) -> Result<(), DemarshalError> {
+    fn verify_array_size(&mut self, old_len: usize) -> Result<(), MarshalError> {
         if self.data.len() > ARRAY_MAX_LEN {
             self.data.truncate(old_len);
-            Err(DemarshalError::NumberTooBig)
+            Err(MarshalError::ArrayTooBig)
         } else { Ok(()) }
     }
 
-    pub fn append<T: Marshal + ?Sized>(&mut self, value: &T) -> Result<(), DemarshalError> {
-        if &self.outer_sig[1..] != &**value.signature() { return Err(DemarshalError::WrongType); }
+    pub fn append<T: Marshal + ?Sized>(&mut self, value: &T) -> Result<(), MarshalError> {
+        let sig = value.signature();
+        if self.outer_sig[1..] != **sig {
+            return Err(MarshalError::wrong_type(&self.outer_sig[1..], &sig));
+        }
         let old_len = self.data.len();
-        value.append_data_to(&mut self.data);
+        if let Err(e) = value.try_append_data_to(&mut self.data) {
+            self.data.truncate(old_len);
+            return Err(e);
+        }
         self.verify_array_size(old_len)
     }
 
-    pub fn from_iter<'a, T, I>(iter: I) -> Result<Self, DemarshalError>
+    pub fn from_iter<'a, T, I>(iter: I) -> Result<Self, MarshalError>
     where T: Marshal + ?Sized + 'a,
     &'a T: Default,
     I: IntoIterator<Item=&'a T>
     {
         let def = <&T>::default();
         let defsig = def.signature();
-        let mut r = ArrayBuf::new(defsig)?;
+        let mut r = ArrayBuf::new(&defsig)?;
         for x in iter.into_iter() {
-            if x.signature() != defsig { return Err(DemarshalError::WrongType); }
-            x.append_data_to(&mut r.data);
+            let sig = x.signature();
+            if sig != defsig { return Err(MarshalError::wrong_type(&defsig, &sig)); }
+            x.try_append_data_to(&mut r.data)?;
         }
         r.verify_array_size(0)?;
         Ok(r)
     }
+
+    /// The number of elements in the array.
+    ///
+    /// For elements of variable size, this requires iterating over the elements.
+    pub fn len(&self) -> usize {
+        let arr = self.array();
+        match fixed_size_of(arr.inner_sig.as_bytes()[0]) {
+            Some(size) if arr.inner_sig.len() == 1 => self.data.len() / size,
+            _ => arr.count(),
+        }
+    }
+
+    /// Reads back the elements appended so far, without marshalling them.
+    pub fn array(&self) -> Array<'_> {
+        Array {
+            inner_sig: SignatureSingle::new_unchecked(&self.outer_sig[1..]),
+            data: &self.data,
+            all_data: &self.data,
+            start_pos: 0,
+            is_big_endian: cfg!(target_endian = "big"),
+            strictness: Default::default(),
+            depth: 1,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool { self.data.is_empty() }
+
+    /// Removes all elements, keeping the element type.
+    pub fn clear(&mut self) { self.data.clear() }
+
+    /// Builds an array of structs, where every tuple becomes one struct.
+    ///
+    /// The struct signature is taken from the tuple type, so an empty iterator gives an empty array.
+    pub fn from_structs<T: MarshalTuple + StaticSignature, I: IntoIterator<Item=T>>(iter: I) -> Result<Self, MarshalError> {
+        let mut r = ArrayBuf::new(&T::static_signature())?;
+        for x in iter.into_iter() {
+            let mut m = MultiBuf::new();
+            x.append_to(&mut m)?;
+            r.append(&StructBuf::new(m)?)?;
+        }
+        Ok(r)
+    }
 }
 
 impl Marshal for ArrayBuf {
-    fn signature(&self) -> &SignatureSingle { &self.outer_sig }
+    fn signature(&self) -> Cow<'_, SignatureSingle> { (&*self.outer_sig).into() }
     fn append_data_to(&self, v: &mut Vec<u8>) {
         let slen = self.data.len() as u32;
         slen.append_data_to(v);
@@ -359,7 +1003,7 @@ impl Marshal for ArrayBuf {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DictBuf {
     key_sig: SignatureSingleBuf,
     value_sig: SignatureSingleBuf,
@@ -368,32 +1012,66 @@ pub struct DictBuf {
 }
 
 impl DictBuf {
-    pub fn new(key_sig: SignatureSingleBuf, value_sig: SignatureSingleBuf) -> Result<Self, DemarshalError> {
+    pub fn new(key_sig: SignatureSingleBuf, value_sig: SignatureSingleBuf) -> Result<Self, MarshalError> {
         let mut x = String::with_capacity(key_sig.len() + value_sig.len() + 3);
         x.push_str("a{");
         x.push_str(&key_sig);
         x.push_str(&value_sig);
         x.push_str("}");
-        let x = SignatureSingle::new_owned(x).map_err(|_| DemarshalError::InvalidString)?;
+        let x = SignatureSingle::new_owned(x).map_err(|_| MarshalError::InvalidString)?;
         Ok(DictBuf { key_sig, value_sig, outer_sig: x, data: vec!() })
     }
 
-    pub fn append<K: Marshal + ?Sized, V: Marshal + ?Sized>(&mut self, key: &K, value: &V) -> Result<(), DemarshalError> {
-        if &*self.value_sig != value.signature() { return Err(DemarshalError::WrongType); }
-        if &*self.key_sig != key.signature() { return Err(DemarshalError::WrongType); }
+    pub fn append<K: Marshal + ?Sized, V: Marshal + ?Sized>(&mut self, key: &K, value: &V) -> Result<(), MarshalError> {
+        let (key_sig, value_sig) = (key.signature(), value.signature());
+        if *self.value_sig != *value_sig {
+            return Err(MarshalError::wrong_type(&self.value_sig, &value_sig));
+        }
+        if *self.key_sig != *key_sig {
+            return Err(MarshalError::wrong_type(&self.key_sig, &key_sig));
+        }
         let old_len = self.data.len();
         align_buf(&mut self.data, 8);
-        key.append_data_to(&mut self.data);
-        value.append_data_to(&mut self.data);
+        let r = key.try_append_data_to(&mut self.data)
+            .and_then(|_| value.try_append_data_to(&mut self.data));
+        if let Err(e) = r {
+            self.data.truncate(old_len);
+            return Err(e);
+        }
         if self.data.len() > ARRAY_MAX_LEN {
             self.data.truncate(old_len);
-            Err(DemarshalError::NumberTooBig)
+            Err(MarshalError::ArrayTooBig)
         } else { Ok(()) }
     }
+
+    /// Reads back the entries appended so far, without marshalling them.
+    pub fn iter(&self) -> Dict<'_> {
+        Dict {
+            outer_sig: &self.outer_sig,
+            key_sig: &self.key_sig,
+            value_sig: &self.value_sig,
+            data: &self.data,
+            all_data: &self.data,
+            start_pos: 0,
+            is_big_endian: cfg!(target_endian = "big"),
+            strictness: Default::default(),
+            depth: 1,
+        }
+    }
+
+    /// Looks up the value of the first entry with the given key.
+    pub fn get<K: Marshal + ?Sized>(&self, key: &K) -> Option<Single<'_>> {
+        if *self.key_sig != *key.signature() { return None; }
+        let mut v = vec!();
+        key.append_data_to(&mut v);
+        self.iter().filter_map(Result::ok).find(|(k, _)| {
+            k.get_real_length().ok().and_then(|len| k.data.get(..len)) == Some(&v[..])
+        }).map(|(_, value)| value)
+    }
 }
 
 impl Marshal for DictBuf {
-    fn signature(&self) -> &SignatureSingle { &self.outer_sig }
+    fn signature(&self) -> Cow<'_, SignatureSingle> { (&*self.outer_sig).into() }
     fn append_data_to(&self, v: &mut Vec<u8>) {
         let slen = self.data.len() as u32;
         slen.append_data_to(v);
@@ -402,19 +1080,24 @@ impl Marshal for DictBuf {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct StructBuf {
     inner: MultiBuf,
     outer_sig: SignatureSingleBuf,
 }
 
 impl StructBuf {
-    pub fn new(inner: MultiBuf) -> Result<Self, DemarshalError> {
+    pub fn new(inner: MultiBuf) -> Result<Self, MarshalError> {
+        let inner = if inner.swap_bytes {
+            let mut native = MultiBuf::new();
+            native.append_multi(&inner)?;
+            native
+        } else { inner };
         let mut outer_sig = String::with_capacity(inner.sig.len() + 2);
         outer_sig.push('(');
         outer_sig.push_str(&inner.sig);
         outer_sig.push(')');
-        let outer_sig = SignatureSingle::new_owned(outer_sig).map_err(|_| DemarshalError::InvalidString)?;
+        let outer_sig = SignatureSingle::new_owned(outer_sig).map_err(|_| MarshalError::InvalidString)?;
         Ok(StructBuf {
             inner, outer_sig
         })
@@ -422,7 +1105,7 @@ impl StructBuf {
 }
 
 impl Marshal for StructBuf {
-    fn signature(&self) -> &SignatureSingle { &self.outer_sig }
+    fn signature(&self) -> Cow<'_, SignatureSingle> { (&*self.outer_sig).into() }
     fn append_data_to(&self, v: &mut Vec<u8>) {
         align_buf(v, 8);
         v.extend_from_slice(&self.inner.data)
@@ -438,16 +1121,16 @@ pub struct VariantBuf {
 impl VariantBuf {
     pub fn new<T: Marshal + ?Sized>(value: &T) -> Result<Self, DemarshalError> {
         let mut data = vec!();
-        value.append_data_to(&mut data);
+        value.try_append_data_to(&mut data)?;
         Ok(VariantBuf {
-            sig: value.signature().into(),
+            sig: value.signature().into_owned(),
             data
         })
     }
 }
 
 impl Marshal for VariantBuf {
-    fn signature(&self) -> &SignatureSingle { SignatureSingle::new_unchecked("v") }
+    fn signature(&self) -> Cow<'_, SignatureSingle> { SignatureSingle::new_unchecked("v").into() }
     fn append_data_to(&self, v: &mut Vec<u8>) {
         (&*self.sig).append_data_to(v);
         align_buf(v, align_of(self.sig.as_bytes()[0]));
@@ -463,7 +1146,56 @@ pub struct Dict<'a> {
     key_sig: &'a SignatureSingle,
     value_sig: &'a SignatureSingle,
     data: &'a [u8],
+    // The data of all entries, including the ones already iterated over.
+    all_data: &'a [u8],
+    start_pos: usize,
     is_big_endian: bool,
+    strictness: ParseStrictness,
+    depth: u8,
+}
+
+impl<'a> Dict<'a> {
+    /// Restarts the iteration from the first entry.
+    pub fn reset(&mut self) {
+        self.start_pos -= self.all_data.len() - self.data.len();
+        self.data = self.all_data;
+    }
+
+    /// Looks up the value of the first entry with the given key.
+    ///
+    /// Returns WrongType if the keys are not strings, object paths or signatures.
+    pub fn get(&self, key: &DBusStr) -> Result<Option<Single<'a>>, DemarshalError> {
+        if !matches!(self.key_sig.as_bytes(), b"s" | b"o" | b"g") { Err(DemarshalError::wrong_type(None, Some(self.outer_sig)))? }
+        for entry in *self {
+            let (k, v) = entry?;
+            if k.parse()?.as_dbus_str()? == key { return Ok(Some(v)) }
+        }
+        Ok(None)
+    }
+
+    /// Collects the entries of a dictionary with string keys, such as "a{sv}".
+    ///
+    /// Returns WrongType if the keys are not strings, or the first error found when parsing the entries.
+    pub fn to_hashmap(&self) -> Result<HashMap<String, Parsed<'a>>, DemarshalError> {
+        if self.key_sig.as_bytes() != b"s" { Err(DemarshalError::wrong_type(None, Some(self.outer_sig)))? }
+        let mut r = HashMap::new();
+        for entry in *self {
+            let (k, v) = entry?;
+            r.insert(String::from(&**k.parse()?.as_dbus_str()?), v.parse()?);
+        }
+        Ok(r)
+    }
+
+    fn check_unique_keys(&self) -> Result<(), DemarshalError> {
+        let mut keys: Vec<&[u8]> = vec!();
+        for entry in *self {
+            let (k, _) = entry?;
+            let k = &k.data[..k.get_real_length()?];
+            if keys.contains(&k) { Err(DemarshalError::InvalidProtocol)? }
+            keys.push(k);
+        }
+        Ok(())
+    }
 }
 
 #[non_exhaustive]
@@ -509,62 +1241,409 @@ pub enum Parsed<'a> {
     UnixFd(usize),
 }
 
-impl Parsed<'_> {
-    pub fn as_dbus_str(&self) -> Result<&DBusStr, DemarshalError> {
-        match self {
+impl<'a> Parsed<'a> {
+    /// Returns the string, borrowed from the underlying data rather than from self.
+    pub fn as_dbus_str(&self) -> Result<&'a DBusStr, DemarshalError> {
+        match *self {
             Parsed::String(x) => Ok(x),
             Parsed::ObjectPath(x) => Ok(x.as_dbus_str()),
             Parsed::Signature(x) => Ok(x.as_dbus_str()),
-            _ => Err(DemarshalError::WrongType),
+            _ => Err(DemarshalError::wrong_type(None, Some(&self.signature()))),
         }
     }
-}
 
-#[derive(Debug, Clone, Default)]
-pub struct MultiBuf {
-    sig: SignatureMultiBuf,
-    data: Vec<u8>,
-}
+    /// Returns the contents of a byte array without copying, see Array::as_byte_slice.
+    pub fn as_byte_slice(&self) -> Option<&'a [u8]> {
+        match self {
+            Parsed::Array(a) => a.as_byte_slice(),
+            _ => None,
+        }
+    }
 
-impl MultiBuf {
-    pub fn new() -> Self { Default::default() }
-    pub fn multi(&self) -> Multi {
-        Multi { sig: &self.sig, data: &self.data, is_big_endian: IS_BIG_ENDIAN }
+    /// Returns the string as a plain str, borrowed from the underlying data.
+    pub fn as_str(&self) -> Result<&'a str, DemarshalError> {
+        self.as_dbus_str().map(|x| &**x)
     }
-    pub fn append<T: Marshal + ?Sized>(&mut self, value: &T) -> Result<(), DemarshalError> {
-        // Adding two signatures does not increase depth, so we don't need to re-verify the
-        // entire signature, just check that the length is not too big.
-        let new_sig = value.signature();
-        if self.sig.len() + new_sig.len() > 255 { return Err(DemarshalError::NumberTooBig)}
-        let temp = mem::replace(&mut self.sig, Default::default());
-        let mut temp = temp.into_inner();
-        temp.push_str(new_sig);
-        debug_assert!(SignatureMulti::is_valid(&temp).is_ok());
-        self.sig = SignatureMulti::new_unchecked_owned(temp);
 
-        value.append_data_to(&mut self.data);
-        Ok(())
+    /// The signature of the value, including the element types of containers.
+    pub fn signature(&self) -> SignatureSingleBuf {
+        let s = match self {
+            Parsed::Array(a) => format!("a{}", a.inner_sig),
+            Parsed::Dict(d) => d.outer_sig.to_string(),
+            Parsed::Struct(m) => format!("({})", m.sig),
+            Parsed::Variant(_) => "v".into(),
+            Parsed::ObjectPath(_) => "o".into(),
+            Parsed::Signature(_) => "g".into(),
+            Parsed::String(_) => "s".into(),
+            Parsed::Boolean(_) => "b".into(),
+            Parsed::Byte(_) => "y".into(),
+            Parsed::Int16(_) => "n".into(),
+            Parsed::Int32(_) => "i".into(),
+            Parsed::Int64(_) => "x".into(),
+            Parsed::UInt16(_) => "q".into(),
+            Parsed::UInt32(_) => "u".into(),
+            Parsed::UInt64(_) => "t".into(),
+            Parsed::Double(_) => "d".into(),
+            Parsed::UnixFd(_) => "h".into(),
+        };
+        SignatureSingle::new_unchecked_owned(s)
     }
-    pub fn into_inner(self) -> (SignatureMultiBuf, Vec<u8>) {
-        (self.sig, self.data)
+
+    /// Returns any unsigned integer (or byte) value, converted to u64.
+    pub fn as_u64(&self) -> Result<u64, DemarshalError> {
+        match *self {
+            Parsed::Byte(x) => Ok(x.into()),
+            Parsed::UInt16(x) => Ok(x.into()),
+            Parsed::UInt32(x) => Ok(x.into()),
+            Parsed::UInt64(x) => Ok(x),
+            _ => Err(DemarshalError::wrong_type(None, Some(&self.signature()))),
+        }
     }
-}
-/*
-fn checked_sig_append(s: &mut SignatureMultiBuf, s2: &str)  -> Result<(), DemarshalError>
-{
-    let temp = mem::replace(s, Default::default());
-    let mut temp = temp.into_inner();
-    let old_len = temp.len();
-    temp.push_str(s2);
-    match <SignatureMulti as StringLike>::is_valid(&temp) {
-        Ok(_) => { *s = SignatureMulti::new_unchecked_owned(temp); Ok(()) }
-        Err(_) => {
-            temp.truncate(old_len);
-            *s = SignatureMulti::new_unchecked_owned(temp);
-            Err(DemarshalError::InvalidString)
+
+    /// Returns any signed integer value, converted to i64.
+    pub fn as_i64(&self) -> Result<i64, DemarshalError> {
+        match *self {
+            Parsed::Int16(x) => Ok(x.into()),
+            Parsed::Int32(x) => Ok(x.into()),
+            Parsed::Int64(x) => Ok(x),
+            _ => Err(DemarshalError::wrong_type(None, Some(&self.signature()))),
         }
     }
-}
+
+    /// Tells which of the string-like types this is, or None if it is not a string.
+    pub fn string_kind(&self) -> Option<StringKind> {
+        match self {
+            Parsed::String(_) => Some(StringKind::Plain),
+            Parsed::ObjectPath(_) => Some(StringKind::ObjectPath),
+            Parsed::Signature(_) => Some(StringKind::Signature),
+            _ => None,
+        }
+    }
+}
+
+impl Parsed<'_> {
+    // Writes the value without its signature, unless it is inside a variant.
+    fn fmt_value(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn fmt_single(s: Result<Single, DemarshalError>, f: &mut fmt::Formatter) -> fmt::Result {
+            match s.and_then(|s| s.parse()) {
+                Ok(p) => p.fmt_value(f),
+                Err(_) => write!(f, "<error>"),
+            }
+        }
+        match self {
+            Parsed::Array(a) => {
+                write!(f, "[")?;
+                for x in *a {
+                    write!(f, " ")?;
+                    fmt_single(x, f)?;
+                }
+                write!(f, " ]")
+            },
+            Parsed::Dict(d) => {
+                write!(f, "{{")?;
+                for (i, x) in d.enumerate() {
+                    if i > 0 { write!(f, ",")? }
+                    write!(f, " ")?;
+                    match x {
+                        Ok((k, v)) => {
+                            fmt_single(Ok(k), f)?;
+                            write!(f, ": ")?;
+                            fmt_single(Ok(v), f)?;
+                        },
+                        Err(_) => write!(f, "<error>")?,
+                    }
+                }
+                write!(f, " }}")
+            },
+            Parsed::Struct(m) => {
+                write!(f, "(")?;
+                for x in m.iter() {
+                    write!(f, " ")?;
+                    fmt_single(x, f)?;
+                }
+                write!(f, " )")
+            },
+            Parsed::Variant(v) => match v.parse() {
+                Ok(p) => write!(f, "{}", p),
+                Err(_) => write!(f, "<error>"),
+            },
+            Parsed::ObjectPath(_) | Parsed::Signature(_) | Parsed::String(_) =>
+                write!(f, "{:?}", self.as_str().unwrap_or_default()),
+            Parsed::Boolean(x) => write!(f, "{}", x),
+            Parsed::Byte(x) => write!(f, "{}", x),
+            Parsed::Int16(x) => write!(f, "{}", x),
+            Parsed::Int32(x) => write!(f, "{}", x),
+            Parsed::Int64(x) => write!(f, "{}", x),
+            Parsed::UInt16(x) => write!(f, "{}", x),
+            Parsed::UInt32(x) => write!(f, "{}", x),
+            Parsed::UInt64(x) => write!(f, "{}", x),
+            Parsed::Double(x) => write!(f, "{}", x),
+            Parsed::UnixFd(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+/// Formats the value like busctl does, e g `s "hello"` or `ai [ 1 2 3 ]`.
+///
+/// Values that cannot be demarshalled are written as `<error>`.
+impl fmt::Display for Parsed<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ", self.signature())?;
+        self.fmt_value(f)
+    }
+}
+
+/// The different kinds of strings that can be returned from Parsed::as_dbus_str.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StringKind {
+    /// A D-Bus String.
+    Plain,
+    /// A D-Bus object path.
+    ObjectPath,
+    /// A D-Bus signature.
+    Signature,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MultiBuf {
+    sig: SignatureMultiBuf,
+    data: Vec<u8>,
+    // The data is in the non-native byte order.
+    swap_bytes: bool,
+}
+
+impl MultiBuf {
+    pub fn new() -> Self { Default::default() }
+    /// Creates a new, empty buffer, reusing a previously recycled allocation from the pool if possible.
+    pub fn from_pool(pool: &BufferPool) -> Self {
+        MultiBuf { data: pool.get(), ..Default::default() }
+    }
+    /// Creates a new, empty buffer, which marshals values in the given byte order
+    /// rather than in the native one.
+    ///
+    /// Values are first marshalled in native byte order and then converted, so this is slower
+    /// than a buffer in native byte order. It is mostly useful for testing.
+    pub fn with_endianness(is_big_endian: bool) -> Self {
+        MultiBuf { swap_bytes: is_big_endian != IS_BIG_ENDIAN, ..Default::default() }
+    }
+    /// The byte order of the marshalled data.
+    pub fn is_big_endian(&self) -> bool { IS_BIG_ENDIAN != self.swap_bytes }
+    /// Hands the allocated memory back to the pool, so it can be reused by a later call to from_pool.
+    pub fn into_pool(self, pool: &BufferPool) {
+        pool.recycle(self.data)
+    }
+    pub fn multi(&self) -> Multi {
+        Multi::new(&self.sig, &self.data, self.is_big_endian())
+    }
+    pub fn append<T: Marshal + ?Sized>(&mut self, value: &T) -> Result<(), MarshalError> {
+        // Adding two signatures does not increase depth, so we don't need to re-verify the
+        // entire signature, just check that the length is not too big.
+        if self.swap_bytes {
+            let mut temp = MultiBuf::new();
+            temp.append(value)?;
+            return self.append_multi(&temp);
+        }
+        let new_sig = value.signature();
+        if self.sig.len() + new_sig.len() > 255 { return Err(MarshalError::SignatureTooLong)}
+        let old_len = self.data.len();
+        if let Err(e) = value.try_append_data_to(&mut self.data) {
+            self.data.truncate(old_len);
+            return Err(e);
+        }
+        self.push_sig(&new_sig);
+        Ok(())
+    }
+    // Appending a single complete type to a valid signature always gives a valid signature,
+    // so only the new part is checked (and only in debug builds), to avoid rescanning the
+    // entire signature on every append.
+    fn push_sig(&mut self, new_sig: &SignatureSingle) {
+        debug_assert!(SignatureSingle::is_valid(new_sig).is_ok());
+        let temp = mem::replace(&mut self.sig, Default::default());
+        let mut temp = temp.into_inner();
+        temp.push_str(new_sig);
+        self.sig = SignatureMulti::new_unchecked_owned(temp);
+    }
+    /// Appends all values of another buffer, after the values already in this buffer.
+    pub fn append_multi(&mut self, other: &MultiBuf) -> Result<(), MarshalError> {
+        if self.sig.len() + other.sig.len() > 255 { return Err(MarshalError::SignatureTooLong) }
+        if self.data.len() & 7 == 0 && self.swap_bytes == other.swap_bytes {
+            // The other buffer's data starts at offset zero, so it is correctly aligned as is.
+            let mut temp = mem::take(&mut self.sig).into_inner();
+            temp.push_str(&other.sig);
+            self.sig = SignatureMulti::new_unchecked_owned(temp);
+            self.data.extend_from_slice(&other.data);
+            return Ok(());
+        }
+        // Otherwise, the padding between the values might change, or the byte order differs,
+        // so copy them one by one.
+        let (old_sig, old_len) = (self.sig.clone(), self.data.len());
+        for x in other.multi().iter() {
+            let r = x.and_then(|x| {
+                self.push_sig(x.sig);
+                x.append_ordered(&mut self.data, self.swap_bytes)
+            });
+            if let Err(e) = r {
+                self.sig = old_sig;
+                self.data.truncate(old_len);
+                // Reading the values back can fail if they are nested too deeply,
+                // or if a value appended invalid data.
+                return Err(match e {
+                    DemarshalError::RecursionLimit => MarshalError::RecursionLimit,
+                    DemarshalError::InvalidString => MarshalError::InvalidString,
+                    e => MarshalError::InvalidData(e),
+                });
+            }
+        }
+        Ok(())
+    }
+    /// Appends an IP address, using the given encoding.
+    pub fn append_ip_addr(&mut self, addr: &IpAddr, encoding: IpAddrEncoding) -> Result<(), MarshalError> {
+        let (family, bytes) = match addr {
+            IpAddr::V4(x) => (AF_INET, x.octets().to_vec()),
+            IpAddr::V6(x) => (AF_INET6, x.octets().to_vec()),
+        };
+        match encoding {
+            IpAddrEncoding::Bytes => self.append(&bytes),
+            IpAddrEncoding::FamilyBytes => {
+                let mut m = MultiBuf::new();
+                m.append(&family)?;
+                m.append(&bytes)?;
+                self.append(&StructBuf::new(m)?)
+            }
+        }
+    }
+    /// Appends an array, marshalling the elements directly into this buffer
+    /// (i e, without going through an ArrayBuf).
+    ///
+    /// All elements must have the signature elem_sig.
+    pub fn append_array<'b, T, I>(&mut self, elem_sig: &SignatureSingle, iter: I) -> Result<(), MarshalError>
+    where T: Marshal + ?Sized + 'b, I: IntoIterator<Item = &'b T> {
+        if self.swap_bytes {
+            let mut temp = MultiBuf::new();
+            temp.append_array(elem_sig, iter)?;
+            return self.append_multi(&temp);
+        }
+        let mut new_sig = String::with_capacity(elem_sig.len() + 1);
+        new_sig.push('a');
+        new_sig.push_str(elem_sig);
+        let new_sig = SignatureSingle::new_owned(new_sig).map_err(|_| MarshalError::InvalidString)?;
+        if self.sig.len() + new_sig.len() > 255 { return Err(MarshalError::SignatureTooLong)}
+
+        let old_len = self.data.len();
+        align_buf(&mut self.data, 4);
+        let len_pos = self.data.len();
+        0u32.append_data_to(&mut self.data);
+        align_buf(&mut self.data, align_of(elem_sig.as_bytes()[0]));
+        let start = self.data.len();
+        for x in iter {
+            let sig = x.signature();
+            if *sig != *elem_sig {
+                self.data.truncate(old_len);
+                return Err(MarshalError::wrong_type(elem_sig, &sig));
+            }
+            if let Err(e) = x.try_append_data_to(&mut self.data) {
+                self.data.truncate(old_len);
+                return Err(e);
+            }
+            if self.data.len() - start > ARRAY_MAX_LEN {
+                self.data.truncate(old_len);
+                return Err(MarshalError::ArrayTooBig);
+            }
+        }
+        let slen = (self.data.len() - start) as u32;
+        self.data[len_pos..len_pos+4].copy_from_slice(&slen.to_ne_bytes());
+        self.push_sig(&new_sig);
+        Ok(())
+    }
+    /// Appends the marshalled data to a BytesMut, padding it to 8 byte alignment first.
+    #[cfg(feature = "bytes")]
+    pub fn append_data_to_bytes(&self, buf: &mut bytes::BytesMut) {
+        let len = buf.len();
+        buf.extend_from_slice(&ZEROS[..(align_up(len, 8) - len)]);
+        buf.extend_from_slice(&self.data);
+    }
+
+    /// Returns the signature and the marshalled data, which is in the byte order of this buffer.
+    pub fn into_inner(self) -> (SignatureMultiBuf, Vec<u8>) {
+        (self.sig, self.data)
+    }
+    /// Creates a buffer from a signature and marshalled data, e g as returned by into_inner.
+    ///
+    /// The data must be in native byte order. All values are parsed, with padding and
+    /// nul bytes checked, and the data must not continue after the last value.
+    pub fn from_raw_parts(sig: SignatureMultiBuf, data: Vec<u8>) -> Result<Self, DemarshalError> {
+        // Dicts with duplicate keys can be built with DictBuf, so they are accepted here too.
+        let strictness = ParseStrictness { check_dict_keys: false, ..ParseStrictness::strict() };
+        let m = Multi::new(&sig, &data, IS_BIG_ENDIAN).with_strictness(strictness);
+        for x in m.iter() { x?.validate()?; }
+        if m.get_real_length()? != data.len() { Err(DemarshalError::InvalidProtocol)? }
+        Ok(Self::from_raw_parts_unchecked(sig, data))
+    }
+    /// Like from_raw_parts, but without checking the data.
+    ///
+    /// Invalid data will not cause undefined behaviour, but reading values back
+    /// might give errors, or garbage.
+    pub fn from_raw_parts_unchecked(sig: SignatureMultiBuf, data: Vec<u8>) -> Self {
+        MultiBuf { sig, data, swap_bytes: false }
+    }
+    /// Removes all values, but keeps the allocated memory so the buffer can be reused.
+    pub fn clear(&mut self) {
+        self.sig = Default::default();
+        self.data.clear();
+    }
+}
+
+const AF_INET: i32 = 2;
+const AF_INET6: i32 = 10;
+
+/// The different ways of encoding an IP address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpAddrEncoding {
+    /// The address as an array of 4 or 16 bytes ("ay").
+    Bytes,
+    /// The address family (AF_INET or AF_INET6) followed by the address bytes ("(iay)"),
+    /// as used by e g systemd.
+    FamilyBytes,
+}
+
+/// A pool of byte buffers that can be reused between marshalling operations,
+/// to avoid allocating a new buffer for every message.
+#[derive(Debug, Default)]
+pub struct BufferPool(Mutex<Vec<Vec<u8>>>);
+
+impl BufferPool {
+    pub fn new() -> Self { Default::default() }
+    /// Takes an empty buffer out of the pool, or allocates a new one if the pool is empty.
+    pub fn get(&self) -> Vec<u8> {
+        self.0.lock().unwrap().pop().unwrap_or_default()
+    }
+    /// Clears the buffer and puts it back in the pool.
+    pub fn recycle(&self, mut v: Vec<u8>) {
+        if v.capacity() == 0 { return; }
+        v.clear();
+        self.0.lock().unwrap().push(v);
+    }
+    /// The number of buffers currently available in the pool.
+    pub fn len(&self) -> usize { self.0.lock().unwrap().len() }
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+}
+
+/*
+fn checked_sig_append(s: &mut SignatureMultiBuf, s2: &str)  -> Result<(), DemarshalError>
+{
+    let temp = mem::replace(s, Default::default());
+    let mut temp = temp.into_inner();
+    let old_len = temp.len();
+    temp.push_str(s2);
+    match <SignatureMulti as StringLike>::is_valid(&temp) {
+        Ok(_) => { *s = SignatureMulti::new_unchecked_owned(temp); Ok(()) }
+        Err(_) => {
+            temp.truncate(old_len);
+            *s = SignatureMulti::new_unchecked_owned(temp);
+            Err(DemarshalError::InvalidString)
+        }
+    }
+}
 */
 const ZEROS: [u8; 8] = [0; 8];
 
@@ -574,23 +1653,150 @@ pub fn align_buf(v: &mut Vec<u8>, align: usize) {
     v.extend_from_slice(&ZEROS[..(x-vlen)])
 }
 
+/// A number that can be copied in bulk out of an array, see Array::copy_to_vec.
+pub trait FixedNumber: Sized {
+    /// The type code in a D-Bus signature.
+    const SIG: u8;
+    /// The size in bytes of the marshalled number.
+    const SIZE: usize;
+    /// Reads the number from a slice of exactly SIZE bytes.
+    fn from_bytes(b: &[u8], is_big_endian: bool) -> Self;
+}
+
+macro_rules! fixed_number_impl {
+    ($t: ty, $s: expr) => {
+        impl FixedNumber for $t {
+            const SIG: u8 = $s;
+            const SIZE: usize = mem::size_of::<$t>();
+            fn from_bytes(b: &[u8], is_big_endian: bool) -> Self {
+                let b = b.try_into().unwrap();
+                if is_big_endian { <$t>::from_be_bytes(b) } else { <$t>::from_le_bytes(b) }
+            }
+        }
+    }
+}
+
+fixed_number_impl!(u8, b'y');
+fixed_number_impl!(u16, b'q');
+fixed_number_impl!(u32, b'u');
+fixed_number_impl!(u64, b't');
+fixed_number_impl!(i16, b'n');
+fixed_number_impl!(i32, b'i');
+fixed_number_impl!(i64, b'x');
+fixed_number_impl!(f64, b'd');
+
 pub trait Marshal {
-    fn signature(&self) -> &SignatureSingle;
+    fn signature(&self) -> Cow<'_, SignatureSingle>;
 //    fn append_sig_to(&self, s: &mut SignatureMultiBuf) -> Result<(), DemarshalError>;
     fn append_data_to(&self, v: &mut Vec<u8>);
+    /// Like append_data_to, but returns an error instead of appending data that is not valid
+    /// D-Bus, e g an array larger than 64 MiB. This is what the buffers use to append values.
+    ///
+    /// On error, the data appended so far is left in v.
+    fn try_append_data_to(&self, v: &mut Vec<u8>) -> Result<(), MarshalError> {
+        self.append_data_to(v);
+        Ok(())
+    }
+}
+
+/// Checks a signature built from other signatures, which might be too long or too deeply nested.
+fn check_signature(sig: &SignatureSingle) -> Result<(), MarshalError> {
+    if sig.len() > 255 { return Err(MarshalError::SignatureTooLong) }
+    SignatureSingle::is_valid(sig).map_err(|_| MarshalError::InvalidString)
+}
+
+/// A type whose values all have the same signature, so that it is known without a value.
+///
+/// This is what gives slices and Vecs their element signature, also when they are empty.
+/// Types such as ArrayBuf, where the signature depends on the value, do not implement it.
+pub trait StaticSignature {
+    fn static_signature() -> Cow<'static, SignatureSingle>;
+}
+
+macro_rules! static_signature_impl {
+    ($t: ty, $s: expr) => {
+        impl StaticSignature for $t {
+            fn static_signature() -> Cow<'static, SignatureSingle> { SignatureSingle::new_unchecked($s).into() }
+        }
+    }
+}
+
+static_signature_impl!(bool, "b");
+static_signature_impl!(DBusStr, "s");
+// A str is not Marshal, but it is appended as a D-Bus string, see AppendToMulti.
+static_signature_impl!(str, "s");
+static_signature_impl!(dbus_strings::DBusString, "s");
+static_signature_impl!(char, "s");
+static_signature_impl!(dbus_strings::ObjectPath, "o");
+static_signature_impl!(dbus_strings::ObjectPathBuf, "o");
+static_signature_impl!(SignatureMulti, "g");
+static_signature_impl!(SignatureMultiBuf, "g");
+static_signature_impl!(SignatureSingle, "g");
+static_signature_impl!(SignatureSingleBuf, "g");
+static_signature_impl!(VariantBuf, "v");
+static_signature_impl!(ByteArray<'_>, "ay");
+
+/// A value that can be appended to a MultiBuf. Unlike Marshal, this can fail,
+/// which allows for values that need validation, such as &str.
+pub trait AppendToMulti {
+    fn append_to(&self, m: &mut MultiBuf) -> Result<(), MarshalError>;
+}
+
+impl<T: Marshal + ?Sized> AppendToMulti for T {
+    fn append_to(&self, m: &mut MultiBuf) -> Result<(), MarshalError> { m.append(self) }
 }
 
+impl AppendToMulti for &str {
+    fn append_to(&self, m: &mut MultiBuf) -> Result<(), MarshalError> { m.append(DBusStr::new(self)?) }
+}
+
+/// A tuple of values, which are appended one after another to a MultiBuf.
+pub trait MarshalTuple {
+    fn append_to(&self, m: &mut MultiBuf) -> Result<(), MarshalError>;
+}
+
+macro_rules! marshal_tuple_impl {
+    ($($t: ident $n: tt),*) => {
+        impl<$($t: AppendToMulti),*> MarshalTuple for ($($t,)*) {
+            fn append_to(&self, m: &mut MultiBuf) -> Result<(), MarshalError> {
+                $( self.$n.append_to(m)?; )*
+                Ok(())
+            }
+        }
+
+        /// The signature of the struct that the tuple becomes in ArrayBuf::from_structs.
+        impl<$($t: StaticSignature),*> StaticSignature for ($($t,)*) {
+            fn static_signature() -> Cow<'static, SignatureSingle> {
+                let mut x = String::from("(");
+                $( x.push_str(&$t::static_signature()); )*
+                x.push(')');
+                SignatureSingle::new_unchecked_owned(x).into()
+            }
+        }
+    }
+}
+
+marshal_tuple_impl!(A 0);
+marshal_tuple_impl!(A 0, B 1);
+marshal_tuple_impl!(A 0, B 1, C 2);
+marshal_tuple_impl!(A 0, B 1, C 2, D 3);
+marshal_tuple_impl!(A 0, B 1, C 2, D 3, E 4);
+marshal_tuple_impl!(A 0, B 1, C 2, D 3, E 4, F 5);
+marshal_tuple_impl!(A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+marshal_tuple_impl!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+
 macro_rules! marshal_impl {
     ($t: ty, $s: expr, $a: expr) => {
         impl Marshal for $t {
-            fn signature(&self) -> &SignatureSingle {
-                SignatureSingle::new_unchecked($s)
+            fn signature(&self) -> Cow<'_, SignatureSingle> {
+                SignatureSingle::new_unchecked($s).into()
             }
             fn append_data_to(&self, v: &mut Vec<u8>) {
                 align_buf(v, $a);
                 v.extend_from_slice(&self.to_ne_bytes())
             }
         }
+        static_signature_impl!($t, $s);
     }
 }
 
@@ -603,8 +1809,13 @@ marshal_impl!(i32, "i", 4);
 marshal_impl!(i64, "x", 8);
 marshal_impl!(f64, "d", 8);
 
+impl Marshal for bool {
+    fn signature(&self) -> Cow<'_, SignatureSingle> { SignatureSingle::new_unchecked("b").into() }
+    fn append_data_to(&self, v: &mut Vec<u8>) { (*self as u32).append_data_to(v) }
+}
+
 impl Marshal for DBusStr {
-    fn signature(&self) -> &SignatureSingle { SignatureSingle::new_unchecked("s") }
+    fn signature(&self) -> Cow<'_, SignatureSingle> { SignatureSingle::new_unchecked("s").into() }
     fn append_data_to(&self, v: &mut Vec<u8>) {
         let slen = self.len() as u32;
         slen.append_data_to(v);
@@ -613,15 +1824,34 @@ impl Marshal for DBusStr {
     }
 }
 
+/// Marshals as a D-Bus string containing a single character.
+///
+/// D-Bus strings cannot contain the nul character, so appending '\0' to a buffer fails.
+impl Marshal for char {
+    fn signature(&self) -> Cow<'_, SignatureSingle> { SignatureSingle::new_unchecked("s").into() }
+    fn append_data_to(&self, v: &mut Vec<u8>) {
+        let mut b = [0; 4];
+        let s = self.encode_utf8(&mut b);
+        (s.len() as u32).append_data_to(v);
+        v.extend_from_slice(s.as_bytes());
+        v.push(0);
+    }
+    fn try_append_data_to(&self, v: &mut Vec<u8>) -> Result<(), MarshalError> {
+        if *self == '\0' { return Err(MarshalError::InvalidString) }
+        self.append_data_to(v);
+        Ok(())
+    }
+}
+
 impl Marshal for dbus_strings::ObjectPath {
-    fn signature(&self) -> &SignatureSingle { SignatureSingle::new_unchecked("o") }
+    fn signature(&self) -> Cow<'_, SignatureSingle> { SignatureSingle::new_unchecked("o").into() }
     fn append_data_to(&self, v: &mut Vec<u8>) {
         self.as_dbus_str().append_data_to(v);
     }
 }
 
 impl Marshal for SignatureMulti {
-    fn signature(&self) -> &SignatureSingle { SignatureSingle::new_unchecked("g") }
+    fn signature(&self) -> Cow<'_, SignatureSingle> { SignatureSingle::new_unchecked("g").into() }
     fn append_data_to(&self, v: &mut Vec<u8>) {
         v.push(self.len() as u8);
         v.extend_from_slice(self.as_bytes());
@@ -630,10 +1860,1737 @@ impl Marshal for SignatureMulti {
 }
 
 impl Marshal for SignatureSingle {
-    fn signature(&self) -> &SignatureSingle { SignatureSingle::new_unchecked("g") }
+    fn signature(&self) -> Cow<'_, SignatureSingle> { SignatureSingle::new_unchecked("g").into() }
     fn append_data_to(&self, v: &mut Vec<u8>) {
         v.push(self.len() as u8);
         v.extend_from_slice(self.as_bytes());
         v.push(0);
     }
 }
+
+// The owned string types marshal like the borrowed types they deref to.
+macro_rules! marshal_owned_impl {
+    ($t: ty) => {
+        impl Marshal for $t {
+            fn signature(&self) -> Cow<'_, SignatureSingle> { (**self).signature() }
+            fn append_data_to(&self, v: &mut Vec<u8>) { (**self).append_data_to(v) }
+        }
+    }
+}
+
+marshal_owned_impl!(dbus_strings::DBusString);
+marshal_owned_impl!(dbus_strings::ObjectPathBuf);
+marshal_owned_impl!(SignatureSingleBuf);
+marshal_owned_impl!(SignatureMultiBuf);
+
+// The signature might be too long or too deeply nested, which try_append_data_to reports.
+fn array_signature<T: StaticSignature + ?Sized>() -> SignatureSingleBuf {
+    let mut x = String::from("a");
+    x.push_str(&T::static_signature());
+    SignatureSingle::new_unchecked_owned(x)
+}
+
+// Appends the length, padding and elements of an array, with the elements appended by f.
+fn append_array_data<F>(v: &mut Vec<u8>, elem_align: usize, f: F) -> Result<(), MarshalError>
+where F: FnOnce(&mut Vec<u8>) -> Result<(), MarshalError> {
+    align_buf(v, 4);
+    let len_pos = v.len();
+    0u32.append_data_to(v);
+    align_buf(v, elem_align);
+    let start = v.len();
+    f(v)?;
+    let slen = v.len() - start;
+    if slen > ARRAY_MAX_LEN { return Err(MarshalError::ArrayTooBig) }
+    v[len_pos..len_pos+4].copy_from_slice(&(slen as u32).to_ne_bytes());
+    Ok(())
+}
+
+/// Marshals as a D-Bus array.
+impl<T: Marshal + StaticSignature> Marshal for [T] {
+    fn signature(&self) -> Cow<'_, SignatureSingle> { array_signature::<T>().into() }
+    fn append_data_to(&self, v: &mut Vec<u8>) {
+        // Only too big arrays fail, and append_data_to has no way to report that.
+        let _ = append_array_data(v, align_of(T::static_signature().as_bytes()[0]), |v| {
+            for x in self { x.append_data_to(v); }
+            Ok(())
+        });
+    }
+    fn try_append_data_to(&self, v: &mut Vec<u8>) -> Result<(), MarshalError> {
+        check_signature(&self.signature())?;
+        append_array_data(v, align_of(T::static_signature().as_bytes()[0]), |v| {
+            for x in self { x.try_append_data_to(v)?; }
+            Ok(())
+        })
+    }
+}
+
+impl<T: StaticSignature> StaticSignature for [T] {
+    fn static_signature() -> Cow<'static, SignatureSingle> { array_signature::<T>().into() }
+}
+
+impl<T: Marshal + StaticSignature> Marshal for Vec<T> {
+    fn signature(&self) -> Cow<'_, SignatureSingle> { (**self).signature() }
+    fn append_data_to(&self, v: &mut Vec<u8>) { (**self).append_data_to(v) }
+    fn try_append_data_to(&self, v: &mut Vec<u8>) -> Result<(), MarshalError> { (**self).try_append_data_to(v) }
+}
+
+impl<T: StaticSignature> StaticSignature for Vec<T> {
+    fn static_signature() -> Cow<'static, SignatureSingle> { array_signature::<T>().into() }
+}
+
+impl<T: StaticSignature + ?Sized> StaticSignature for &T {
+    fn static_signature() -> Cow<'static, SignatureSingle> { T::static_signature() }
+}
+
+impl<T: Marshal + ?Sized> Marshal for &T {
+    fn signature(&self) -> Cow<'_, SignatureSingle> { (**self).signature() }
+    fn append_data_to(&self, v: &mut Vec<u8>) { (**self).append_data_to(v) }
+    fn try_append_data_to(&self, v: &mut Vec<u8>) -> Result<(), MarshalError> { (**self).try_append_data_to(v) }
+}
+
+macro_rules! marshal_struct_impl {
+    ($($t: ident $n: tt),*) => {
+        /// Marshals as a D-Bus struct.
+        ///
+        /// The signature might be too long or too deeply nested, which try_append_data_to reports.
+        impl<$($t: Marshal),*> Marshal for ($($t,)*) {
+            fn signature(&self) -> Cow<'_, SignatureSingle> {
+                let mut x = String::from("(");
+                $( x.push_str(&self.$n.signature()); )*
+                x.push(')');
+                SignatureSingle::new_unchecked_owned(x).into()
+            }
+            fn append_data_to(&self, v: &mut Vec<u8>) {
+                align_buf(v, 8);
+                $( self.$n.append_data_to(v); )*
+            }
+            fn try_append_data_to(&self, v: &mut Vec<u8>) -> Result<(), MarshalError> {
+                check_signature(&self.signature())?;
+                align_buf(v, 8);
+                $( self.$n.try_append_data_to(v)?; )*
+                Ok(())
+            }
+        }
+    }
+}
+
+marshal_struct_impl!(A 0, B 1);
+marshal_struct_impl!(A 0, B 1, C 2);
+marshal_struct_impl!(A 0, B 1, C 2, D 3);
+marshal_struct_impl!(A 0, B 1, C 2, D 3, E 4);
+marshal_struct_impl!(A 0, B 1, C 2, D 3, E 4, F 5);
+
+/// Implements marshalling for a newtype, i e a tuple struct with a single field,
+/// so that it is marshalled exactly as its field, rather than as a D-Bus struct.
+///
+/// The field type must implement `marshalled::Marshal`, `marshalled::StaticSignature` and `marshalled::Demarshal`.
+///
+/// ```
+/// struct DeviceId(u32);
+/// dbus_native::marshal_transparent!(DeviceId, u32);
+/// ```
+#[macro_export]
+macro_rules! marshal_transparent {
+    ($t: ident, $inner: ty) => {
+        impl $crate::marshalled::Marshal for $t {
+            fn signature(&self) -> std::borrow::Cow<'_, $crate::strings::SignatureSingle> { $crate::marshalled::Marshal::signature(&self.0) }
+            fn append_data_to(&self, v: &mut Vec<u8>) { $crate::marshalled::Marshal::append_data_to(&self.0, v) }
+            fn try_append_data_to(&self, v: &mut Vec<u8>) -> Result<(), $crate::types::MarshalError> {
+                $crate::marshalled::Marshal::try_append_data_to(&self.0, v)
+            }
+        }
+
+        impl $crate::marshalled::StaticSignature for $t {
+            fn static_signature() -> std::borrow::Cow<'static, $crate::strings::SignatureSingle> {
+                <$inner as $crate::marshalled::StaticSignature>::static_signature()
+            }
+        }
+
+        impl<'a> $crate::marshalled::Demarshal<'a> for $t {
+            fn demarshal(single: &$crate::marshalled::Single<'a>) -> Result<Self, $crate::types::DemarshalError> {
+                <$inner as $crate::marshalled::Demarshal<'a>>::demarshal(single).map($t)
+            }
+        }
+    }
+}
+
+/// A byte array, which is marshalled as "ay" by copying all bytes at once,
+/// rather than one byte at a time.
+#[derive(Debug, Clone, Copy)]
+pub struct ByteArray<'a>(pub &'a [u8]);
+
+impl Marshal for ByteArray<'_> {
+    fn signature(&self) -> Cow<'_, SignatureSingle> { SignatureSingle::new_unchecked("ay").into() }
+    fn append_data_to(&self, v: &mut Vec<u8>) {
+        (self.0.len() as u32).append_data_to(v);
+        v.extend_from_slice(self.0);
+    }
+    fn try_append_data_to(&self, v: &mut Vec<u8>) -> Result<(), MarshalError> {
+        if self.0.len() > ARRAY_MAX_LEN { return Err(MarshalError::ArrayTooBig) }
+        self.append_data_to(v);
+        Ok(())
+    }
+}
+
+macro_rules! parsed_try_from_impl {
+    ($t: ty, $v: ident, $sig: expr) => {
+        impl TryFrom<Parsed<'_>> for $t {
+            type Error = DemarshalError;
+            fn try_from(p: Parsed<'_>) -> Result<Self, DemarshalError> {
+                if let Parsed::$v(x) = p { Ok(x) } else { Err(DemarshalError::wrong_type(Some($sig), Some(&p.signature()))) }
+            }
+        }
+    }
+}
+
+parsed_try_from_impl!(u8, Byte, "y");
+parsed_try_from_impl!(u16, UInt16, "q");
+parsed_try_from_impl!(u32, UInt32, "u");
+parsed_try_from_impl!(u64, UInt64, "t");
+parsed_try_from_impl!(i16, Int16, "n");
+parsed_try_from_impl!(i32, Int32, "i");
+parsed_try_from_impl!(i64, Int64, "x");
+parsed_try_from_impl!(f64, Double, "d");
+parsed_try_from_impl!(bool, Boolean, "b");
+
+/// A value that can be read from a Single. This is the reading counterpart of Marshal.
+pub trait Demarshal<'a>: Sized {
+    fn demarshal(single: &Single<'a>) -> Result<Self, DemarshalError>;
+}
+
+macro_rules! demarshal_impl {
+    ($t: ty) => {
+        impl Demarshal<'_> for $t {
+            fn demarshal(single: &Single) -> Result<Self, DemarshalError> { single.parse()?.try_into() }
+        }
+    }
+}
+
+demarshal_impl!(u8);
+demarshal_impl!(u16);
+demarshal_impl!(u32);
+demarshal_impl!(u64);
+demarshal_impl!(i16);
+demarshal_impl!(i32);
+demarshal_impl!(i64);
+demarshal_impl!(f64);
+demarshal_impl!(bool);
+
+impl<'a> Demarshal<'a> for &'a str {
+    fn demarshal(single: &Single<'a>) -> Result<Self, DemarshalError> { single.parse()?.as_str() }
+}
+
+/// Reads a string, but not an object path or signature.
+impl<'a> Demarshal<'a> for &'a DBusStr {
+    fn demarshal(single: &Single<'a>) -> Result<Self, DemarshalError> {
+        match single.parse()? {
+            Parsed::String(x) => Ok(x),
+            p => Err(DemarshalError::wrong_type(Some("s"), Some(&p.signature()))),
+        }
+    }
+}
+
+impl Demarshal<'_> for String {
+    fn demarshal(single: &Single) -> Result<Self, DemarshalError> { single.parse()?.as_str().map(Into::into) }
+}
+
+/// Reads an array, where every element is read as a T.
+impl<'a, T: Demarshal<'a>> Demarshal<'a> for Vec<T> {
+    fn demarshal(single: &Single<'a>) -> Result<Self, DemarshalError> {
+        match single.parse()? {
+            Parsed::Array(a) => a.map(|x| T::demarshal(&x?)).collect(),
+            p => Err(DemarshalError::wrong_type(None, Some(&p.signature()))),
+        }
+    }
+}
+
+macro_rules! demarshal_struct_impl {
+    ($($t: ident),*) => {
+        /// Reads a D-Bus struct, which must have exactly as many fields as the tuple.
+        impl<'a, $($t: Demarshal<'a>),*> Demarshal<'a> for ($($t,)*) {
+            fn demarshal(single: &Single<'a>) -> Result<Self, DemarshalError> {
+                let m = match single.parse()? {
+                    Parsed::Struct(m) => m,
+                    p => return Err(DemarshalError::wrong_type(None, Some(&p.signature()))),
+                };
+                let wrong_len = || DemarshalError::wrong_type(None, Some(m.signature()));
+                let mut iter = m.iter();
+                let r = ($($t::demarshal(&iter.next().ok_or_else(wrong_len)??)?,)*);
+                if iter.next().is_some() { return Err(wrong_len()) }
+                Ok(r)
+            }
+        }
+    }
+}
+
+demarshal_struct_impl!(A, B);
+demarshal_struct_impl!(A, B, C);
+demarshal_struct_impl!(A, B, C, D);
+
+#[test]
+fn single_endianness() {
+    let sig = SignatureSingle::new_unchecked("u");
+    let be = Single::new(sig, &[0, 0, 0, 5], 0, true);
+    assert!(be.is_big_endian());
+    assert!(matches!(be.parse().unwrap(), Parsed::UInt32(5)));
+    let le = Single::new(sig, &[5, 0, 0, 0], 0, false);
+    assert!(!le.is_big_endian());
+    assert!(matches!(le.parse().unwrap(), Parsed::UInt32(5)));
+}
+
+#[test]
+fn multibuf_clear() {
+    let mut b = MultiBuf::new();
+    b.append(&5u32).unwrap();
+    b.append(DBusStr::new("Hello").unwrap()).unwrap();
+    let cap = b.data.capacity();
+    b.clear();
+    assert_eq!(b.sig.len(), 0);
+    assert_eq!(b.data.len(), 0);
+    assert_eq!(b.data.capacity(), cap);
+
+    b.append(&7u8).unwrap();
+    let mut fresh = MultiBuf::new();
+    fresh.append(&7u8).unwrap();
+    assert_eq!(b.sig, fresh.sig);
+    assert_eq!(b.data, fresh.data);
+    assert_eq!(b.data.capacity(), cap);
+}
+
+#[test]
+fn parsed_string_kind() {
+    let s = Parsed::String(DBusStr::new("Hello").unwrap());
+    assert_eq!(s.string_kind(), Some(StringKind::Plain));
+    let o = Parsed::ObjectPath(dbus_strings::ObjectPath::new("/hello").unwrap());
+    assert_eq!(o.string_kind(), Some(StringKind::ObjectPath));
+    let g = Parsed::Signature(SignatureMulti::new("a{sv}").unwrap());
+    assert_eq!(g.string_kind(), Some(StringKind::Signature));
+    assert_eq!(Parsed::UInt32(5).string_kind(), None);
+}
+
+#[test]
+fn buffer_pool_reuse() {
+    let pool = BufferPool::new();
+    let mut b = MultiBuf::from_pool(&pool);
+    b.append(&5u32).unwrap();
+    b.append(DBusStr::new("Hello").unwrap()).unwrap();
+    let (ptr, cap) = (b.data.as_ptr(), b.data.capacity());
+    b.into_pool(&pool);
+    assert_eq!(pool.len(), 1);
+
+    for _ in 0..10 {
+        let mut b = MultiBuf::from_pool(&pool);
+        assert!(pool.is_empty());
+        assert_eq!(b.data.len(), 0);
+        b.append(&7u32).unwrap();
+        assert_eq!(b.data.as_ptr(), ptr);
+        assert_eq!(b.data.capacity(), cap);
+        b.into_pool(&pool);
+    }
+    assert_eq!(pool.len(), 1);
+}
+
+#[test]
+fn strictness_marshalled() {
+    fn parse_all(m: Multi) -> Result<(), DemarshalError> {
+        for x in m.iter() { x?.parse()?; }
+        Ok(())
+    }
+    let lenient = ParseStrictness::lenient();
+    let strict = ParseStrictness::strict();
+
+    let sig = SignatureMulti::new("yu").unwrap();
+    let data = [1, 9, 0, 0, 5, 0, 0, 0];
+    let m = Multi::new(sig, &data, false);
+    assert!(parse_all(m).is_ok());
+    assert!(parse_all(m.with_strictness(ParseStrictness { check_padding: false, ..strict })).is_ok());
+    assert!(parse_all(m.with_strictness(ParseStrictness { check_padding: true, ..lenient })).is_err());
+
+    let sig = SignatureSingle::new("s").unwrap();
+    let data = [2, 0, 0, 0, b'h', b'i', 1];
+    let s = Single::new(sig, &data, 0, false);
+    assert!(s.parse().is_ok());
+    assert!(s.with_strictness(ParseStrictness { check_nul: false, ..strict }).parse().is_ok());
+    assert!(s.with_strictness(ParseStrictness { check_nul: true, ..lenient }).parse().is_err());
+
+    let sig = SignatureSingle::new("a{yy}").unwrap();
+    let data = [10, 0, 0, 0, 0, 0, 0, 0, 1, 2, 0, 0, 0, 0, 0, 0, 1, 3];
+    let s = Single::new(sig, &data, 0, false);
+    assert!(s.parse().is_ok());
+    assert!(s.with_strictness(ParseStrictness { check_dict_keys: false, ..strict }).parse().is_ok());
+    assert!(s.with_strictness(ParseStrictness { check_dict_keys: true, ..lenient }).parse().is_err());
+}
+
+#[test]
+fn marshal_vec() {
+    let mut b = MultiBuf::new();
+    b.append(&5u8).unwrap();
+    b.append(&vec![1u32, 2, 3]).unwrap();
+    b.append(&Vec::<u8>::new()).unwrap();
+    b.append(&[7i64][..]).unwrap();
+    assert_eq!(&**b.sig, "yauayax");
+
+    let mut a = ArrayBuf::new(SignatureSingle::new("u").unwrap()).unwrap();
+    for x in &[1u32, 2, 3] { a.append(x).unwrap(); }
+    let mut b2 = MultiBuf::new();
+    b2.append(&5u8).unwrap();
+    b2.append(&a).unwrap();
+    assert_eq!(&b.data[..b2.data.len()], &*b2.data);
+
+    let mut iter = b.multi().iter();
+    assert!(matches!(iter.next().unwrap().unwrap().parse().unwrap(), Parsed::Byte(5)));
+    if let Parsed::Array(mut a) = iter.next().unwrap().unwrap().parse().unwrap() {
+        assert_eq!(&**a.inner_sig, "u");
+        assert_eq!(a.data.len(), 12);
+        assert!(matches!(a.next().unwrap().unwrap().parse().unwrap(), Parsed::UInt32(1)));
+    } else { panic!() }
+    if let Parsed::Array(mut a) = iter.next().unwrap().unwrap().parse().unwrap() {
+        assert_eq!(&**a.inner_sig, "y");
+        assert!(a.next().is_none());
+    } else { panic!() }
+    if let Parsed::Array(mut a) = iter.next().unwrap().unwrap().parse().unwrap() {
+        assert_eq!(&**a.inner_sig, "x");
+        assert!(matches!(a.next().unwrap().unwrap().parse().unwrap(), Parsed::Int64(7)));
+    } else { panic!() }
+    assert!(iter.next().is_none());
+}
+
+#[test]
+fn marshal_vec_too_big() {
+    let mut b = MultiBuf::new();
+    b.append(&5u8).unwrap();
+    let v = vec![0u64; ARRAY_MAX_LEN / 8 + 1];
+    assert!(matches!(b.append(&v), Err(MarshalError::ArrayTooBig)));
+    assert!(matches!(VariantBuf::new(&v[..]), Err(DemarshalError::NumberTooBig)));
+    assert_eq!(&**b.sig, "y");
+    assert_eq!(b.data, [5]);
+}
+
+#[test]
+fn marshal_vec_borrowed() {
+    let (x, y) = (DBusStr::new("x").unwrap(), DBusStr::new("yz").unwrap());
+    let mut b = MultiBuf::new();
+    b.append(&vec![x, y]).unwrap();
+    b.append(&Vec::<Vec<(u16, &DBusStr)>>::new()).unwrap();
+    b.append(&vec![(1u8, x)]).unwrap();
+    assert_eq!(&**b.sig, "asaa(qs)a(ys)");
+    assert_eq!(&*<[&DBusStr]>::static_signature(), SignatureSingle::new("as").unwrap());
+
+    let mut iter = b.multi().iter();
+    if let Parsed::Array(a) = iter.next().unwrap().unwrap().parse().unwrap() {
+        let v: Vec<String> = a.map(|s| s.unwrap().parse().unwrap().as_dbus_str().unwrap().to_string()).collect();
+        assert_eq!(v, vec!["x", "yz"]);
+    } else { panic!() }
+    if let Parsed::Array(mut a) = iter.next().unwrap().unwrap().parse().unwrap() {
+        assert_eq!(&**a.inner_sig, "a(qs)");
+        assert!(a.next().is_none());
+    } else { panic!() }
+}
+
+#[test]
+fn misaligned_array_length() {
+    let sig = SignatureSingle::new("au").unwrap();
+    let data = [6, 0, 0, 0, 1, 0, 0, 0, 2, 0];
+    let s = Single::new(sig, &data, 0, false);
+    assert!(matches!(s.parse(), Err(DemarshalError::MisalignedArrayLength)));
+
+    let data = [8, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0];
+    let s = Single::new(sig, &data, 0, false);
+    assert!(matches!(s.parse(), Ok(Parsed::Array(_))));
+}
+
+#[test]
+fn parse_str_zero_copy() {
+    let data = [5, 0, 0, 0, b'H', b'e', b'l', b'l', b'o', 0];
+    let s = Single::new(SignatureSingle::new("s").unwrap(), &data, 0, false);
+    let x = s.parse().unwrap().as_dbus_str().unwrap().as_ptr();
+    assert_eq!(x, data[4..].as_ptr());
+
+    let data = [2, 0, 0, 0, b'/', b'a', 0];
+    let s = Single::new(SignatureSingle::new("o").unwrap(), &data, 0, false);
+    let x = s.parse().unwrap().as_dbus_str().unwrap().as_ptr();
+    assert_eq!(x, data[4..].as_ptr());
+
+    let data = [2, b'a', b'y', 0];
+    let s = Single::new(SignatureSingle::new("g").unwrap(), &data, 0, false);
+    let x = s.parse().unwrap().as_dbus_str().unwrap().as_ptr();
+    assert_eq!(x, data[1..].as_ptr());
+}
+
+#[test]
+fn marshal_char() {
+    let mut b = MultiBuf::new();
+    b.append(&'A').unwrap();
+    b.append(&'\u{1F600}').unwrap();
+    assert_eq!(&**b.sig, "ss");
+    let mut iter = b.multi().iter();
+    let x = iter.next().unwrap().unwrap().parse().unwrap();
+    assert_eq!(&**x.as_dbus_str().unwrap(), "A");
+    let x = iter.next().unwrap().unwrap().parse().unwrap();
+    assert_eq!(&**x.as_dbus_str().unwrap(), "\u{1F600}");
+    assert_eq!(&b.data[8..12], &4u32.to_ne_bytes());
+    assert!(iter.next().is_none());
+
+    // D-Bus strings cannot contain nul
+    assert!(matches!(b.append(&'\0'), Err(MarshalError::InvalidString)));
+    assert_eq!(&**b.sig, "ss");
+    assert_eq!(b.data.len(), 17);
+}
+
+#[test]
+fn multibuf_append_array() {
+    let elems: Vec<StructBuf> = [("Hello", 1i32), ("World", -2), ("!", 3)].iter().map(|&(s, i)| {
+        let mut m = MultiBuf::new();
+        m.append(DBusStr::new(s).unwrap()).unwrap();
+        m.append(&i).unwrap();
+        StructBuf::new(m).unwrap()
+    }).collect();
+    let elem_sig = SignatureSingle::new("(si)").unwrap();
+
+    let mut a = ArrayBuf::new(elem_sig).unwrap();
+    for x in &elems { a.append(x).unwrap(); }
+    let mut b1 = MultiBuf::new();
+    b1.append(&5u8).unwrap();
+    b1.append(&a).unwrap();
+
+    let mut b2 = MultiBuf::new();
+    b2.append(&5u8).unwrap();
+    b2.append_array(elem_sig, &elems).unwrap();
+    assert_eq!(&**b2.sig, "ya(si)");
+    assert_eq!(b1.sig, b2.sig);
+    assert_eq!(b1.data, b2.data);
+
+    let old = b2.clone();
+    assert!(matches!(b2.append_array(SignatureSingle::new("s").unwrap(), &elems), Err(MarshalError::WrongType { .. })));
+    assert_eq!(old.sig, b2.sig);
+    assert_eq!(old.data, b2.data);
+
+    b2.append_array::<u64, _>(SignatureSingle::new("t").unwrap(), &[]).unwrap();
+    assert_eq!(&**b2.sig, "ya(si)at");
+}
+
+#[test]
+fn not_enough_data_context() {
+    let data = [5, 0, 0, 0, 5, 0, 0, 0, b'H', b'e'];
+    let mut iter = Multi::new(SignatureMulti::new("us").unwrap(), &data, false).iter();
+    assert!(matches!(iter.next().unwrap().unwrap().parse().unwrap(), Parsed::UInt32(5)));
+    let e = iter.next().unwrap().unwrap_err();
+    assert!(matches!(e, DemarshalError::NotEnoughData { at: 4, needed: 10, available: 6 }));
+
+    let s = Single::new(SignatureSingle::new("s").unwrap(), &data[4..], 4, false);
+    let e = s.parse().unwrap_err();
+    assert!(matches!(e, DemarshalError::NotEnoughData { at: 4, needed: 9, available: 6 }));
+}
+
+#[test]
+fn multibuf_long_struct() {
+    let mut m = MultiBuf::new();
+    for i in 0..200u8 { m.append(&i).unwrap(); }
+    assert_eq!(m.sig.len(), 200);
+    let s = StructBuf::new(m).unwrap();
+    assert_eq!(s.signature().len(), 202);
+
+    let mut b = MultiBuf::new();
+    b.append(&s).unwrap();
+    let mut iter = b.multi().iter();
+    if let Parsed::Struct(x) = iter.next().unwrap().unwrap().parse().unwrap() {
+        for (i, v) in x.iter().enumerate() {
+            assert!(matches!(v.unwrap().parse().unwrap(), Parsed::Byte(z) if z as usize == i));
+        }
+        assert_eq!(x.iter().count(), 200);
+    } else { panic!() }
+
+    for _ in 0..53 { b.append(&0u8).unwrap(); }
+    assert_eq!(b.sig.len(), 255);
+    assert!(matches!(b.append(&0u8), Err(MarshalError::SignatureTooLong)));
+}
+
+#[test]
+fn arraybuf_from_structs() {
+    let v = vec![("Hello", 1i32), ("World", -2), ("!", 3)];
+    let a = ArrayBuf::from_structs(v.iter().copied()).unwrap();
+    assert_eq!(&**a.signature(), "a(si)");
+
+    let mut a2 = ArrayBuf::new(SignatureSingle::new("(si)").unwrap()).unwrap();
+    for (s, i) in &v {
+        let mut m = MultiBuf::new();
+        m.append(DBusStr::new(s).unwrap()).unwrap();
+        m.append(i).unwrap();
+        a2.append(&StructBuf::new(m).unwrap()).unwrap();
+    }
+    assert_eq!(a.data, a2.data);
+
+    let mut b = MultiBuf::new();
+    b.append(&a).unwrap();
+    let x = b.multi().iter().next().unwrap().unwrap().parse().unwrap();
+    if let Parsed::Array(mut arr) = x {
+        if let Parsed::Struct(st) = arr.next().unwrap().unwrap().parse().unwrap() {
+            let mut st = st.iter();
+            assert_eq!(&**st.next().unwrap().unwrap().parse().unwrap().as_dbus_str().unwrap(), "Hello");
+            assert!(matches!(st.next().unwrap().unwrap().parse().unwrap(), Parsed::Int32(1)));
+        } else { panic!() }
+    } else { panic!() }
+
+    let empty = ArrayBuf::from_structs(Vec::<(&str, i32)>::new()).unwrap();
+    assert_eq!(&**empty.signature(), "a(si)");
+    assert!(empty.is_empty());
+    let mut b = MultiBuf::new();
+    b.append(&empty).unwrap();
+    assert!(matches!(b.multi().iter().next().unwrap().unwrap().parse().unwrap(), Parsed::Array(a) if a.count() == 0));
+    assert!(ArrayBuf::from_structs(vec![("a\0b", 1i32)]).is_err());
+}
+
+#[test]
+fn numeric_lossy() {
+    let data = 4000000000u32.to_le_bytes();
+    let s = Single::new(SignatureSingle::new("u").unwrap(), &data, 0, false);
+    assert_eq!(s.get_numeric_lossy::<i64>().unwrap(), 4000000000);
+    assert!(matches!(s.get_numeric_lossy::<i32>(), Err(DemarshalError::NumberTooBig)));
+
+    let data = (-5i32).to_le_bytes();
+    let s = Single::new(SignatureSingle::new("i").unwrap(), &data, 0, false);
+    assert!(matches!(s.get_numeric_lossy::<u32>(), Err(DemarshalError::NumberTooBig)));
+    assert_eq!(s.get_numeric_lossy::<i8>().unwrap(), -5);
+
+    let data = 7i32.to_le_bytes();
+    let s = Single::new(SignatureSingle::new("i").unwrap(), &data, 0, false);
+    assert_eq!(s.get_numeric_lossy::<u32>().unwrap(), 7);
+
+    let data = 1.5f64.to_le_bytes();
+    let s = Single::new(SignatureSingle::new("d").unwrap(), &data, 0, false);
+    assert!(matches!(s.get_numeric_lossy::<u32>(), Err(DemarshalError::WrongType { .. })));
+}
+
+#[test]
+fn ip_addr_roundtrip() {
+    let addrs: [IpAddr; 2] = ["192.168.1.17".parse().unwrap(), "2001:db8::ff00:42:8329".parse().unwrap()];
+    for enc in &[IpAddrEncoding::Bytes, IpAddrEncoding::FamilyBytes] {
+        let mut b = MultiBuf::new();
+        for a in &addrs { b.append_ip_addr(a, *enc).unwrap(); }
+        let sig = if *enc == IpAddrEncoding::Bytes { "ayay" } else { "(iay)(iay)" };
+        assert_eq!(&**b.sig, sig);
+        let v: Vec<IpAddr> = b.multi().iter().map(|x| x.unwrap().read_ip_addr().unwrap()).collect();
+        assert_eq!(&v, &addrs);
+    }
+
+    let mut b = MultiBuf::new();
+    b.append(&vec![1u8, 2, 3]).unwrap();
+    let mut m = MultiBuf::new();
+    m.append(&AF_INET6).unwrap();
+    m.append(&vec![1u8, 2, 3, 4]).unwrap();
+    b.append(&StructBuf::new(m).unwrap()).unwrap();
+    for x in b.multi().iter() {
+        assert!(matches!(x.unwrap().read_ip_addr(), Err(DemarshalError::InvalidProtocol)));
+    }
+}
+
+#[test]
+fn read_u16() {
+    let sig = SignatureSingle::new("q").unwrap();
+    let le = Single::new(sig, &[0x02, 0x01], 0, false);
+    assert!(matches!(le.parse().unwrap(), Parsed::UInt16(0x0102)));
+    let be = Single::new(sig, &[0x01, 0x02], 0, true);
+    assert!(matches!(be.parse().unwrap(), Parsed::UInt16(0x0102)));
+    let sig = SignatureSingle::new("n").unwrap();
+    let le = Single::new(sig, &[0xfe, 0xff], 0, false);
+    assert!(matches!(le.parse().unwrap(), Parsed::Int16(-2)));
+    assert!(Single::new(sig, &[0xfe], 0, false).parse().is_err());
+
+    let mut b = MultiBuf::new();
+    b.append(&0x0102u16).unwrap();
+    b.append(&0x0304u16).unwrap();
+    b.append(&-2i16).unwrap();
+    let v: Vec<_> = b.multi().iter().map(|x| x.unwrap().parse().unwrap()).collect();
+    assert!(matches!(v[..], [Parsed::UInt16(0x0102), Parsed::UInt16(0x0304), Parsed::Int16(-2)]));
+}
+
+#[test]
+fn parsed_outlives_iterators() {
+    fn flatten<'a>(p: Parsed<'a>, v: &mut Vec<Parsed<'a>>) {
+        match p {
+            Parsed::Array(a) => for x in a { flatten(x.unwrap().parse().unwrap(), v) },
+            Parsed::Dict(d) => for x in d {
+                let (k, x) = x.unwrap();
+                flatten(k.parse().unwrap(), v);
+                flatten(x.parse().unwrap(), v);
+            },
+            Parsed::Struct(m) => for x in m.iter() { flatten(x.unwrap().parse().unwrap(), v) },
+            x => v.push(x),
+        }
+    }
+
+    let mut b = MultiBuf::new();
+    b.append(&ArrayBuf::from_structs(vec!(("Hello", 5i32))).unwrap()).unwrap();
+    let mut d = DictBuf::new(SignatureSingleBuf::new("s").unwrap(), SignatureSingleBuf::new("(si)").unwrap()).unwrap();
+    let mut inner = MultiBuf::new();
+    inner.append(DBusStr::new("World").unwrap()).unwrap();
+    inner.append(&7i32).unwrap();
+    let inner = StructBuf::new(inner).unwrap();
+    d.append(DBusStr::new("a").unwrap(), &inner).unwrap();
+    d.append(DBusStr::new("b").unwrap(), &inner).unwrap();
+    b.append(&d).unwrap();
+
+    let mut v: Vec<Parsed> = vec!();
+    for x in b.multi().iter() { flatten(x.unwrap().parse().unwrap(), &mut v); }
+    let strs: Vec<&DBusStr> = v.iter().filter_map(|x| x.as_dbus_str().ok()).collect();
+    assert_eq!(v.len(), 8);
+    drop(v);
+    let strs: Vec<&str> = strs.iter().map(|x| &***x).collect();
+    assert_eq!(strs, vec!("Hello", "a", "World", "b", "World"));
+}
+
+#[test]
+fn array_iter_alignment() {
+    let mut b = MultiBuf::new();
+    b.append(&ArrayBuf::from_structs(vec!((1u8, 0x0102u16), (3, 0x0304), (5, 0x0506))).unwrap()).unwrap();
+    b.append(&vec!(7i32, 8, 9)).unwrap();
+    let mut iter = b.multi().iter();
+    let a = match iter.next().unwrap().unwrap().parse().unwrap() {
+        Parsed::Array(a) => a,
+        _ => unreachable!(),
+    };
+    let v: Vec<(u8, u16)> = a.map(|x| {
+        let m = match x.unwrap().parse().unwrap() { Parsed::Struct(m) => m, _ => unreachable!() };
+        let mut m = m.iter();
+        match (m.next().unwrap().unwrap().parse().unwrap(), m.next().unwrap().unwrap().parse().unwrap()) {
+            (Parsed::Byte(y), Parsed::UInt16(q)) => (y, q),
+            _ => unreachable!(),
+        }
+    }).collect();
+    assert_eq!(v, vec!((1, 0x0102), (3, 0x0304), (5, 0x0506)));
+    let a = match iter.next().unwrap().unwrap().parse().unwrap() {
+        Parsed::Array(a) => a,
+        _ => unreachable!(),
+    };
+    let v: Vec<i32> = a.map(|x| x.unwrap().get_numeric_lossy().unwrap()).collect();
+    assert_eq!(v, vec!(7, 8, 9));
+}
+
+#[test]
+fn recursion_limit() {
+    fn nested_variants(count: usize) -> Vec<u8> {
+        let mut v = vec!();
+        for _ in 1..count { v.extend_from_slice(&[1, b'v', 0]); }
+        v.extend_from_slice(&[1, b'y', 0, 5]);
+        v
+    }
+    let sig = SignatureSingle::new("v").unwrap();
+    let data = nested_variants(32);
+    assert!(Single::new(sig, &data, 0, false).get_real_length().is_ok());
+    let mut s = Single::new(sig, &data, 0, false);
+    while let Parsed::Variant(inner) = s.parse().unwrap() { s = inner; }
+    assert!(matches!(s.parse().unwrap(), Parsed::Byte(5)));
+
+    let data = nested_variants(33);
+    let s = Single::new(sig, &data, 0, false);
+    assert!(matches!(s.get_real_length(), Err(DemarshalError::RecursionLimit)));
+    let mut s = Single::new(sig, &data, 0, false);
+    let e = loop {
+        match s.parse() {
+            Ok(Parsed::Variant(inner)) => s = inner,
+            Ok(_) => unreachable!(),
+            Err(e) => break e,
+        }
+    };
+    assert!(matches!(e, DemarshalError::RecursionLimit));
+
+    let mut data = vec!(0u8; 4);
+    data.extend_from_slice(&nested_variants(32));
+    let sig = SignatureSingle::new("(iv)").unwrap();
+    let s = Single::new(sig, &data, 0, false);
+    assert!(matches!(s.get_real_length(), Err(DemarshalError::RecursionLimit)));
+}
+
+#[test]
+fn error_offsets() {
+    // A byte, then a struct containing a byte and an array claiming two i32, but only one is present.
+    let data = [1, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 8, 0, 0, 0, 3, 0, 0, 0];
+    let sig = SignatureMulti::new("y(yai)").unwrap();
+    let m = Multi::new(sig, &data, false);
+    let mut iter = m.iter();
+    iter.next().unwrap().unwrap();
+    assert_eq!(iter.position(), 8);
+    assert!(matches!(iter.next().unwrap(), Err(DemarshalError::NotEnoughData { at: 12, needed: 12, available: 8 })));
+    assert_eq!(iter.position(), 8);
+
+    let sig = SignatureSingle::new("ai").unwrap();
+    let e = Single::new(sig, &data[12..], 12, false).parse_at().unwrap_err();
+    assert_eq!(e.offset, 16);
+    assert!(matches!(e.error, DemarshalError::NotEnoughData { .. }));
+    let sig = SignatureSingle::new("b").unwrap();
+    let e = Single::new(sig, &data[16..], 16, false).parse_at().unwrap_err();
+    assert_eq!(e.offset, 16);
+    assert!(matches!(e.error, DemarshalError::InvalidBoolean));
+    assert_eq!(e.to_string(), "invalid boolean (at offset 16)");
+}
+
+#[test]
+fn variant_overrun() {
+    let sig = SignatureSingle::new("v").unwrap();
+    // Variant containing a string claiming 100 bytes, where only 3 remain.
+    let data = [1, b's', 0, 0, 100, 0, 0, 0, b'a', b'b', 0];
+    let s = Single::new(sig, &data, 0, false);
+    assert!(matches!(s.get_real_length(), Err(DemarshalError::NotEnoughData { at: 4, needed: 105, available: 7 })));
+    assert!(matches!(s.parse(), Err(DemarshalError::NotEnoughData { .. })));
+
+    let sig = SignatureMulti::new("vy").unwrap();
+    let m = Multi::new(sig, &data, false);
+    assert!(matches!(m.iter().next().unwrap(), Err(DemarshalError::NotEnoughData { .. })));
+
+    let data = [1, b's', 0, 0, 2, 0, 0, 0, b'a', b'b', 0, 7];
+    let m = Multi::new(sig, &data, false);
+    let v: Vec<_> = m.iter().map(|x| x.unwrap().parse().unwrap()).collect();
+    match &v[..] {
+        [Parsed::Variant(s), Parsed::Byte(7)] => assert_eq!(&**s.parse().unwrap().as_dbus_str().unwrap(), "ab"),
+        _ => panic!(),
+    }
+}
+
+#[cfg(feature = "bytes")]
+#[test]
+fn multibuf_bytes() {
+    let mut b = MultiBuf::new();
+    b.append(&5u8).unwrap();
+    b.append(DBusStr::new("Hello").unwrap()).unwrap();
+    b.append(&7u64).unwrap();
+
+    let mut bm = bytes::BytesMut::from(&[1u8, 2, 3][..]);
+    b.append_data_to_bytes(&mut bm);
+    let mut v = vec!(1u8, 2, 3);
+    align_buf(&mut v, 8);
+    v.extend_from_slice(&b.data);
+    assert_eq!(&bm[..], &v[..]);
+
+    let mut bm = bytes::BytesMut::new();
+    b.append_data_to_bytes(&mut bm);
+    let data = bm.freeze();
+    let m = Multi::from_bytes(&b.sig, &data, IS_BIG_ENDIAN);
+    let v: Vec<_> = m.iter().map(|x| x.unwrap().parse().unwrap()).collect();
+    assert!(matches!(v[..], [Parsed::Byte(5), Parsed::String(_), Parsed::UInt64(7)]));
+}
+
+#[test]
+fn marshal_bool() {
+    let mut b = MultiBuf::new();
+    b.append(&5u8).unwrap();
+    b.append(&true).unwrap();
+    b.append(&false).unwrap();
+    assert_eq!(&**b.sig, "ybb");
+    assert_eq!(&b.data[4..], &[1u32.to_ne_bytes(), 0u32.to_ne_bytes()].concat()[..]);
+    let v: Vec<_> = b.multi().iter().map(|x| x.unwrap().parse().unwrap()).collect();
+    assert!(matches!(v[..], [Parsed::Byte(5), Parsed::Boolean(true), Parsed::Boolean(false)]));
+}
+
+#[test]
+fn marshal_byte_array() {
+    let data: Vec<u8> = (0..10240).map(|x| (x * 7) as u8).collect();
+    let mut b = MultiBuf::new();
+    b.append(&5u8).unwrap();
+    b.append(&ByteArray(&data)).unwrap();
+    assert_eq!(&**b.sig, "yay");
+
+    let mut b2 = MultiBuf::new();
+    b2.append(&5u8).unwrap();
+    b2.append(&*data).unwrap();
+    assert_eq!(b.data, b2.data);
+
+    let mut iter = b.multi().iter();
+    iter.next().unwrap().unwrap();
+    let a = match iter.next().unwrap().unwrap().parse().unwrap() {
+        Parsed::Array(a) => a,
+        _ => unreachable!(),
+    };
+    let v: Vec<u8> = a.map(|x| match x.unwrap().parse().unwrap() {
+        Parsed::Byte(x) => x,
+        _ => unreachable!(),
+    }).collect();
+    assert_eq!(v, data);
+
+    let data = vec![0u8; ARRAY_MAX_LEN + 1];
+    assert!(matches!(b.append(&ByteArray(&data)), Err(MarshalError::ArrayTooBig)));
+    assert_eq!(&**b.sig, "yay");
+}
+
+#[test]
+fn double_special_values() {
+    let sig = SignatureSingle::new("d").unwrap();
+    let values = [f64::NAN, -f64::NAN, f64::INFINITY, f64::NEG_INFINITY, -0.0, 0.0, f64::MIN_POSITIVE / 2.0];
+    for &x in &values {
+        let mut b = MultiBuf::new();
+        b.append(&x).unwrap();
+        let le = x.to_le_bytes();
+        let be = x.to_be_bytes();
+        let parsed = [
+            b.multi().iter().next().unwrap().unwrap(),
+            Single::new(sig, &le, 0, false),
+            Single::new(sig, &be, 0, true),
+        ];
+        for s in &parsed {
+            match s.parse().unwrap() {
+                Parsed::Double(y) => assert_eq!(x.to_bits(), y.to_bits()),
+                _ => unreachable!(),
+            }
+        }
+        assert!(parsed[1].value_eq(&parsed[2]).unwrap());
+    }
+
+    let nan = f64::NAN.to_le_bytes();
+    let s = Single::new(sig, &nan, 0, false);
+    assert!(s.value_eq(&s).unwrap());
+    let (zero, neg_zero) = (0f64.to_le_bytes(), (-0f64).to_le_bytes());
+    assert!(!Single::new(sig, &zero, 0, false).value_eq(&Single::new(sig, &neg_zero, 0, false)).unwrap());
+
+    let mut b1 = MultiBuf::new();
+    b1.append(&vec!(f64::NAN, 1.5)).unwrap();
+    b1.append(DBusStr::new("x").unwrap()).unwrap();
+    let mut b2 = b1.clone();
+    assert!(b1.multi().body_eq(&b2.multi()).unwrap());
+    b2.clear();
+    b2.append(&vec!(f64::NAN, -1.5)).unwrap();
+    b2.append(DBusStr::new("x").unwrap()).unwrap();
+    assert!(!b1.multi().body_eq(&b2.multi()).unwrap());
+}
+
+#[test]
+fn byte_array_slice() {
+    let data: Vec<u8> = (0..100).collect();
+    let mut b = MultiBuf::new();
+    b.append(&ByteArray(&data)).unwrap();
+    b.append(&vec!(1u16, 2u16)).unwrap();
+    let m = b.multi();
+    let raw = m.raw_data();
+    let mut it = m.iter();
+    let p = it.next().unwrap().unwrap().parse().unwrap();
+    let slice = p.as_byte_slice().unwrap();
+    assert_eq!(slice, &data[..]);
+    assert_eq!(slice.as_ptr(), raw[4..].as_ptr());
+    assert!(it.next().unwrap().unwrap().parse().unwrap().as_byte_slice().is_none());
+}
+
+#[test]
+fn marshal_tuples() {
+    let x = DBusStr::new("x").unwrap();
+    let mut a = ArrayBuf::new(SignatureSingle::new("(us)").unwrap()).unwrap();
+    a.append(&(1u32, x)).unwrap();
+    a.append(&(2u32, DBusStr::new("yz").unwrap())).unwrap();
+    assert!(a.append(&(3u16, x)).is_err());
+
+    let mut b = MultiBuf::new();
+    b.append(&a).unwrap();
+    b.append(&(5u8, x, -7i64, 2u16)).unwrap();
+    assert_eq!(&**b.multi().signature(), "a(us)(ysxq)");
+
+    let m = b.multi();
+    let mut it = m.iter();
+    let arr = match it.next().unwrap().unwrap().parse().unwrap() {
+        Parsed::Array(arr) => arr,
+        _ => unreachable!(),
+    };
+    let items: Vec<_> = arr.map(|s| match s.unwrap().parse().unwrap() {
+        Parsed::Struct(m) => {
+            let mut f = m.iter().map(|s| s.unwrap().parse().unwrap());
+            match (f.next(), f.next(), f.next()) {
+                (Some(Parsed::UInt32(n)), Some(Parsed::String(s)), None) => (n, &**s),
+                _ => unreachable!(),
+            }
+        },
+        _ => unreachable!(),
+    }).collect();
+    assert_eq!(items, vec!((1, "x"), (2, "yz")));
+
+    let s = it.next().unwrap().unwrap();
+    assert_eq!(&**s.sig, "(ysxq)");
+    match s.parse().unwrap() {
+        Parsed::Struct(m) => {
+            let mut f = m.iter().map(|s| s.unwrap().parse().unwrap());
+            assert!(matches!(f.next(), Some(Parsed::Byte(5))));
+            assert_eq!(f.next().unwrap().as_dbus_str().unwrap(), x);
+            assert!(matches!(f.next(), Some(Parsed::Int64(-7))));
+            assert!(matches!(f.next(), Some(Parsed::UInt16(2))));
+            assert!(f.next().is_none());
+        },
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn tuple_signature_from_values() {
+    // The struct signature follows the field values, not just the field types.
+    let u = ArrayBuf::new(SignatureSingle::new("u").unwrap()).unwrap();
+    let s = ArrayBuf::new(SignatureSingle::new("s").unwrap()).unwrap();
+    assert_eq!(&**(1u8, &u).signature(), "(yau)");
+    assert_eq!(&**(1u8, &s).signature(), "(yas)");
+    assert_eq!(&**(&u, (true, &s)).signature(), "(au(bas))");
+}
+
+#[test]
+fn tuple_signature_too_long() {
+    let elem = format!("({})", "y".repeat(200));
+    let a = ArrayBuf::new(SignatureSingle::new(&elem).unwrap()).unwrap();
+    let mut b = MultiBuf::new();
+    assert!(matches!(b.append(&(&a, &a)), Err(MarshalError::SignatureTooLong)));
+    assert!(matches!(VariantBuf::new(&(&a, &a)), Err(DemarshalError::NumberTooBig)));
+    assert!(b.data.is_empty());
+
+    // Structs nested too deeply
+    let elem = format!("{}y{}", "(".repeat(32), ")".repeat(32));
+    let a = ArrayBuf::new(SignatureSingle::new(&elem).unwrap()).unwrap();
+    assert!(matches!(b.append(&(1u8, &a)), Err(MarshalError::InvalidString)));
+    assert!(b.sig.is_empty());
+    assert!(b.data.is_empty());
+    b.append(&(1u8, 2u8)).unwrap();
+}
+
+#[test]
+fn array_buf_len() {
+    let mut a = ArrayBuf::new(SignatureSingle::new("s").unwrap()).unwrap();
+    assert!(a.is_empty());
+    for x in &["a", "bcdef", ""] { a.append(DBusStr::new(x).unwrap()).unwrap(); }
+    assert_eq!(a.len(), 3);
+    assert!(!a.is_empty());
+    a.clear();
+    assert!(a.is_empty());
+    assert_eq!(a.len(), 0);
+    a.append(DBusStr::new("x").unwrap()).unwrap();
+    assert_eq!(a.len(), 1);
+    assert_eq!(&**a.outer_sig, "as");
+
+    let mut a = ArrayBuf::new(SignatureSingle::new("q").unwrap()).unwrap();
+    for x in 0..5u16 { a.append(&x).unwrap(); }
+    assert_eq!(a.len(), 5);
+    let a = ArrayBuf::from_structs(vec!((1u8, 2u64), (3u8, 4u64))).unwrap();
+    assert_eq!(a.len(), 2);
+}
+
+#[test]
+fn read_into_buffer() {
+    let s = DBusStr::new("Hello world").unwrap();
+    let be = [0, 0, 0, 11, b'H', b'e', b'l', b'l', b'o', b' ', b'w', b'o', b'r', b'l', b'd', 0];
+    let single = Single::new(SignatureSingle::new("s").unwrap(), &be, 0, true);
+    let mut out = vec![1, 2, 3];
+    let sig = single.read_into(&mut out).unwrap();
+    assert_eq!(&**sig, "s");
+    assert_eq!(out.len(), 8 + be.len());
+    let copy = Single::new(&sig, &out[8..], 0, cfg!(target_endian = "big"));
+    assert_eq!(copy.parse().unwrap().as_dbus_str().unwrap(), s);
+    assert!(copy.value_eq(&single).unwrap());
+
+    let mut b = MultiBuf::new();
+    b.append(&(7u8, vec!(1u64, 2u64), s)).unwrap();
+    let mut d = DictBuf::new(SignatureSingle::new_owned("y").unwrap(), SignatureSingle::new_owned("v").unwrap()).unwrap();
+    d.append(&3u8, &VariantBuf::new(&(1u16, 2u32)).unwrap()).unwrap();
+    b.append(&d).unwrap();
+    let mut out = vec!();
+    for x in b.multi().iter() {
+        let x = x.unwrap();
+        out.clear();
+        let sig = x.read_into(&mut out).unwrap();
+        let copy = Single::new(&sig, &out, 0, cfg!(target_endian = "big"));
+        assert!(copy.value_eq(&x).unwrap());
+        assert_eq!(copy.get_real_length().unwrap(), out.len());
+    }
+}
+
+#[test]
+fn array_buf_read_back() {
+    let mut a = ArrayBuf::new(SignatureSingle::new("u").unwrap()).unwrap();
+    for x in &[5u32, 7, 0xdeadbeef] { a.append(x).unwrap(); }
+    let v: Vec<u32> = a.array().map(|x| match x.unwrap().parse().unwrap() {
+        Parsed::UInt32(x) => x,
+        _ => unreachable!(),
+    }).collect();
+    assert_eq!(v, vec!(5, 7, 0xdeadbeef));
+
+    let mut a = ArrayBuf::new(SignatureSingle::new("(ys)").unwrap()).unwrap();
+    a.append(&(1u8, DBusStr::new("a").unwrap())).unwrap();
+    a.append(&(2u8, DBusStr::new("bc").unwrap())).unwrap();
+    let mut it = a.array();
+    assert!(matches!(it.next().unwrap().unwrap().parse().unwrap(), Parsed::Struct(_)));
+    assert!(it.next().unwrap().is_ok());
+    assert!(it.next().is_none());
+}
+
+#[test]
+fn dict_buf_lookup() {
+    let mut d = DictBuf::new(SignatureSingle::new_owned("s").unwrap(), SignatureSingle::new_owned("u").unwrap()).unwrap();
+    d.append(DBusStr::new("a").unwrap(), &1u32).unwrap();
+    d.append(DBusStr::new("b").unwrap(), &2u32).unwrap();
+    assert!(matches!(d.get(DBusStr::new("b").unwrap()).unwrap().parse().unwrap(), Parsed::UInt32(2)));
+    assert!(matches!(d.get(DBusStr::new("a").unwrap()).unwrap().parse().unwrap(), Parsed::UInt32(1)));
+    assert!(d.get(DBusStr::new("c").unwrap()).is_none());
+    assert!(d.get(&1u32).is_none());
+
+    let entries: Vec<_> = d.iter().map(|e| {
+        let (k, v) = e.unwrap();
+        match (k.parse().unwrap(), v.parse().unwrap()) {
+            (Parsed::String(k), Parsed::UInt32(v)) => (&**k, v),
+            _ => unreachable!(),
+        }
+    }).collect();
+    assert_eq!(entries, vec!(("a", 1), ("b", 2)));
+
+    // Entries start at 8 byte boundaries
+    let mut d = DictBuf::new(SignatureSingle::new_owned("y").unwrap(), SignatureSingle::new_owned("t").unwrap()).unwrap();
+    for x in 0..5u8 { d.append(&x, &(x as u64 * 100)).unwrap(); }
+    assert!(matches!(d.get(&3u8).unwrap().parse().unwrap(), Parsed::UInt64(300)));
+    assert_eq!(d.iter().count(), 5);
+}
+
+#[test]
+fn dict_get() {
+    let mut d = DictBuf::new(SignatureSingle::new_owned("s").unwrap(), SignatureSingle::new_owned("v").unwrap()).unwrap();
+    d.append(DBusStr::new("Name").unwrap(), &VariantBuf::new(DBusStr::new("dbus-rs").unwrap()).unwrap()).unwrap();
+    d.append(DBusStr::new("Size").unwrap(), &VariantBuf::new(&5u64).unwrap()).unwrap();
+    let mut b = MultiBuf::new();
+    b.append(&d).unwrap();
+    let m = b.multi();
+    let dict = match m.iter().next().unwrap().unwrap().parse().unwrap() {
+        Parsed::Dict(d) => d,
+        _ => unreachable!(),
+    };
+    let v = dict.get(DBusStr::new("Size").unwrap()).unwrap().unwrap();
+    match v.parse().unwrap() {
+        Parsed::Variant(v) => assert!(matches!(v.parse().unwrap(), Parsed::UInt64(5))),
+        _ => unreachable!(),
+    }
+    assert!(dict.get(DBusStr::new("Color").unwrap()).unwrap().is_none());
+
+    let mut d = DictBuf::new(SignatureSingle::new_owned("u").unwrap(), SignatureSingle::new_owned("u").unwrap()).unwrap();
+    d.append(&1u32, &2u32).unwrap();
+    assert!(matches!(d.iter().get(DBusStr::new("1").unwrap()), Err(DemarshalError::WrongType { .. })));
+}
+
+#[test]
+fn append_multi() {
+    let mut other = MultiBuf::new();
+    other.append(&(DBusStr::new("Hello").unwrap(), -5i32)).unwrap();
+    other.append(&7u16).unwrap();
+    let mut b = MultiBuf::new();
+    b.append(&3u8).unwrap();
+    b.append_multi(&other).unwrap();
+    assert_eq!(&**b.sig, "y(si)q");
+
+    let mut expected = MultiBuf::new();
+    expected.append(&3u8).unwrap();
+    expected.append(&(DBusStr::new("Hello").unwrap(), -5i32)).unwrap();
+    expected.append(&7u16).unwrap();
+    assert_eq!(b.data, expected.data);
+    assert!(b.multi().body_eq(&expected.multi()).unwrap());
+
+    let mut it = b.multi().iter();
+    assert!(matches!(it.next().unwrap().unwrap().parse().unwrap(), Parsed::Byte(3)));
+    match it.next().unwrap().unwrap().parse().unwrap() {
+        Parsed::Struct(m) => {
+            let mut f = m.iter().map(|s| s.unwrap().parse().unwrap());
+            assert_eq!(&**f.next().unwrap().as_dbus_str().unwrap(), "Hello");
+            assert!(matches!(f.next(), Some(Parsed::Int32(-5))));
+        },
+        _ => unreachable!(),
+    }
+    assert!(matches!(it.next().unwrap().unwrap().parse().unwrap(), Parsed::UInt16(7)));
+    assert!(it.next().is_none());
+
+    // Aligned case
+    let mut b = MultiBuf::new();
+    b.append(&1u64).unwrap();
+    b.append_multi(&other).unwrap();
+    assert_eq!(&**b.sig, "t(si)q");
+    assert_eq!(b.data.len(), 8 + other.data.len());
+
+    // Truncated data is reported as such, and leaves the buffer unchanged
+    struct Truncated;
+    impl Marshal for Truncated {
+        fn signature(&self) -> Cow<'_, SignatureSingle> { SignatureSingle::new_unchecked("u").into() }
+        fn append_data_to(&self, v: &mut Vec<u8>) { v.extend_from_slice(&[1, 0]) }
+    }
+    let mut bad = MultiBuf::new();
+    bad.append(&Truncated).unwrap();
+    let mut b = MultiBuf::new();
+    b.append(&3u8).unwrap();
+    let e = b.append_multi(&bad).unwrap_err();
+    assert!(matches!(e, MarshalError::InvalidData(DemarshalError::NotEnoughData { .. })));
+    assert_eq!(&**b.sig, "y");
+    assert_eq!(b.data, [3]);
+}
+
+#[test]
+fn dict_to_hashmap() {
+    let mut d = DictBuf::new(SignatureSingle::new_owned("s").unwrap(), SignatureSingle::new_owned("v").unwrap()).unwrap();
+    let mut b = MultiBuf::new();
+    b.append(&d).unwrap();
+    d.append(DBusStr::new("Name").unwrap(), &VariantBuf::new(DBusStr::new("dbus-rs").unwrap()).unwrap()).unwrap();
+    d.append(DBusStr::new("Size").unwrap(), &VariantBuf::new(&5u64).unwrap()).unwrap();
+    d.append(DBusStr::new("Enabled").unwrap(), &VariantBuf::new(&true).unwrap()).unwrap();
+    b.append(&d).unwrap();
+    let m = b.multi();
+    let mut it = m.iter().map(|x| match x.unwrap().parse().unwrap() {
+        Parsed::Dict(d) => d.to_hashmap().unwrap(),
+        _ => unreachable!(),
+    });
+    assert!(it.next().unwrap().is_empty());
+    let map = it.next().unwrap();
+    assert_eq!(map.len(), 3);
+    let inner = |k: &str| match &map[k] {
+        Parsed::Variant(v) => v.parse().unwrap(),
+        _ => unreachable!(),
+    };
+    assert_eq!(&**inner("Name").as_dbus_str().unwrap(), "dbus-rs");
+    assert!(matches!(inner("Size"), Parsed::UInt64(5)));
+    assert!(matches!(inner("Enabled"), Parsed::Boolean(true)));
+
+    let mut d = DictBuf::new(SignatureSingle::new_owned("y").unwrap(), SignatureSingle::new_owned("u").unwrap()).unwrap();
+    d.append(&1u8, &2u32).unwrap();
+    assert!(matches!(d.iter().to_hashmap(), Err(DemarshalError::WrongType { .. })));
+
+    // Errors in entries are not skipped
+    let mut d = DictBuf::new(SignatureSingle::new_owned("s").unwrap(), SignatureSingle::new_owned("u").unwrap()).unwrap();
+    d.append(DBusStr::new("a").unwrap(), &7u32).unwrap();
+    let mut data = vec!();
+    d.append_data_to(&mut data);
+    let single = Single::new(SignatureSingle::new("a{sb}").unwrap(), &data, 0, cfg!(target_endian = "big"));
+    let dict = match single.parse().unwrap() {
+        Parsed::Dict(d) => d,
+        _ => unreachable!(),
+    };
+    assert!(matches!(dict.to_hashmap(), Err(DemarshalError::InvalidBoolean)));
+}
+
+#[test]
+fn array_dict_reset() {
+    let mut b = MultiBuf::new();
+    b.append(&1u8).unwrap();
+    b.append(&vec!(1u32, 2, 3)).unwrap();
+    let mut d = DictBuf::new(SignatureSingle::new_owned("s").unwrap(), SignatureSingle::new_owned("t").unwrap()).unwrap();
+    d.append(DBusStr::new("a").unwrap(), &1u64).unwrap();
+    d.append(DBusStr::new("bc").unwrap(), &2u64).unwrap();
+    b.append(&d).unwrap();
+    let m = b.multi();
+    let mut it = m.iter().skip(1);
+
+    let mut arr = match it.next().unwrap().unwrap().parse().unwrap() {
+        Parsed::Array(a) => a,
+        _ => unreachable!(),
+    };
+    let pass1: Vec<_> = arr.by_ref().map(|x| { let x = x.unwrap(); (x.position(), format!("{:?}", x.parse().unwrap())) }).collect();
+    assert!(arr.next().is_none());
+    arr.reset();
+    let pass2: Vec<_> = arr.by_ref().map(|x| { let x = x.unwrap(); (x.position(), format!("{:?}", x.parse().unwrap())) }).collect();
+    assert_eq!(pass1.len(), 3);
+    assert_eq!(pass1, pass2);
+
+    let mut dict = match it.next().unwrap().unwrap().parse().unwrap() {
+        Parsed::Dict(d) => d,
+        _ => unreachable!(),
+    };
+    let first = dict.next().unwrap().unwrap().0.position();
+    assert_eq!(dict.by_ref().count(), 1);
+    dict.reset();
+    assert_eq!(dict.next().unwrap().unwrap().0.position(), first);
+    assert_eq!(dict.count(), 1);
+}
+
+#[test]
+fn multi_to_vec() {
+    let mut b = MultiBuf::new();
+    b.append(DBusStr::new("Hello").unwrap()).unwrap();
+    b.append(&42u32).unwrap();
+    b.append(&true).unwrap();
+    let m = b.multi();
+    assert_eq!(&**m.signature(), "sub");
+    let v = m.to_vec().unwrap();
+    assert_eq!(v.len(), 3);
+    assert_eq!(&**v[0].as_dbus_str().unwrap(), "Hello");
+    assert!(matches!(v[1], Parsed::UInt32(42)));
+    assert!(matches!(v[2], Parsed::Boolean(true)));
+
+    let data = [5, 0, 0, 0, 2, 0, 0, 0];
+    let m = Multi::new(SignatureMulti::new("ub").unwrap(), &data, cfg!(target_endian = "big"));
+    assert!(matches!(m.to_vec(), Err(DemarshalError::InvalidBoolean)));
+}
+
+#[test]
+fn single_accessors() {
+    let mut b = MultiBuf::new();
+    b.append(&0x12345678u32).unwrap();
+    b.append(DBusStr::new("abc").unwrap()).unwrap();
+    let m = b.multi();
+    let mut it = m.iter();
+    let x = it.next().unwrap().unwrap();
+    assert_eq!(&**x.signature(), "u");
+    assert!(matches!(x.parse().unwrap(), Parsed::UInt32(0x12345678)));
+    assert_eq!(x.raw_data().unwrap(), &0x12345678u32.to_ne_bytes());
+    let x = it.next().unwrap().unwrap();
+    assert_eq!(x.signature().as_bytes()[0], b's');
+    assert_eq!(x.raw_data().unwrap().len(), 8);
+
+    let short = [10, 0, 0, 0, b'a'];
+    let x = Single::new(SignatureSingle::new("s").unwrap(), &short, 0, false);
+    assert!(x.raw_data().is_err());
+}
+
+#[test]
+fn variant_sig_overflow() {
+    let sig = SignatureSingle::new("v").unwrap();
+    // Signature length points past the end of the buffer
+    let data = [200, b'u', 0, 0];
+    assert!(matches!(Single::new(sig, &data, 0, false).parse(), Err(DemarshalError::NotEnoughData { .. })));
+    assert!(Single::new(sig, &data, 0, false).get_real_length().is_err());
+
+    // Alignment would overflow
+    let data = [1, b't', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    let s = Single::new(sig, &data, usize::MAX - 4, false);
+    assert!(matches!(s.parse(), Err(DemarshalError::NumberTooBig)));
+    assert!(s.get_real_length().is_err());
+}
+
+#[test]
+fn parsed_signature() {
+    let mut b = MultiBuf::new();
+    b.append(&-3i32).unwrap();
+    b.append(&vec!(1i32, 2i32)).unwrap();
+    b.append(&(DBusStr::new("a").unwrap(), 5i32)).unwrap();
+    b.append(&(1u8, (DBusStr::new("b").unwrap(), 6i32), vec!(1.5f64))).unwrap();
+    let mut d = DictBuf::new(SignatureSingle::new_owned("s").unwrap(), SignatureSingle::new_owned("v").unwrap()).unwrap();
+    d.append(DBusStr::new("a").unwrap(), &VariantBuf::new(&1u8).unwrap()).unwrap();
+    b.append(&d).unwrap();
+    let sigs: Vec<String> = b.multi().to_vec().unwrap().iter().map(|x| x.signature().to_string()).collect();
+    assert_eq!(sigs, vec!("i", "ai", "(si)", "(y(si)ad)", "a{sv}"));
+}
+
+#[test]
+fn parsed_numeric_conversions() {
+    let mut b = MultiBuf::new();
+    b.append(&7u16).unwrap();
+    b.append(&-3i32).unwrap();
+    b.append(&2.5f64).unwrap();
+    b.append(&true).unwrap();
+    let v = b.multi().to_vec().unwrap();
+
+    assert_eq!(u16::try_from(v[0].clone()).unwrap(), 7);
+    assert_eq!(i32::try_from(v[1].clone()).unwrap(), -3);
+    assert_eq!(f64::try_from(v[2].clone()).unwrap(), 2.5);
+    assert!(bool::try_from(v[3].clone()).unwrap());
+
+    assert_eq!(v[0].as_u64().unwrap(), 7);
+    assert_eq!(v[1].as_i64().unwrap(), -3);
+
+    assert!(matches!(u32::try_from(v[0].clone()), Err(DemarshalError::WrongType { .. })));
+    assert!(matches!(u64::try_from(v[1].clone()), Err(DemarshalError::WrongType { .. })));
+    assert!(matches!(v[1].as_u64(), Err(DemarshalError::WrongType { .. })));
+    assert!(matches!(v[3].as_i64(), Err(DemarshalError::WrongType { .. })));
+}
+
+#[test]
+fn parsed_as_str() {
+    let mut b = MultiBuf::new();
+    b.append(DBusStr::new("Hello").unwrap()).unwrap();
+    b.append(dbus_strings::ObjectPath::new("/hello").unwrap()).unwrap();
+    b.append(SignatureMulti::new("a{sv}").unwrap()).unwrap();
+    b.append(&5u32).unwrap();
+    let v = b.multi().to_vec().unwrap();
+    assert_eq!(v[0].as_str().unwrap(), "Hello");
+    assert_eq!(v[1].as_str().unwrap(), "/hello");
+    assert_eq!(v[2].as_str().unwrap(), "a{sv}");
+    assert!(matches!(v[3].as_str(), Err(DemarshalError::WrongType { .. })));
+}
+
+#[test]
+fn parsed_display() {
+    let mut b = MultiBuf::new();
+    b.append(DBusStr::new("hello").unwrap()).unwrap();
+    b.append(&42u32).unwrap();
+    b.append(&vec!(1i32, 2i32, 3i32)).unwrap();
+    b.append(&Vec::<u8>::new()).unwrap();
+    b.append(&(DBusStr::new("x").unwrap(), true)).unwrap();
+    let mut d = DictBuf::new(SignatureSingle::new_owned("s").unwrap(), SignatureSingle::new_owned("v").unwrap()).unwrap();
+    d.append(DBusStr::new("a").unwrap(), &VariantBuf::new(&1u8).unwrap()).unwrap();
+    d.append(DBusStr::new("b").unwrap(), &VariantBuf::new(DBusStr::new("c\"d").unwrap()).unwrap()).unwrap();
+    b.append(&d).unwrap();
+    let v: Vec<String> = b.multi().to_vec().unwrap().iter().map(|x| x.to_string()).collect();
+    assert_eq!(v, vec!(
+        r#"s "hello""#,
+        "u 42",
+        "ai [ 1 2 3 ]",
+        "ay [ ]",
+        r#"(sb) ( "x" true )"#,
+        r#"a{sv} { "a": y 1, "b": s "c\"d" }"#,
+    ));
+}
+
+#[test]
+fn multi_to_owned_le() {
+    let sig = SignatureMulti::new("qa{sv}(yx)").unwrap();
+    let mut data = vec!(1, 2, 0, 0);
+    data.extend_from_slice(&40u32.to_be_bytes());
+    data.extend_from_slice(&1u32.to_be_bytes());
+    data.extend_from_slice(b"a\0\x01u\0\0\0\0");
+    data.extend_from_slice(&7u32.to_be_bytes());
+    data.extend_from_slice(&1u32.to_be_bytes());
+    data.extend_from_slice(b"b\0\x01d\0\0\0\0\0\0\0\0");
+    data.extend_from_slice(&1.5f64.to_be_bytes());
+    data.extend_from_slice(&[5, 0, 0, 0, 0, 0, 0, 0]);
+    data.extend_from_slice(&(-2i64).to_be_bytes());
+    let be = Multi::new(sig, &data, true);
+    let v: Vec<String> = be.to_vec().unwrap().iter().map(|x| x.to_string()).collect();
+    assert_eq!(v, vec!("q 258", r#"a{sv} { "a": u 7, "b": d 1.5 }"#, "(yx) ( 5 -2 )"));
+
+    let le = be.to_owned_le().unwrap();
+    let le = le.multi();
+    assert!(!le.is_big_endian());
+    assert_eq!(le.signature(), sig);
+    assert_eq!(&le.raw_data()[..2], &[2, 1]);
+    assert_eq!(le.raw_data().len(), data.len());
+    assert!(le.body_eq(&be).unwrap());
+    let v2: Vec<String> = le.to_vec().unwrap().iter().map(|x| x.to_string()).collect();
+    assert_eq!(v, v2);
+}
+
+#[test]
+fn multibuf_endianness() {
+    let mut be = MultiBuf::with_endianness(true);
+    assert!(be.is_big_endian());
+    be.append(&0x01020304u32).unwrap();
+    assert_eq!(be.multi().raw_data(), &[1, 2, 3, 4]);
+
+    let mut le = MultiBuf::with_endianness(false);
+    le.append(&0x01020304u32).unwrap();
+    assert_eq!(le.multi().raw_data(), &[4, 3, 2, 1]);
+
+    // Containers, and mixing buffers of different byte order
+    let mut d = DictBuf::new(SignatureSingle::new_owned("s").unwrap(), SignatureSingle::new_owned("v").unwrap()).unwrap();
+    d.append(DBusStr::new("a").unwrap(), &VariantBuf::new(&7u16).unwrap()).unwrap();
+    be.append(DBusStr::new("hello").unwrap()).unwrap();
+    be.append(&d).unwrap();
+    be.append_array(SignatureSingle::new("x").unwrap(), &[-2i64, 3]).unwrap();
+    be.append_multi(&le).unwrap();
+    assert_eq!(&be.multi().raw_data()[4..8], &[0, 0, 0, 5]);
+    let v: Vec<String> = be.multi().to_vec().unwrap().iter().map(|x| x.to_string()).collect();
+    assert_eq!(v, vec!("u 16909060", r#"s "hello""#, r#"a{sv} { "a": q 7 }"#, "ax [ -2 3 ]", "u 16909060"));
+
+    let mut native = MultiBuf::new();
+    native.append_multi(&be).unwrap();
+    assert!(native.multi().body_eq(&be.multi()).unwrap());
+}
+
+#[test]
+fn buf_equality() {
+    let mut a1 = ArrayBuf::new(SignatureSingle::new("u").unwrap()).unwrap();
+    a1.append(&1u32).unwrap();
+    a1.append(&2u32).unwrap();
+    let mut a2 = ArrayBuf::new(SignatureSingle::new("u").unwrap()).unwrap();
+    a2.data.reserve(100);
+    for x in &[1u32, 2] { a2.append(x).unwrap(); }
+    assert_eq!(a1, a2);
+    a2.append(&3u32).unwrap();
+    assert_ne!(a1, a2);
+    let a3 = ArrayBuf::new(SignatureSingle::new("i").unwrap()).unwrap();
+    assert_ne!(ArrayBuf::new(SignatureSingle::new("u").unwrap()).unwrap(), a3);
+
+    let mut d1 = DictBuf::new(SignatureSingle::new_owned("s").unwrap(), SignatureSingle::new_owned("u").unwrap()).unwrap();
+    let mut d2 = d1.clone();
+    d1.append(DBusStr::new("a").unwrap(), &1u32).unwrap();
+    d2.append(DBusStr::new("a").unwrap(), &1u32).unwrap();
+    assert_eq!(d1, d2);
+    d2.append(DBusStr::new("b").unwrap(), &2u32).unwrap();
+    assert_ne!(d1, d2);
+
+    let s = |x: u8| {
+        let mut m = MultiBuf::new();
+        m.append(&x).unwrap();
+        m.append(DBusStr::new("x").unwrap()).unwrap();
+        StructBuf::new(m).unwrap()
+    };
+    assert_eq!(s(1), s(1));
+    assert_ne!(s(1), s(2));
+}
+
+#[test]
+fn array_fixed_len() {
+    let mut b = MultiBuf::new();
+    b.append(&(1..=8u64).collect::<Vec<_>>()).unwrap();
+    b.append(&vec!(DBusStr::new("a").unwrap())).unwrap();
+    let v = b.multi().to_vec().unwrap();
+    let mut a = if let Parsed::Array(a) = v[0] { a } else { unreachable!() };
+    assert_eq!(a.fixed_len(), Some(8));
+    assert_eq!(a.size_hint(), (8, Some(8)));
+    a.next().unwrap().unwrap();
+    assert_eq!(a.size_hint(), (7, Some(7)));
+    let x: Vec<_> = a.collect();
+    assert_eq!(x.len(), 7);
+    assert!(x.capacity() >= 7);
+
+    let a = if let Parsed::Array(a) = v[1] { a } else { unreachable!() };
+    assert_eq!(a.fixed_len(), None);
+    assert_eq!(a.size_hint(), (0, None));
+}
+
+#[test]
+fn signature_components() {
+    let mut b = MultiBuf::new();
+    b.append(SignatureMulti::new("a{sv}i").unwrap()).unwrap();
+    b.append(&5u8).unwrap();
+    let mut iter = b.multi().iter();
+    let s = iter.next().unwrap().unwrap();
+    let v: Vec<&str> = s.read_signature_components().unwrap().map(|x| &**x).collect();
+    assert_eq!(v, vec!("a{sv}", "i"));
+    let s = iter.next().unwrap().unwrap();
+    assert!(matches!(s.read_signature_components(), Err(DemarshalError::WrongType { .. })));
+}
+
+#[test]
+fn array_nth_fast() {
+    let mut b = MultiBuf::new();
+    b.append(&0u8).unwrap();
+    b.append(&(0..10i32).map(|x| x * 10).collect::<Vec<_>>()).unwrap();
+    b.append(&vec!(DBusStr::new("a").unwrap(), DBusStr::new("bc").unwrap())).unwrap();
+    let v = b.multi().to_vec().unwrap();
+    let a = if let Parsed::Array(a) = v[1] { a } else { unreachable!() };
+    let x = a.nth_fast(5).unwrap().unwrap();
+    assert!(matches!(x.parse().unwrap(), Parsed::Int32(50)));
+    assert_eq!(x.position(), { let mut a2 = a; a2.nth(5).unwrap().unwrap().position() });
+    assert!(a.nth_fast(10).unwrap().is_none());
+    assert!(a.nth_fast(usize::MAX).unwrap().is_none());
+
+    let a = if let Parsed::Array(a) = v[2] { a } else { unreachable!() };
+    assert_eq!(a.nth_fast(1).unwrap().unwrap().parse().unwrap().as_str().unwrap(), "bc");
+    assert!(a.nth_fast(2).unwrap().is_none());
+}
+
+#[test]
+fn read2_byte_order() {
+    let sig = SignatureSingle::new_unchecked("q");
+    for &(x, be, le) in &[(u16::MAX, [0xff, 0xff], [0xff, 0xff]), (0x0100, [1, 0], [0, 1]), (0x0001, [0, 1], [1, 0])] {
+        assert!(matches!(Single::new(sig, &be, 0, true).parse().unwrap(), Parsed::UInt16(y) if y == x));
+        assert!(matches!(Single::new(sig, &le, 0, false).parse().unwrap(), Parsed::UInt16(y) if y == x));
+        let n = SignatureSingle::new_unchecked("n");
+        assert!(matches!(Single::new(n, &be, 0, true).parse().unwrap(), Parsed::Int16(y) if y == x as i16));
+    }
+    // Only the value's own slice is read, even when not starting at offset zero
+    assert!(matches!(Single::new(sig, &[1, 0], 6, true).parse().unwrap(), Parsed::UInt16(0x0100)));
+    assert!(matches!(Single::new(sig, &[1], 6, true).parse(), Err(DemarshalError::NotEnoughData { at: 6, needed: 2, available: 1 })));
+}
+
+#[test]
+fn multi_indexed() {
+    let mut b = MultiBuf::new();
+    for i in 0..10u32 {
+        b.append(DBusStr::new(&"x".repeat(i as usize)).unwrap()).unwrap();
+        b.append(&i).unwrap();
+    }
+    let m = b.multi();
+    let mut idx = m.iter_indexed().unwrap();
+    assert_eq!(idx.total_len(), 20);
+    assert_eq!(idx.len(), 20);
+    let s = idx.get(13).unwrap();
+    assert_eq!(s.position(), m.iter().nth(13).unwrap().unwrap().position());
+    assert!(matches!(s.parse().unwrap(), Parsed::UInt32(6)));
+    assert_eq!(idx.get(12).unwrap().parse().unwrap().as_str().unwrap(), "xxxxxx");
+    assert!(idx.get(20).is_none());
+
+    idx.next().unwrap();
+    assert_eq!(idx.len(), 19);
+    assert_eq!(idx.count(), 19);
+
+    let sig = SignatureMulti::new("su").unwrap();
+    assert!(Multi::new(sig, &[5, 0, 0, 0, b'a'], false).iter_indexed().is_err());
+}
+
+#[test]
+fn array_copy_to_vec() {
+    let mut b = MultiBuf::new();
+    b.append(&vec!(1u32, 0x01020304, u32::MAX)).unwrap();
+    b.append(&vec!(1.5f64, -0.25)).unwrap();
+    let v = b.multi().to_vec().unwrap();
+    let au = if let Parsed::Array(a) = v[0] { a } else { unreachable!() };
+    assert_eq!(au.copy_to_vec::<u32>().unwrap(), vec!(1, 0x01020304, u32::MAX));
+    let ad = if let Parsed::Array(a) = v[1] { a } else { unreachable!() };
+    assert_eq!(ad.copy_to_vec::<f64>().unwrap(), vec!(1.5, -0.25));
+
+    match au.copy_to_vec::<i32>() {
+        Err(DemarshalError::WrongType { expected: Some(e), actual: Some(a) }) => {
+            assert_eq!((&**e, &**a), ("ai", "au"));
+        }
+        x => panic!("Unexpected result {:?}", x),
+    }
+
+    // Big endian, and only the remaining elements
+    let data = [0, 0, 0, 8, 0, 0, 0, 1, 0, 0, 0, 2];
+    let m = Multi::new(SignatureMulti::new("au").unwrap(), &data, true);
+    let mut a = if let Parsed::Array(a) = m.iter().next().unwrap().unwrap().parse().unwrap() { a } else { unreachable!() };
+    assert_eq!(a.copy_to_vec::<u32>().unwrap(), vec!(1, 2));
+    a.next();
+    assert_eq!(a.copy_to_vec::<u32>().unwrap(), vec!(2));
+}
+
+#[test]
+fn marshal_error_wrong_type() {
+    let mut a = ArrayBuf::new(SignatureSingle::new("u").unwrap()).unwrap();
+    let e = a.append(&5i32).unwrap_err();
+    match &e {
+        MarshalError::WrongType { expected, actual } => {
+            assert_eq!(&***expected, "u");
+            assert_eq!(&***actual, "i");
+        },
+        _ => panic!("unexpected error {:?}", e),
+    }
+    assert_eq!(e.to_string(), "wrong type: expected `u`, got `i`");
+    assert!(a.is_empty());
+
+    let mut d = DictBuf::new(SignatureSingle::new_owned("s").unwrap(), SignatureSingle::new_owned("y").unwrap()).unwrap();
+    assert!(matches!(d.append(&5u8, &5u8), Err(MarshalError::WrongType { .. })));
+
+    // Still usable where a DemarshalError is expected
+    let e: DemarshalError = e.into();
+    assert!(matches!(e, DemarshalError::WrongType { expected: Some(_), actual: Some(_) }));
+}
+
+#[test]
+fn array_max_len() {
+    let mut data = ((ARRAY_MAX_LEN + 1) as u32).to_ne_bytes().to_vec();
+    data.extend_from_slice(&[0; 4]);
+    for sig in &["ay", "a{sv}"] {
+        let s = Single::new(SignatureSingle::new(sig).unwrap(), &data, 0, IS_BIG_ENDIAN);
+        assert!(matches!(s.parse(), Err(DemarshalError::NumberTooBig)));
+        assert!(matches!(s.get_real_length(), Err(DemarshalError::NumberTooBig)));
+    }
+    // Exactly at the limit is allowed, so it just runs out of data
+    let mut data = (ARRAY_MAX_LEN as u32).to_ne_bytes().to_vec();
+    data.extend_from_slice(&[0; 4]);
+    let s = Single::new(SignatureSingle::new("a{sv}").unwrap(), &data, 0, IS_BIG_ENDIAN);
+    assert!(matches!(s.parse(), Err(DemarshalError::NotEnoughData { .. })));
+}
+
+#[test]
+fn multibuf_raw_parts() {
+    let mut d = DictBuf::new(SignatureSingle::new_owned("s").unwrap(), SignatureSingle::new_owned("v").unwrap()).unwrap();
+    d.append(DBusStr::new("a").unwrap(), &VariantBuf::new(&7u16).unwrap()).unwrap();
+    let mut b = MultiBuf::new();
+    b.append(&5u8).unwrap();
+    b.append(&d).unwrap();
+    b.append_array(SignatureSingle::new("s").unwrap(), vec!(DBusStr::new("x").unwrap())).unwrap();
+    let (sig, data) = b.clone().into_inner();
+    let b2 = MultiBuf::from_raw_parts(sig.clone(), data.clone()).unwrap();
+    assert_eq!(b, b2);
+
+    // A nul byte that is not there
+    let mut bad = data.clone();
+    *bad.last_mut().unwrap() = b'!';
+    assert!(MultiBuf::from_raw_parts(sig.clone(), bad).is_err());
+    // Invalid UTF-8 in the dict key
+    let mut bad = data.clone();
+    let pos = data.iter().position(|&x| x == b'a').unwrap();
+    bad[pos] = 0xff;
+    assert!(matches!(MultiBuf::from_raw_parts(sig.clone(), bad), Err(DemarshalError::InvalidString)));
+    // Truncated, and trailing data
+    assert!(MultiBuf::from_raw_parts(sig.clone(), data[..data.len() - 1].to_vec()).is_err());
+    let mut bad = data;
+    bad.push(0);
+    assert!(matches!(MultiBuf::from_raw_parts(sig, bad), Err(DemarshalError::InvalidProtocol)));
+}
+
+#[test]
+fn array_element_past_end() {
+    // An "as" array of 8 bytes, whose only string claims to be 20 bytes long,
+    // followed by enough data for the string to (wrongly) fit.
+    let mut data = 8u32.to_ne_bytes().to_vec();
+    data.extend_from_slice(&20u32.to_ne_bytes());
+    data.extend_from_slice(b"ab\0\0");
+    data.extend_from_slice(&[b'x'; 24]);
+    let sig = SignatureMulti::new("asay").unwrap();
+    let m = Multi::new(sig, &data, IS_BIG_ENDIAN);
+    let arr = match m.iter().next().unwrap().unwrap().parse().unwrap() {
+        Parsed::Array(arr) => arr,
+        _ => unreachable!(),
+    };
+    assert_eq!(arr.data.len(), 8);
+    let mut arr2 = arr;
+    assert!(matches!(arr2.next(), Some(Err(DemarshalError::NotEnoughData { at: 4, needed: 25, available: 8 }))));
+    assert!(arr.nth_fast(0).is_err());
+}
+
+#[test]
+fn marshal_owned_strings() {
+    use dbus_strings::{ObjectPath, ObjectPathBuf, DBusString};
+    let path: ObjectPathBuf = ObjectPath::new_owned("/org/example").unwrap();
+    let s: DBusString = DBusStr::new_owned("hello").unwrap();
+    let sig1: SignatureSingleBuf = SignatureSingle::new_owned("a{sv}").unwrap();
+    let sig2: SignatureMultiBuf = SignatureMulti::new_owned("su").unwrap();
+    let mut b = MultiBuf::new();
+    b.append(&path).unwrap();
+    b.append(&s).unwrap();
+    b.append(&sig1).unwrap();
+    b.append(&sig2).unwrap();
+    assert_eq!(&**b.multi().signature(), "osgg");
+    let v = b.multi().to_vec().unwrap();
+    assert!(matches!(v[0], Parsed::ObjectPath(x) if &**x == "/org/example"));
+    assert!(matches!(v[1], Parsed::String(x) if &**x == "hello"));
+    assert!(matches!(v[2], Parsed::Signature(x) if &**x == "a{sv}"));
+    assert!(matches!(v[3], Parsed::Signature(x) if &**x == "su"));
+
+    let mut b2 = MultiBuf::new();
+    b2.append(&*path).unwrap();
+    b2.append(&*s).unwrap();
+    b2.append(&*sig1).unwrap();
+    b2.append(&*sig2).unwrap();
+    assert_eq!(b, b2);
+}
+
+#[test]
+fn demarshal_roundtrip() {
+    fn roundtrip<T: Marshal + for<'a> Demarshal<'a>>(x: &T) -> T {
+        let mut b = vec!();
+        x.append_data_to(&mut b);
+        T::demarshal(&Single::new(&x.signature(), &b, 0, IS_BIG_ENDIAN)).unwrap()
+    }
+    assert_eq!(roundtrip(&7u8), 7);
+    assert_eq!(roundtrip(&7u16), 7);
+    assert_eq!(roundtrip(&7u32), 7);
+    assert_eq!(roundtrip(&7u64), 7);
+    assert_eq!(roundtrip(&-7i16), -7);
+    assert_eq!(roundtrip(&-7i32), -7);
+    assert_eq!(roundtrip(&-7i64), -7);
+    assert_eq!(roundtrip(&2.5f64), 2.5);
+    assert!(roundtrip(&true));
+    assert_eq!(roundtrip(&vec!(1u32, 2, 3)), vec!(1, 2, 3));
+    assert_eq!(roundtrip(&(5u32, -1i64)), (5, -1));
+    assert_eq!(roundtrip(&(5u32, true, 3u8)), (5, true, 3));
+    assert_eq!(roundtrip(&(5u32, true, 3u8, vec!(9u16))), (5, true, 3, vec!(9)));
+
+    let mut b = MultiBuf::new();
+    b.append(&(8u32, DBusStr::new("eight").unwrap())).unwrap();
+    b.append_array(SignatureSingle::new("s").unwrap(), vec!(DBusStr::new("a").unwrap(), DBusStr::new("b").unwrap())).unwrap();
+    b.append(DBusStr::new("borrowed").unwrap()).unwrap();
+    let m = b.multi();
+    let mut iter = m.iter();
+    let x: (u32, String) = Demarshal::demarshal(&iter.next().unwrap().unwrap()).unwrap();
+    assert_eq!(x, (8, "eight".into()));
+    let x: Vec<String> = Demarshal::demarshal(&iter.next().unwrap().unwrap()).unwrap();
+    assert_eq!(x, vec!("a", "b"));
+    let x: &str = Demarshal::demarshal(&iter.next().unwrap().unwrap()).unwrap();
+    assert_eq!(x, "borrowed");
+
+    // Wrong types, and structs with the wrong number of fields
+    let s = b.multi().iter().next().unwrap().unwrap();
+    assert!(matches!(u32::demarshal(&s), Err(DemarshalError::WrongType { .. })));
+    assert!(matches!(<(u32, String, u8)>::demarshal(&s), Err(DemarshalError::WrongType { .. })));
+    assert!(matches!(<Vec<u32>>::demarshal(&s), Err(DemarshalError::WrongType { .. })));
+}
+
+#[test]
+fn multi_read_array() {
+    let mut m = MultiBuf::new();
+    for x in &[1.5f64, -2.0, 0.25, 8.0] { m.append(x).unwrap(); }
+    let mut b = MultiBuf::new();
+    b.append(&StructBuf::new(m).unwrap()).unwrap();
+    let st = match b.multi().iter().next().unwrap().unwrap().parse().unwrap() {
+        Parsed::Struct(st) => st,
+        _ => unreachable!(),
+    };
+    assert_eq!(&**st.signature(), "dddd");
+    let x: [f64; 4] = st.read_array().unwrap();
+    assert_eq!(x, [1.5, -2.0, 0.25, 8.0]);
+
+    assert!(matches!(st.read_array::<f64, 3>(), Err(DemarshalError::WrongType { .. })));
+    assert!(matches!(st.read_array::<f64, 5>(), Err(DemarshalError::WrongType { .. })));
+    assert!(matches!(st.read_array::<i32, 4>(), Err(DemarshalError::WrongType { .. })));
+}
@@ -1,11 +1,15 @@
 use std::borrow::Cow;
 use dbus_strings as strings;
 use crate::types;
-use crate::types::{Marshal, DemarshalError};
+use crate::types::{Marshal, DemarshalError, MarshalError};
 use std::convert::TryInto;
 use std::num::NonZeroU32;
 use std::io;
-use crate::marshalled::{Multi, MultiBuf, DictBuf, VariantBuf, Parsed, Single};
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixStream;
+use dbus_native_channel::fdpassing;
+use std::collections::BTreeMap;
+use crate::marshalled::{Multi, MultiBuf, DictBuf, VariantBuf, Parsed, Single, ParseStrictness, Demarshal, MultiIter};
 
 const FIXED_HEADER_SIZE: usize = 16;
 
@@ -32,9 +36,10 @@ pub struct Message<'a> {
     destination: Option<Cow<'a, strings::BusName>>,
     sender: Option<Cow<'a, strings::BusName>>,
     signature: Option<Cow<'a, strings::SignatureMulti>>,
-//    unix_fds: Option<u32>,
+    unix_fds: Option<u32>,
     body: Cow<'a, [u8]>,
     is_big_endian: bool,
+    strictness: ParseStrictness,
 }
 
 impl<'a> Message<'a> {
@@ -51,12 +56,13 @@ impl<'a> Message<'a> {
             destination: None,
             sender: None,
             signature: None,
-//            unix_fds: None,
+            unix_fds: None,
             body: Cow::Borrowed(&[]),
             #[cfg(target_endian = "little")]
             is_big_endian: false,
             #[cfg(target_endian = "big")]
             is_big_endian: true,
+            strictness: Default::default(),
         }
     }
 
@@ -117,6 +123,20 @@ impl<'a> Message<'a> {
         Ok(())
     }
 
+    pub fn set_sender(&mut self, value: Option<Cow<'a, strings::BusName>>) { self.sender = value; }
+
+    /// Sets the number of file descriptors sent along with this message
+    /// (the UNIX_FDS header field).
+    pub fn set_unix_fds(&mut self, value: Option<u32>) { self.unix_fds = value; }
+
+    /// The number of file descriptors sent along with this message, or None if the message
+    /// has no UNIX_FDS header field.
+    pub fn unix_fds(&self) -> Option<u32> { self.unix_fds }
+
+    /// The unique name of the connection that sent this message, or None if the message
+    /// has no sender header field, as is usual on peer-to-peer connections.
+    pub fn sender(&self) -> Option<&strings::BusName> { self.sender.as_deref() }
+
     pub fn set_error_name(&mut self, value: Option<Cow<'a, strings::ErrorName>>) -> Result<(), ()> {
         if value.is_none() && self.msg_type == ERROR { Err(())? }
         self.error_name = value;
@@ -131,6 +151,18 @@ impl<'a> Message<'a> {
 
     pub fn reply_serial(&self) -> Option<NonZeroU32> { self.reply_serial }
 
+    /// Checks that the header fields required for this message type are present.
+    pub fn validate_required_fields(&self) -> Result<(), DemarshalError> {
+        let ok = match self.msg_type {
+            METHOD_CALL => self.path.is_some() && self.member.is_some(),
+            METHOD_RETURN => self.reply_serial.is_some(),
+            ERROR => self.error_name.is_some() && self.reply_serial.is_some(),
+            SIGNAL => self.path.is_some() && self.interface.is_some() && self.member.is_some(),
+            _ => true,
+        };
+        if ok { Ok(()) } else { Err(DemarshalError::InvalidProtocol) }
+    }
+
     pub fn set_serial(&mut self, value: Option<std::num::NonZeroU32>) { self.serial = value; }
 
     pub fn serial(&self) -> Option<std::num::NonZeroU32> { self.serial }
@@ -166,6 +198,7 @@ impl<'a> Message<'a> {
             add_header_field(b, 6, self.destination.as_ref(), |x| x.as_dbus_str())?;
             add_header_field(b, 7, self.sender.as_ref(), |x| x.as_dbus_str())?;
             add_header_field(b, 8, self.signature.as_ref(), |x| &**x)?;
+            add_header_field(b, 9, self.unix_fds, |x| x)?;
             Ok(())
         })?;
         b.write_single(b.align_buf(8))?;
@@ -174,11 +207,10 @@ impl<'a> Message<'a> {
     }
 
     pub fn marshal(&self, serial: std::num::NonZeroU32, header_only: bool) -> Result<Vec<u8>, types::DemarshalError> {
-        fn add_header_field<'a, Z, F>(arr: &mut DictBuf, header_type: u8, field: Option<Z>, f: F)
+        fn add_header_field<'a, Z, F>(arr: &mut HeaderFields, header_type: u8, field: Option<Z>, f: F)
         where F: FnOnce(Z) -> VariantBuf {
             if let Some(field) = field {
-                let field = f(field);
-                arr.append(&header_type, &field).unwrap();
+                arr.insert(header_type, f(field));
             }
         }
 
@@ -188,8 +220,7 @@ impl<'a> Message<'a> {
         buf.extend_from_slice(&[ENDIAN, self.msg_type, self.flags, 1]);
         buf.extend_from_slice(&(body_len as u32).to_ne_bytes());
         buf.extend_from_slice(&(serial.get()).to_ne_bytes());
-        use crate::strings::{StringLike, SignatureSingle};
-        let mut arr = DictBuf::new(SignatureSingle::new_unchecked_owned("y".into()), SignatureSingle::new_unchecked_owned("v".into())).unwrap();
+        let mut arr = HeaderFields::new();
         add_header_field(&mut arr, 1, self.path.as_ref(), |x| VariantBuf::new(&**x).unwrap());
         add_header_field(&mut arr, 2, self.interface.as_ref(), |x| VariantBuf::new(x.as_dbus_str()).unwrap());
         add_header_field(&mut arr, 3, self.member.as_ref(), |x| VariantBuf::new(x.as_dbus_str()).unwrap());
@@ -198,7 +229,8 @@ impl<'a> Message<'a> {
         add_header_field(&mut arr, 6, self.destination.as_ref(), |x| VariantBuf::new(x.as_dbus_str()).unwrap());
         add_header_field(&mut arr, 7, self.sender.as_ref(), |x| VariantBuf::new(x.as_dbus_str()).unwrap());
         add_header_field(&mut arr, 8, self.signature.as_ref(), |x| VariantBuf::new(&**x).unwrap());
-        crate::marshalled::Marshal::append_data_to(&arr, &mut buf);
+        add_header_field(&mut arr, 9, self.unix_fds, |x| VariantBuf::new(&x).unwrap());
+        crate::marshalled::Marshal::append_data_to(&arr.to_dictbuf(), &mut buf);
         crate::marshalled::align_buf(&mut buf, 8);
         if !header_only {
             buf.extend_from_slice(&self.body);
@@ -212,8 +244,15 @@ impl<'a> Message<'a> {
 
     // Should disconnect on error. If Ok(None) is returned, its a message that should be ignored.
     pub fn demarshal(buf: &'a [u8]) -> Result<Option<Self>, types::DemarshalError> {
+        Self::demarshal_with_strictness(buf, Default::default())
+    }
+
+    /// Like demarshal, but lets you choose which checks to perform.
+    ///
+    /// The strictness is also used when reading the body.
+    pub fn demarshal_with_strictness(buf: &'a [u8], strictness: ParseStrictness) -> Result<Option<Self>, types::DemarshalError> {
         let start = message_start_parse(buf)?;
-        if buf.len() < start.total_size { Err(DemarshalError::NotEnoughData)? }
+        if buf.len() < start.total_size { Err(DemarshalError::not_enough_data(0, start.total_size, buf.len()))? }
         let msg_type = buf[1];
         if msg_type < 1 || msg_type > 4 { return Ok(None) };
         let mut m = Self::new_internal(msg_type);
@@ -221,55 +260,139 @@ impl<'a> Message<'a> {
         m.flags = buf[2] & 0x7;
         m.serial = Some(start.serial);
         m.body = Cow::Borrowed(&buf[start.body_start..start.total_size]);
+        m.strictness = strictness;
+        // A message without the UNIX_FDS header field has no file descriptors.
+        m.strictness.max_unix_fds = Some(0);
+        if matches!(strictness.max_header_size, Some(max) if start.header_fields_len > max as usize) {
+            Err(DemarshalError::NumberTooBig)?
+        }
 
         use strings::StringLike;
         let dictsig = strings::SignatureSingle::new_unchecked("a{yv}");
-        let single = Single::new(dictsig, &buf[12..start.body_start], 12, m.is_big_endian);
+        let single = Single::new(dictsig, &buf[12..start.body_start], 12, m.is_big_endian).with_strictness(strictness);
         let parsed = single.parse()?;
         let dict = if let Parsed::Dict(dict) = parsed { dict } else { Err(DemarshalError::InvalidProtocol)? };
+        let mut seen_fields = 0u32;
+        let mut field_count = 0u32;
         for entry in dict {
+            field_count += 1;
+            if matches!(strictness.max_header_fields, Some(max) if field_count > max) {
+                Err(DemarshalError::NumberTooBig)?
+            }
             let (key, value) = entry?;
             let (key, value) = (key.parse()?, value.parse()?);
             let key = if let Parsed::Byte(key) = key { key } else { Err(DemarshalError::InvalidProtocol)? };
+            // Field code 0 is invalid according to the D-Bus specification.
+            if strictness.check_header_fields && key == 0 { Err(DemarshalError::InvalidProtocol)? }
+            if strictness.check_header_fields && key < 32 {
+                if seen_fields & (1 << key) != 0 { Err(DemarshalError::InvalidProtocol)? }
+                seen_fields |= 1 << key;
+            }
             let value = if let Parsed::Variant(value) = value { value } else { Err(DemarshalError::InvalidProtocol)? };
             let value = value.parse()?;
             match key {
                 1 => if let Parsed::ObjectPath(x) = value {
                     m.path = Some(Cow::Borrowed(x))
-                } else { Err(DemarshalError::WrongType)? },
+                } else { Err(DemarshalError::wrong_type(Some("o"), Some(&value.signature())))? },
                 2 => if let Parsed::String(x) = value {
                     m.interface = Some(Cow::Borrowed(x.try_into()?))
-                } else { Err(DemarshalError::WrongType)? },
+                } else { Err(DemarshalError::wrong_type(Some("s"), Some(&value.signature())))? },
                 3 => if let Parsed::String(x) = value {
                     m.member = Some(Cow::Borrowed(x.try_into()?))
-                } else { Err(DemarshalError::WrongType)? },
+                } else { Err(DemarshalError::wrong_type(Some("s"), Some(&value.signature())))? },
                 4 => if let Parsed::String(x) = value {
                     m.error_name = Some(Cow::Borrowed(x.try_into()?))
-                } else { Err(DemarshalError::WrongType)? },
+                } else { Err(DemarshalError::wrong_type(Some("s"), Some(&value.signature())))? },
                 5 => if let Parsed::UInt32(x) = value {
                     m.reply_serial = NonZeroU32::new(x)
-                } else { Err(DemarshalError::WrongType)? }
+                } else { Err(DemarshalError::wrong_type(Some("u"), Some(&value.signature())))? }
                 6 => if let Parsed::String(x) = value {
                     m.destination = Some(Cow::Borrowed(x.try_into()?))
-                } else { Err(DemarshalError::WrongType)? },
+                } else { Err(DemarshalError::wrong_type(Some("s"), Some(&value.signature())))? },
                 7 => if let Parsed::String(x) = value {
                     m.sender = Some(Cow::Borrowed(x.try_into()?))
-                } else { Err(DemarshalError::WrongType)? },
+                } else { Err(DemarshalError::wrong_type(Some("s"), Some(&value.signature())))? },
                 8 => if let Parsed::Signature(x) = value {
                     m.signature = Some(Cow::Borrowed(x))
-                } else { Err(DemarshalError::WrongType)? }
+                } else { Err(DemarshalError::wrong_type(Some("g"), Some(&value.signature())))? }
+                9 => if let Parsed::UInt32(x) = value {
+                    m.unix_fds = Some(x);
+                    m.strictness.max_unix_fds = Some(x);
+                } else { Err(DemarshalError::wrong_type(Some("u"), Some(&value.signature())))? }
                 _ => {},
             }
         }
+        if strictness.check_signal_interface && msg_type == SIGNAL && m.interface.is_none() {
+            Err(DemarshalError::InvalidProtocol)?
+        }
+        if strictness.check_sender && m.sender.is_none() {
+            Err(DemarshalError::InvalidProtocol)?
+        }
+        if strictness.check_trailing_data && m.read_body().get_real_length()? != m.body.len() {
+            Err(DemarshalError::InvalidProtocol)?
+        }
         Ok(Some(m))
     }
 
+    /// Returns the body signature, the raw body data and whether the data is big endian.
+    ///
+    /// This is useful for forwarding the body verbatim without parsing it,
+    /// given that the destination has the same byte order.
+    pub fn body_bytes(&self) -> (&strings::SignatureMulti, &[u8], bool) {
+        let body = self.read_body();
+        (body.signature(), body.raw_data(), body.is_big_endian())
+    }
+
+    /// The arguments of the message, in the byte order of the message and with the signature
+    /// from its signature header field.
+    ///
+    /// A message without a body gives an empty Multi.
     pub fn read_body<'b>(&'b self) -> Multi<'b> {
         let sig = self.signature.as_ref().map(|x| &**x).unwrap_or(Default::default());
-        Multi::new(sig, &self.body, self.is_big_endian())
+        Multi::new(sig, &self.body, self.is_big_endian()).with_strictness(self.strictness)
     }
 
-    pub fn set_body(&mut self, body: MultiBuf) {
+    /// Iterates over the arguments of the body, which must be exactly `count`, so that
+    /// the first `count` calls to `next` return Some.
+    fn body_args(&self, count: usize) -> Result<MultiIter<'_>, DemarshalError> {
+        let body = self.read_body();
+        if body.signature().iter_singles().count() != count {
+            Err(DemarshalError::wrong_type(None, Some(body.signature())))?
+        }
+        Ok(body.iter())
+    }
+
+    /// Reads a body consisting of exactly one argument of type A.
+    pub fn read1<'b, A: Demarshal<'b>>(&'b self) -> Result<A, DemarshalError> {
+        let mut args = self.body_args(1)?;
+        A::demarshal(&args.next().unwrap()?)
+    }
+
+    /// Reads a body consisting of exactly two arguments, of type A and B.
+    pub fn read2<'b, A: Demarshal<'b>, B: Demarshal<'b>>(&'b self) -> Result<(A, B), DemarshalError> {
+        let mut args = self.body_args(2)?;
+        Ok((A::demarshal(&args.next().unwrap()?)?, B::demarshal(&args.next().unwrap()?)?))
+    }
+
+    /// Reads a body consisting of exactly three arguments, of type A, B and C.
+    pub fn read3<'b, A, B, C>(&'b self) -> Result<(A, B, C), DemarshalError>
+    where A: Demarshal<'b>, B: Demarshal<'b>, C: Demarshal<'b> {
+        let mut args = self.body_args(3)?;
+        Ok((A::demarshal(&args.next().unwrap()?)?, B::demarshal(&args.next().unwrap()?)?, C::demarshal(&args.next().unwrap()?)?))
+    }
+
+    /// Sets the body, and the signature header field, from a MultiBuf.
+    ///
+    /// The signature is taken over from the MultiBuf as is, rather than being derived from the data.
+    ///
+    /// If the MultiBuf has another byte order than the message, the values are converted,
+    /// which fails if they are nested too deeply. The message is then left unchanged.
+    pub fn set_body(&mut self, mut body: MultiBuf) -> Result<(), MarshalError> {
+        if body.is_big_endian() != self.is_big_endian {
+            let mut converted = MultiBuf::with_endianness(self.is_big_endian);
+            converted.append_multi(&body)?;
+            body = converted;
+        }
         let (sig, data) = body.into_inner();
         if sig.len() == 0 {
             self.signature = None;
@@ -278,6 +401,22 @@ impl<'a> Message<'a> {
             self.signature = Some(Cow::Owned(sig));
             self.body = data.into();
         }
+        Ok(())
+    }
+
+    /// Sets the body of the message to already marshalled data.
+    ///
+    /// The data must have the same byte order as the message.
+    pub fn set_body_multi(&mut self, body: Multi<'a>) -> Result<(), DemarshalError> {
+        if body.is_big_endian() != self.is_big_endian { Err(DemarshalError::InvalidProtocol)? }
+        if body.signature().is_empty() {
+            self.signature = None;
+            self.body = Default::default();
+        } else {
+            self.signature = Some(Cow::Borrowed(body.signature()));
+            self.body = Cow::Borrowed(body.raw_data());
+        }
+        Ok(())
     }
 /*
     pub fn demarshal_body<'b>(&'b self) -> types::DemarshalState<'b> {
@@ -286,15 +425,40 @@ impl<'a> Message<'a> {
     } */
 }
 
+/// Collects message header fields, and marshals them in ascending field code order,
+/// regardless of the order they were inserted in.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderFields(BTreeMap<u8, VariantBuf>);
+
+impl HeaderFields {
+    pub fn new() -> Self { Default::default() }
+
+    /// Sets a header field, replacing (and returning) the old value if there was one.
+    pub fn insert(&mut self, code: u8, value: VariantBuf) -> Option<VariantBuf> {
+        self.0.insert(code, value)
+    }
+
+    /// Creates the "a{yv}" array of header fields.
+    pub fn to_dictbuf(&self) -> DictBuf {
+        use crate::strings::{StringLike, SignatureSingle};
+        let mut arr = DictBuf::new(SignatureSingle::new_unchecked_owned("y".into()), SignatureSingle::new_unchecked_owned("v".into())).unwrap();
+        for (code, value) in &self.0 {
+            arr.append(code, value).unwrap();
+        }
+        arr
+    }
+}
+
 struct MsgStart {
     body_start: usize,
+    header_fields_len: usize,
     is_big_endian: bool,
     serial: NonZeroU32,
     total_size: usize,
 }
 
 fn message_start_parse(buf: &[u8]) -> Result<MsgStart, DemarshalError> {
-    if buf.len() < FIXED_HEADER_SIZE { Err(DemarshalError::NotEnoughData)? };
+    if buf.len() < FIXED_HEADER_SIZE { Err(DemarshalError::not_enough_data(0, FIXED_HEADER_SIZE, buf.len()))? };
     if buf[3] != 1 { Err(DemarshalError::InvalidProtocol)? };
     let body_len = buf[4..8].try_into().unwrap();
     let serial = buf[8..12].try_into().unwrap();
@@ -310,8 +474,8 @@ fn message_start_parse(buf: &[u8]) -> Result<MsgStart, DemarshalError> {
     if body_len >= 134217728 || arr_len >= 67108864 || total_size >= 134217728 {
         Err(DemarshalError::NumberTooBig)?
     }
-    let serial = NonZeroU32::new(serial).ok_or(DemarshalError::NotEnoughData)?;
-    Ok(MsgStart { total_size, serial, body_start, is_big_endian })
+    let serial = NonZeroU32::new(serial).ok_or(DemarshalError::InvalidProtocol)?;
+    Ok(MsgStart { total_size, serial, body_start, header_fields_len: arr_len as usize, is_big_endian })
 }
 
 pub fn total_message_size(buf: &[u8]) -> Result<usize, DemarshalError> {
@@ -381,6 +545,47 @@ impl MessageReader {
     }
 }
 
+/// Writes a message to a unix socket, together with the file descriptors that
+/// its UnixFd values refer to.
+///
+/// The UNIX_FDS header field is set to the number of file descriptors. These are
+/// borrowed, i e, they must be kept open until the message has been written.
+#[derive(Debug, Clone)]
+pub struct MessageWriter {
+    data: Vec<u8>,
+    fds: Vec<RawFd>,
+    written: usize,
+}
+
+impl MessageWriter {
+    pub fn new(msg: &Message, serial: NonZeroU32, fds: Vec<RawFd>) -> Result<Self, DemarshalError> {
+        let mut msg = msg.clone();
+        msg.set_unix_fds(if fds.is_empty() { None } else { Some(fds.len() as u32) });
+        Ok(MessageWriter { data: msg.marshal(serial, false)?, fds, written: 0 })
+    }
+
+    /// Whether the entire message has been written.
+    pub fn is_done(&self) -> bool { self.written == self.data.len() }
+
+    /// Writes the rest of the message, retrying after partial writes and interruptions.
+    ///
+    /// For a non-blocking socket, this returns a WouldBlock error when the socket is full.
+    /// Call it again when the socket is writable to continue where it left off.
+    pub fn write_to(&mut self, sock: &UnixStream) -> io::Result<()> {
+        while !self.is_done() {
+            // The file descriptors are sent along with the first byte only.
+            let fds = if self.written == 0 { &self.fds[..] } else { &[] };
+            match fdpassing::send_with_fds(sock, &self.data[self.written..], fds) {
+                Ok(0) => Err(io::ErrorKind::WriteZero)?,
+                Ok(n) => self.written += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => Err(e)?,
+            }
+        }
+        Ok(())
+    }
+}
+
 pub fn get_hello_message() -> Message<'static> {
     use dbus_strings::StringLike;
     let path = strings::ObjectPath::new("/org/freedesktop/DBus").unwrap();
@@ -421,3 +626,343 @@ fn hello() {
         6, 1, 115, 0, 20, 0, 0, 0, 111, 114, 103, 46, 102, 114, 101, 101, 100, 101, 115, 107, 116, 111, 112, 46, 68, 66, 117, 115, 0, 0, 0, 0
     ][..]);
 }
+
+#[test]
+fn strictness_message() {
+    use dbus_strings::StringLike;
+    let lenient = ParseStrictness::lenient();
+    let strict = ParseStrictness::strict();
+    let serial = NonZeroU32::new(1).unwrap();
+
+    // Signal without interface
+    let path = strings::ObjectPath::new("/hello").unwrap();
+    let member = strings::MemberName::new("Hello").unwrap();
+    let mut v = Message::new_method_call(path.into(), member.into()).unwrap().marshal(serial, false).unwrap();
+    v[1] = SIGNAL;
+    assert!(Message::demarshal(&v).unwrap().is_some());
+    assert!(Message::demarshal_with_strictness(&v, ParseStrictness { check_signal_interface: false, ..strict }).is_ok());
+    assert!(Message::demarshal_with_strictness(&v, ParseStrictness { check_signal_interface: true, ..lenient }).is_err());
+
+    // Trailing data
+    let mut m = Message::new_method_return(serial);
+    let mut b = MultiBuf::new();
+    b.append(&5u8).unwrap();
+    m.set_body(b).unwrap();
+    let mut v = m.marshal(serial, false).unwrap();
+    v[4] = 2;
+    v.push(0);
+    assert!(Message::demarshal(&v).unwrap().is_some());
+    assert!(Message::demarshal_with_strictness(&v, ParseStrictness { check_trailing_data: false, ..strict }).is_ok());
+    assert!(Message::demarshal_with_strictness(&v, ParseStrictness { check_trailing_data: true, ..lenient }).is_err());
+
+    // Duplicate header fields
+    let mut v = vec![ENDIAN, METHOD_RETURN, 0, 1, 0, 0, 0, 0, 1, 0, 0, 0];
+    let mut arr = DictBuf::new(strings::SignatureSingle::new_unchecked_owned("y".into()),
+        strings::SignatureSingle::new_unchecked_owned("v".into())).unwrap();
+    arr.append(&5u8, &VariantBuf::new(&1u32).unwrap()).unwrap();
+    arr.append(&5u8, &VariantBuf::new(&2u32).unwrap()).unwrap();
+    crate::marshalled::Marshal::append_data_to(&arr, &mut v);
+    crate::marshalled::align_buf(&mut v, 8);
+    assert!(Message::demarshal(&v).unwrap().is_some());
+    // The header is a dictionary, so the dictionary key check catches this too.
+    assert!(Message::demarshal_with_strictness(&v, ParseStrictness { check_dict_keys: true, ..lenient }).is_err());
+    assert!(Message::demarshal_with_strictness(&v, ParseStrictness { check_header_fields: false, check_dict_keys: false, ..strict }).is_ok());
+    assert!(Message::demarshal_with_strictness(&v, ParseStrictness { check_header_fields: true, ..lenient }).is_err());
+}
+
+#[test]
+fn required_fields() {
+    use dbus_strings::StringLike;
+    let m = Message::new_internal(METHOD_RETURN);
+    assert!(m.validate_required_fields().is_err());
+    assert!(Message::new_method_return(NonZeroU32::new(1).unwrap()).validate_required_fields().is_ok());
+
+    let mut m = Message::new_internal(METHOD_CALL);
+    m.set_path(Some(strings::ObjectPath::new("/hello").unwrap().into())).unwrap();
+    assert!(m.validate_required_fields().is_err());
+
+    let path = strings::ObjectPath::new("/hello").unwrap();
+    let interface = strings::InterfaceName::new("com.example.Hello").unwrap();
+    let member = strings::MemberName::new("Hello").unwrap();
+    let m = Message::new_signal(path.into(), interface.into(), member.into()).unwrap();
+    assert!(m.validate_required_fields().is_ok());
+}
+
+#[test]
+fn header_fields_order() {
+    use dbus_strings::{StringLike, SignatureSingle};
+    let mut h = HeaderFields::new();
+    h.insert(8, VariantBuf::new(strings::SignatureMulti::new("s").unwrap()).unwrap());
+    h.insert(3, VariantBuf::new(strings::DBusStr::new("Hello").unwrap()).unwrap());
+    h.insert(5, VariantBuf::new(&1u32).unwrap());
+    h.insert(1, VariantBuf::new(strings::ObjectPath::new("/hello").unwrap()).unwrap());
+    assert!(h.insert(5, VariantBuf::new(&2u32).unwrap()).is_some());
+
+    let mut v = vec!();
+    crate::marshalled::Marshal::append_data_to(&h.to_dictbuf(), &mut v);
+    let single = Single::new(SignatureSingle::new("a{yv}").unwrap(), &v, 0, ENDIAN == b'B');
+    let dict = if let Parsed::Dict(dict) = single.parse().unwrap() { dict } else { panic!() };
+    let codes: Vec<u8> = dict.map(|e| match e.unwrap().0.parse().unwrap() {
+        Parsed::Byte(x) => x,
+        _ => panic!(),
+    }).collect();
+    assert_eq!(codes, vec![1, 3, 5, 8]);
+}
+
+#[test]
+fn body_bytes() {
+    use dbus_strings::StringLike;
+    let mut m = get_hello_message();
+    let mut b = MultiBuf::new();
+    b.append(strings::DBusStr::new("Hello").unwrap()).unwrap();
+    b.append(&5u64).unwrap();
+    m.set_body(b).unwrap();
+    let v = m.marshal(NonZeroU32::new(1).unwrap(), false).unwrap();
+    let m2 = Message::demarshal(&v).unwrap().unwrap();
+    let (sig, body, is_big_endian) = m2.body_bytes();
+    let body_len = u32::from_ne_bytes(v[4..8].try_into().unwrap()) as usize;
+    assert_eq!(body.len(), body_len);
+    assert_eq!(body, &v[v.len() - body_len..]);
+    assert_eq!(&**sig, "st");
+    assert_eq!(sig, m2.read_body().signature());
+    assert_eq!(is_big_endian, cfg!(target_endian = "big"));
+
+    let m = get_hello_message();
+    let (sig, body, _) = m.body_bytes();
+    assert_eq!((&**sig, body.len()), ("", 0));
+}
+
+#[test]
+fn header_limits() {
+    use dbus_strings::StringLike;
+    let lenient = ParseStrictness::lenient();
+    let mut v = vec![ENDIAN, METHOD_RETURN, 0, 1, 0, 0, 0, 0, 1, 0, 0, 0];
+    let mut arr = DictBuf::new(strings::SignatureSingle::new_unchecked_owned("y".into()),
+        strings::SignatureSingle::new_unchecked_owned("v".into())).unwrap();
+    arr.append(&5u8, &VariantBuf::new(&1u32).unwrap()).unwrap();
+    for _ in 0..5000 { arr.append(&200u8, &VariantBuf::new(&1u8).unwrap()).unwrap(); }
+    crate::marshalled::Marshal::append_data_to(&arr, &mut v);
+    crate::marshalled::align_buf(&mut v, 8);
+    assert!(Message::demarshal(&v).unwrap().is_some());
+    assert!(matches!(Message::demarshal_with_strictness(&v, ParseStrictness { max_header_fields: Some(100), ..lenient }).unwrap_err(),
+        DemarshalError::NumberTooBig));
+    assert!(Message::demarshal_with_strictness(&v, ParseStrictness { max_header_fields: Some(5001), ..lenient }).is_ok());
+    assert!(matches!(Message::demarshal_with_strictness(&v, ParseStrictness { max_header_size: Some(1024), ..lenient }).unwrap_err(),
+        DemarshalError::NumberTooBig));
+    assert!(Message::demarshal_with_strictness(&v, ParseStrictness { max_header_size: Some(40008), ..lenient }).is_ok());
+
+    let limited = ParseStrictness { max_header_fields: Some(8), max_header_size: Some(1024), ..ParseStrictness::strict() };
+    let hello = get_hello_message().marshal(NonZeroU32::new(1).unwrap(), false).unwrap();
+    assert!(Message::demarshal_with_strictness(&hello, limited).is_ok());
+
+    // Field code 0 is invalid
+    let mut v = vec![ENDIAN, METHOD_RETURN, 0, 1, 0, 0, 0, 0, 1, 0, 0, 0];
+    let mut arr = DictBuf::new(strings::SignatureSingle::new_unchecked_owned("y".into()),
+        strings::SignatureSingle::new_unchecked_owned("v".into())).unwrap();
+    arr.append(&0u8, &VariantBuf::new(&1u8).unwrap()).unwrap();
+    arr.append(&5u8, &VariantBuf::new(&1u32).unwrap()).unwrap();
+    crate::marshalled::Marshal::append_data_to(&arr, &mut v);
+    crate::marshalled::align_buf(&mut v, 8);
+    assert!(Message::demarshal(&v).unwrap().is_some());
+    assert!(Message::demarshal_with_strictness(&v, ParseStrictness { check_header_fields: true, ..lenient }).is_err());
+}
+
+#[test]
+fn read_reply_args() {
+    use dbus_strings::StringLike;
+    let serial = NonZeroU32::new(1).unwrap();
+    let mut m = Message::new_method_return(serial);
+    let mut b = MultiBuf::new();
+    b.append(strings::DBusStr::new("Hello").unwrap()).unwrap();
+    b.append(&7u32).unwrap();
+    m.set_body(b).unwrap();
+    let v = m.marshal(serial, false).unwrap();
+    let m = Message::demarshal(&v).unwrap().unwrap();
+
+    let (s, x) = m.read2::<&strings::DBusStr, u32>().unwrap();
+    assert_eq!((&**s, x), ("Hello", 7));
+    assert!(matches!(m.read2::<u32, &strings::DBusStr>(), Err(DemarshalError::WrongType { .. })));
+    assert!(matches!(m.read1::<&strings::DBusStr>(), Err(DemarshalError::WrongType { .. })));
+    assert!(matches!(m.read3::<&strings::DBusStr, u32, u8>(), Err(DemarshalError::WrongType { .. })));
+
+    let m = Message::new_method_return(serial);
+    assert!(matches!(m.read1::<u8>(), Err(DemarshalError::WrongType { .. })));
+}
+
+#[test]
+fn transparent_newtype() {
+    #[derive(Debug, PartialEq)]
+    struct DeviceId(u32);
+    crate::marshal_transparent!(DeviceId, u32);
+
+    let mut b1 = MultiBuf::new();
+    b1.append(&DeviceId(5)).unwrap();
+    let mut b2 = MultiBuf::new();
+    b2.append(&5u32).unwrap();
+    assert_eq!(b1.multi().signature(), b2.multi().signature());
+    assert_eq!(b1.multi().raw_data(), b2.multi().raw_data());
+
+    let serial = NonZeroU32::new(1).unwrap();
+    let mut m = Message::new_method_return(serial);
+    m.set_body(b1).unwrap();
+    let v = m.marshal(serial, false).unwrap();
+    let m = Message::demarshal(&v).unwrap().unwrap();
+    assert_eq!(m.read1::<DeviceId>().unwrap(), DeviceId(5));
+    assert_eq!(m.read1::<u32>().unwrap(), 5);
+
+    let single = b2.multi().iter().next().unwrap().unwrap();
+    let x: DeviceId = Demarshal::demarshal(&single).unwrap();
+    assert_eq!(x, DeviceId(5));
+    let x: Result<(DeviceId, u8), _> = Demarshal::demarshal(&single);
+    assert!(matches!(x, Err(DemarshalError::WrongType { .. })));
+}
+
+#[test]
+fn body_signature_field() {
+    use dbus_strings::StringLike;
+    let serial = NonZeroU32::new(1).unwrap();
+    let mut m = Message::new_method_return(serial);
+    let mut b = MultiBuf::new();
+    b.append(strings::DBusStr::new("Hello").unwrap()).unwrap();
+    b.append(&vec!(1u32, 2u32)).unwrap();
+    let body_sig = b.multi().signature().to_owned();
+    m.set_body(b).unwrap();
+    let v = m.marshal(serial, true).unwrap();
+
+    let fields = Single::new(strings::SignatureSingle::new("a{yv}").unwrap(), &v[12..], 12, ENDIAN == b'B');
+    let dict = if let Parsed::Dict(dict) = fields.parse().unwrap() { dict } else { panic!() };
+    let sig_field = dict.map(|e| e.unwrap()).find_map(|(k, v)| match (k.parse().unwrap(), v.parse().unwrap()) {
+        (Parsed::Byte(8), Parsed::Variant(v)) => Some(v.parse().unwrap()),
+        _ => None,
+    }).unwrap();
+    assert_eq!(sig_field.as_str().unwrap(), &**body_sig);
+    assert_eq!(&**m.body_bytes().0, "sau");
+
+    // An empty body has no signature field
+    m.set_body(MultiBuf::new()).unwrap();
+    assert!(m.signature.is_none());
+}
+
+#[test]
+fn set_body_other_endianness() {
+    let mut m = Message::new_method_return(NonZeroU32::new(1).unwrap());
+    m.is_big_endian = !cfg!(target_endian = "big");
+    let mut b = MultiBuf::new();
+    b.append(&5u32).unwrap();
+    m.set_body(b).unwrap();
+    assert_eq!(&*m.body, &5u32.swap_bytes().to_ne_bytes());
+
+    // Values nested too deeply cannot be converted
+    let mut v = crate::marshalled::VariantBuf::new(&5u8).unwrap();
+    for _ in 0..40 { v = crate::marshalled::VariantBuf::new(&v).unwrap(); }
+    let mut b = MultiBuf::new();
+    b.append(&v).unwrap();
+    assert!(matches!(m.set_body(b), Err(MarshalError::RecursionLimit)));
+    assert_eq!(&***m.signature.as_ref().unwrap(), "u");
+    assert_eq!(&*m.body, &5u32.swap_bytes().to_ne_bytes());
+}
+
+#[test]
+fn wrong_type_signatures() {
+    let mut m = Message::new_method_return(NonZeroU32::new(1).unwrap());
+    let mut b = MultiBuf::new();
+    b.append(&42u32).unwrap();
+    m.set_body(b).unwrap();
+    let e = m.read1::<&strings::DBusStr>().unwrap_err();
+    match &e {
+        DemarshalError::WrongType { expected: Some(expected), actual: Some(actual) } => {
+            assert_eq!(&***expected, "s");
+            assert_eq!(&***actual, "u");
+        },
+        _ => panic!("Unexpected error {:?}", e),
+    }
+    assert_eq!(e.to_string(), "wrong type: expected `s`, got `u`");
+}
+
+#[test]
+fn peer_to_peer_sender() {
+    use dbus_strings::StringLike;
+    let serial = NonZeroU32::new(1).unwrap();
+    let path = strings::ObjectPath::new("/hello").unwrap();
+    let member = strings::MemberName::new("Hello").unwrap();
+    let mut m = Message::new_method_call(path.into(), member.into()).unwrap();
+    let v = m.marshal(serial, false).unwrap();
+
+    // Peer-to-peer: no sender is fine
+    let strict = ParseStrictness::strict();
+    let m2 = Message::demarshal_with_strictness(&v, strict).unwrap().unwrap();
+    assert!(m2.sender().is_none());
+
+    // Bus: every message is expected to have a sender
+    let bus = ParseStrictness { check_sender: true, ..strict };
+    assert!(matches!(Message::demarshal_with_strictness(&v, bus), Err(DemarshalError::InvalidProtocol)));
+    m.set_sender(Some(strings::BusName::new(":1.54").unwrap().into()));
+    let v = m.marshal(serial, false).unwrap();
+    let m2 = Message::demarshal_with_strictness(&v, bus).unwrap().unwrap();
+    assert_eq!(&**m2.sender().unwrap(), ":1.54");
+}
+
+#[test]
+fn read_body_args() {
+    use dbus_strings::StringLike;
+    let serial = NonZeroU32::new(1).unwrap();
+    let path = strings::ObjectPath::new("/hello").unwrap();
+    let member = strings::MemberName::new("Hello").unwrap();
+    let mut m = Message::new_method_call(path.into(), member.into()).unwrap();
+    let v = m.marshal(serial, false).unwrap();
+    let m2 = Message::demarshal(&v).unwrap().unwrap();
+    assert_eq!(m2.read_body().iter().count(), 0);
+
+    let mut s = MultiBuf::new();
+    s.append(strings::DBusStr::new("Hi").unwrap()).unwrap();
+    s.append(&-5i32).unwrap();
+    let mut b = MultiBuf::new();
+    b.append(&crate::marshalled::StructBuf::new(s).unwrap()).unwrap();
+    m.set_body(b).unwrap();
+    let v = m.marshal(serial, false).unwrap();
+    let m2 = Message::demarshal(&v).unwrap().unwrap();
+    let body = m2.read_body();
+    assert_eq!(&**body.signature(), "(si)");
+    let mut args = body.iter();
+    let st = match args.next().unwrap().unwrap().parse().unwrap() {
+        Parsed::Struct(st) => st,
+        _ => unreachable!(),
+    };
+    assert!(args.next().is_none());
+    let fields: Vec<String> = st.to_vec().unwrap().iter().map(|x| x.to_string()).collect();
+    assert_eq!(fields, vec!(r#"s "Hi""#, "i -5"));
+}
+
+#[test]
+fn unix_fd_out_of_range() {
+    use dbus_strings::StringLike;
+    let serial = NonZeroU32::new(1).unwrap();
+    let path = strings::ObjectPath::new("/hello").unwrap();
+    let member = strings::MemberName::new("Hello").unwrap();
+    let mut m = Message::new_method_call(path.into(), member.into()).unwrap();
+    let sig = strings::SignatureMulti::new("h").unwrap();
+    let data = 5u32.to_ne_bytes();
+    m.set_body_multi(Multi::new(sig, &data, cfg!(target_endian = "big"))).unwrap();
+    m.set_unix_fds(Some(1));
+    let v = m.marshal(serial, false).unwrap();
+
+    let m2 = Message::demarshal(&v).unwrap().unwrap();
+    assert_eq!(m2.unix_fds(), Some(1));
+    let fd = m2.read_body().iter().next().unwrap().unwrap();
+    assert!(matches!(fd.parse(), Err(DemarshalError::UnixFdOutOfRange { index: 5, count: 1 })));
+
+    // Without the header field, the message has no file descriptors.
+    m.set_unix_fds(None);
+    let v = m.marshal(serial, false).unwrap();
+    let m2 = Message::demarshal(&v).unwrap().unwrap();
+    let fd = m2.read_body().iter().next().unwrap().unwrap();
+    assert!(matches!(fd.parse(), Err(DemarshalError::UnixFdOutOfRange { index: 5, count: 0 })));
+
+    let data = 0u32.to_ne_bytes();
+    m.set_body_multi(Multi::new(sig, &data, cfg!(target_endian = "big"))).unwrap();
+    m.set_unix_fds(Some(1));
+    let v = m.marshal(serial, false).unwrap();
+    let m2 = Message::demarshal(&v).unwrap().unwrap();
+    let fd = m2.read_body().iter().next().unwrap().unwrap();
+    assert!(matches!(fd.parse(), Ok(Parsed::UnixFd(0))));
+}
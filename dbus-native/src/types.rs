@@ -2,7 +2,7 @@ use std::borrow::Cow;
 use std::convert::TryInto;
 use dbus_strings as strings;
 use std::fmt;
-use strings::{SignatureSingle, StringLike};
+use strings::{SignatureSingle, SignatureMulti, SignatureMultiBuf, StringLike};
 
 use std::io::{Result as IoResult, Write, Seek, IoSlice, ErrorKind, SeekFrom};
 
@@ -38,26 +38,166 @@ pub struct DemarshalState<'a> {
     pub is_big_endian: bool,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub enum DemarshalError {
-    NotEnoughData,
+    /// The data ended prematurely.
+    NotEnoughData {
+        /// The offset where the value that could not be read starts.
+        at: usize,
+        /// The number of bytes needed (counted from "at").
+        needed: usize,
+        /// The number of bytes available (counted from "at").
+        available: usize,
+    },
     InvalidString,
     InvalidProtocol,
     InvalidBoolean,
-    WrongType,
+    /// The value has another type than the one asked for.
+    WrongType {
+        /// The signature that was asked for, if a specific type was expected.
+        expected: Option<SignatureMultiBuf>,
+        /// The signature of the value, if known.
+        actual: Option<SignatureMultiBuf>,
+    },
     NumberTooBig,
+    /// The length of an array of fixed size elements is not a multiple of the element size.
+    MisalignedArrayLength,
+    /// Containers are nested too deeply.
+    RecursionLimit,
+    /// A UnixFd value refers to a file descriptor that was not sent along with the message.
+    UnixFdOutOfRange {
+        /// The value, i e the index into the file descriptors.
+        index: u32,
+        /// The number of file descriptors.
+        count: u32,
+    },
 }
 
-impl std::error::Error for DemarshalError {
+impl DemarshalError {
+    pub (crate) fn not_enough_data(at: usize, needed: usize, available: usize) -> Self {
+        DemarshalError::NotEnoughData { at, needed, available }
+    }
 
+    pub (crate) fn wrong_type(expected: Option<&str>, actual: Option<&str>) -> Self {
+        DemarshalError::WrongType {
+            expected: expected.map(|s| SignatureMulti::new_unchecked_owned(s.into())),
+            actual: actual.map(|s| SignatureMulti::new_unchecked_owned(s.into())),
+        }
+    }
 }
 
+impl std::error::Error for DemarshalError {}
+
 impl fmt::Display for DemarshalError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        fmt::Debug::fmt(self, f)
+        match self {
+            DemarshalError::NotEnoughData { at, needed, available } =>
+                write!(f, "not enough data at offset {}: needed {} bytes, but only {} available", at, needed, available),
+            DemarshalError::InvalidString => write!(f, "invalid string"),
+            DemarshalError::InvalidProtocol => write!(f, "invalid protocol data"),
+            DemarshalError::InvalidBoolean => write!(f, "invalid boolean"),
+            DemarshalError::WrongType { expected, actual } => {
+                write!(f, "wrong type")?;
+                if let Some(e) = expected { write!(f, ": expected `{}`", e)? }
+                match (expected, actual) {
+                    (Some(_), Some(a)) => write!(f, ", got `{}`", a),
+                    (None, Some(a)) => write!(f, ": got `{}`", a),
+                    _ => Ok(()),
+                }
+            },
+            DemarshalError::NumberTooBig => write!(f, "number too big"),
+            DemarshalError::MisalignedArrayLength => write!(f, "array length is not a multiple of the element size"),
+            DemarshalError::RecursionLimit => write!(f, "containers nested too deeply"),
+            DemarshalError::UnixFdOutOfRange { index, count } =>
+                write!(f, "unix fd index {} out of range, message has {} unix fds", index, count),
+        }
+    }
+}
+
+/// Error returned when a value could not be marshalled.
+#[derive(Debug, Clone)]
+pub enum MarshalError {
+    /// The value has another type than the container expects.
+    WrongType {
+        /// The signature the container expects.
+        expected: SignatureMultiBuf,
+        /// The signature of the value.
+        actual: SignatureMultiBuf,
+    },
+    /// The signature would become longer than 255 bytes.
+    SignatureTooLong,
+    /// The array would become larger than 64 MiB.
+    ArrayTooBig,
+    /// A string, or a signature built from other signatures, is not valid.
+    InvalidString,
+    /// Containers are nested too deeply for the value to be converted to another byte order.
+    RecursionLimit,
+    /// Already marshalled data could not be read back, e g because a value appended invalid data.
+    InvalidData(DemarshalError),
+}
+
+impl MarshalError {
+    pub (crate) fn wrong_type(expected: &str, actual: &str) -> Self {
+        MarshalError::WrongType {
+            expected: SignatureMulti::new_unchecked_owned(expected.into()),
+            actual: SignatureMulti::new_unchecked_owned(actual.into()),
+        }
+    }
+}
+
+impl std::error::Error for MarshalError {}
+
+impl fmt::Display for MarshalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MarshalError::WrongType { expected, actual } => write!(f, "wrong type: expected `{}`, got `{}`", expected, actual),
+            MarshalError::SignatureTooLong => write!(f, "signature too long"),
+            MarshalError::ArrayTooBig => write!(f, "array too big"),
+            MarshalError::InvalidString => write!(f, "invalid string"),
+            MarshalError::RecursionLimit => write!(f, "containers nested too deeply"),
+            MarshalError::InvalidData(e) => write!(f, "invalid data: {}", e),
+        }
+    }
+}
+
+impl From<MarshalError> for DemarshalError {
+    fn from(e: MarshalError) -> DemarshalError {
+        match e {
+            MarshalError::WrongType { expected, actual } => DemarshalError::WrongType { expected: Some(expected), actual: Some(actual) },
+            MarshalError::SignatureTooLong | MarshalError::ArrayTooBig => DemarshalError::NumberTooBig,
+            MarshalError::InvalidString => DemarshalError::InvalidString,
+            MarshalError::RecursionLimit => DemarshalError::RecursionLimit,
+            MarshalError::InvalidData(e) => e,
+        }
     }
 }
 
+impl From<strings::InvalidStringError> for MarshalError {
+    fn from(_: strings::InvalidStringError) -> MarshalError { MarshalError::InvalidString }
+}
+
+/// A DemarshalError, together with the offset where it occurred.
+#[derive(Debug, Clone)]
+pub struct DemarshalErrorAt {
+    pub error: DemarshalError,
+    /// The offset, counted from the start of the outermost buffer (usually the message body).
+    pub offset: usize,
+}
+
+impl std::error::Error for DemarshalErrorAt {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> { Some(&self.error) }
+}
+
+impl fmt::Display for DemarshalErrorAt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (at offset {})", self.error, self.offset)
+    }
+}
+
+impl From<DemarshalErrorAt> for DemarshalError {
+    fn from(e: DemarshalErrorAt) -> DemarshalError { e.error }
+}
+
 impl From<strings::InvalidStringError> for DemarshalError {
     fn from(_: strings::InvalidStringError) -> DemarshalError { DemarshalError::InvalidString }
 }
@@ -127,14 +267,17 @@ impl<'a> DemarshalState<'a> {
     pub fn new(buf: &'a [u8], pos: usize, signature: &'a str, is_big_endian: bool) -> Self {
         DemarshalState { buf, pos, signature, is_big_endian }
     }
+    fn not_enough_data(&self, at: usize, needed: usize) -> DemarshalError {
+        DemarshalError::not_enough_data(at, needed, self.buf.len().saturating_sub(at))
+    }
     pub fn align_buf(&mut self, align: usize) -> Result<(), DemarshalError> {
         self.pos = align_up(self.pos, align);
-        if self.pos >= self.buf.len() { Err(DemarshalError::NotEnoughData) } else { Ok(()) }
+        if self.pos >= self.buf.len() { Err(self.not_enough_data(self.pos, 1)) } else { Ok(()) }
     }
     pub fn read_single(&mut self, data_len: usize, align: usize) -> Result<&[u8], DemarshalError> {
         let p = align_up(self.pos, align);
         let p2 = p + data_len;
-        if p2 > self.buf.len() { Err(DemarshalError::NotEnoughData)? };
+        if p2 > self.buf.len() { Err(self.not_enough_data(p, data_len))? };
         self.pos = p2;
         Ok(&self.buf[p..p2])
     }
@@ -144,21 +287,21 @@ impl<'a> DemarshalState<'a> {
         let x: [u8; 4] = x.try_into().unwrap();
         let z = (if self.is_big_endian { u32::from_be_bytes(x) } else { u32::from_le_bytes(x) }) as usize;
         let new_pos = self.pos + z + 1;
-        if new_pos > self.buf.len() { Err(DemarshalError::NotEnoughData)? };
+        if new_pos > self.buf.len() { Err(self.not_enough_data(self.pos, z + 1))? };
         let r = &self.buf[self.pos..self.pos+z];
         self.pos = new_pos;
         let r = std::str::from_utf8(r).map_err(|_| DemarshalError::InvalidString)?;
         Ok(r)
     }
     pub fn read_array(&mut self, el_align: usize) -> Result<DemarshalState<'a>, DemarshalError> {
-        if self.signature.as_bytes().get(0) != Some(&b'a') { Err(DemarshalError::WrongType)? };
+        if self.signature.as_bytes().get(0) != Some(&b'a') { Err(DemarshalError::wrong_type(None, Some(self.signature)))? };
         let x = self.read_single(4, 4)?;
         let x: [u8; 4] = x.try_into().unwrap();
         let arr_size = (if self.is_big_endian { u32::from_be_bytes(x) } else { u32::from_le_bytes(x) }) as usize;
         if arr_size > 67108864 { Err(DemarshalError::NumberTooBig)? };
         let arr_start = align_up(self.pos, el_align);
         let new_pos = self.pos + arr_size;
-        if new_pos > self.buf.len() { Err(DemarshalError::NotEnoughData)? };
+        if new_pos > self.buf.len() { Err(self.not_enough_data(self.pos, arr_size))? };
         self.pos = new_pos;
 
         // FIXME: This signature should be cropped better
@@ -169,7 +312,7 @@ impl<'a> DemarshalState<'a> {
         // if self.signature.as_bytes().get(0) != Some(&b'v') { Err(DemarshalError::WrongType)? };
         let z = u8::read_buf(self)? as usize;
         let new_pos = self.pos + z + 1;
-        if new_pos > self.buf.len() { Err(DemarshalError::NotEnoughData)? };
+        if new_pos > self.buf.len() { Err(self.not_enough_data(self.pos, z + 1))? };
         let r = &self.buf[self.pos..self.pos+z];
         let r = std::str::from_utf8(r).map_err(|_| DemarshalError::InvalidString)?;
         self.pos = new_pos;
@@ -253,7 +396,7 @@ impl<'a> Demarshal<'a> for &'a Signature {
     fn read_buf(b: &mut DemarshalState<'a>) -> Result<Self, DemarshalError> {
         let z = u8::read_buf(b)? as usize;
         let new_pos = b.pos + z + 1;
-        if new_pos > b.buf.len() { Err(DemarshalError::NotEnoughData)? };
+        if new_pos > b.buf.len() { Err(b.not_enough_data(b.pos, z + 1))? };
         let r = &b.buf[b.pos..b.pos+z];
         let r = std::str::from_utf8(r).map_err(|_| DemarshalError::InvalidString)?;
         b.pos = new_pos;
@@ -294,7 +437,7 @@ impl Marshal for u8 {
 impl Demarshal<'_> for u8 {
     fn read_buf(b: &mut DemarshalState<'_>) -> Result<Self, DemarshalError> {
         // if b.signature.as_bytes().get(0) != Some(&b'y') { Err(DemarshalError::WrongType)? };
-        if b.finished() { Err(DemarshalError::NotEnoughData)? };
+        if b.finished() { Err(b.not_enough_data(b.pos, 1))? };
         let r = b.buf[b.pos];
         b.pos += 1;
         // b.signature = &b.signature[1..];
@@ -365,3 +508,16 @@ impl<T: Marshal> Marshal for Variant<T> {
         self.0.write_buf(b)
     }
 }
+
+#[test]
+fn demarshal_error_display() {
+    fn parse_bool() -> Result<(), Box<dyn std::error::Error>> {
+        let sig = SignatureSingle::new("b")?;
+        crate::marshalled::Single::new(sig, &[2, 0, 0, 0], 0, false).parse()?;
+        Ok(())
+    }
+    assert_eq!(parse_bool().unwrap_err().to_string(), "invalid boolean");
+    assert_eq!(DemarshalError::NumberTooBig.to_string(), "number too big");
+    assert_eq!(DemarshalError::not_enough_data(4, 8, 2).to_string(),
+        "not enough data at offset 4: needed 8 bytes, but only 2 available");
+}
@@ -0,0 +1,83 @@
+use dbus_native as dbus;
+use dbus::{message, fdpassing, strings};
+use dbus::strings::StringLike;
+use dbus::marshalled::{Multi, Parsed};
+use std::io::{Read, Write};
+use std::num::NonZeroU32;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixStream;
+
+fn method_call(body_sig: &'static str, body: &'static [u8]) -> message::Message<'static> {
+    let path = strings::ObjectPath::new("/hello").unwrap();
+    let member = strings::MemberName::new("Hello").unwrap();
+    let mut m = message::Message::new_method_call(path.into(), member.into()).unwrap();
+    let sig = strings::SignatureMulti::new(body_sig).unwrap();
+    m.set_body_multi(Multi::new(sig, body, cfg!(target_endian = "big"))).unwrap();
+    m
+}
+
+#[test]
+fn send_fd_over_socketpair() {
+    let (a, b) = UnixStream::pair().unwrap();
+    let (x, y) = UnixStream::pair().unwrap();
+
+    static BODY: [u8; 4] = [0; 4];
+    let m = method_call("h", &BODY);
+    let mut w = message::MessageWriter::new(&m, NonZeroU32::new(1).unwrap(), vec!(x.as_raw_fd())).unwrap();
+    w.write_to(&a).unwrap();
+    assert!(w.is_done());
+    drop(x);
+
+    let mut buf = vec![0u8; 1024];
+    let mut fds = vec!();
+    let (n, truncated) = fdpassing::recv_with_fds(&b, &mut buf, &mut fds).unwrap();
+    assert!(!truncated);
+    assert_eq!(n, message::total_message_size(&buf).unwrap());
+    assert_eq!(fds.len(), 1);
+    let m2 = message::Message::demarshal(&buf[..n]).unwrap().unwrap();
+    assert_eq!(m2.unix_fds(), Some(1));
+    let index = match m2.read_body().iter().next().unwrap().unwrap().parse().unwrap() {
+        Parsed::UnixFd(index) => index,
+        _ => unreachable!(),
+    };
+
+    // The received fd is a new descriptor for the same socket as x.
+    let mut x2 = unsafe { UnixStream::from_raw_fd(fds[index]) };
+    x2.write_all(b"ping").unwrap();
+    let mut ping = [0u8; 4];
+    (&y).read_exact(&mut ping).unwrap();
+    assert_eq!(&ping, b"ping");
+}
+
+#[test]
+fn partial_writes() {
+    let (a, b) = UnixStream::pair().unwrap();
+    a.set_nonblocking(true).unwrap();
+
+    static BODY: [u8; 4 + 1048576] = {
+        let mut x = [0u8; 4 + 1048576];
+        let len = (1048576u32).to_ne_bytes();
+        x[0] = len[0]; x[1] = len[1]; x[2] = len[2]; x[3] = len[3];
+        x
+    };
+    let m = method_call("ay", &BODY);
+    let mut w = message::MessageWriter::new(&m, NonZeroU32::new(1).unwrap(), vec!()).unwrap();
+    let mut received = vec!();
+    let mut buf = vec![0u8; 65536];
+    loop {
+        match w.write_to(&a) {
+            Ok(()) => break,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {},
+            Err(e) => panic!("{}", e),
+        }
+        let n = (&b).read(&mut buf).unwrap();
+        received.extend_from_slice(&buf[..n]);
+    }
+    while received.len() < 16 || received.len() < message::total_message_size(&received).unwrap() {
+        let n = (&b).read(&mut buf).unwrap();
+        received.extend_from_slice(&buf[..n]);
+    }
+    let m2 = message::Message::demarshal(&received).unwrap().unwrap();
+    assert_eq!(m2.unix_fds(), None);
+    assert_eq!(m2.read_body().raw_data(), &BODY[..]);
+}
@@ -276,6 +276,50 @@ impl SignatureMulti {
             (SignatureSingle::new_unchecked(&self[0..x]), SignatureMulti::new_unchecked(&self[x..]))
         )
     }
+
+    /// Iterates over the single types this signature consists of.
+    pub fn iter_singles(&self) -> impl Iterator<Item = &SignatureSingle> {
+        let mut rest = self;
+        std::iter::from_fn(move || {
+            let (first, r) = rest.single()?;
+            rest = r;
+            Some(first)
+        })
+    }
+
+    /// Human readable descriptions of the single types, see SignatureSingle::describe.
+    pub fn describe(&self) -> Vec<String> {
+        self.iter_singles().map(|x| x.describe()).collect()
+    }
+}
+
+impl SignatureSingle {
+    /// A human readable description of the type, e g "dict of string to variant" for "a{sv}".
+    pub fn describe(&self) -> String {
+        let b = self.as_bytes();
+        match b[0] {
+            b'y' => "byte".into(),
+            b'b' => "boolean".into(),
+            b'n' => "int16".into(),
+            b'q' => "uint16".into(),
+            b'i' => "int32".into(),
+            b'u' => "uint32".into(),
+            b'x' => "int64".into(),
+            b't' => "uint64".into(),
+            b'd' => "double".into(),
+            b'h' => "unix fd".into(),
+            b's' => "string".into(),
+            b'o' => "object path".into(),
+            b'g' => "signature".into(),
+            b'v' => "variant".into(),
+            b'a' if b[1] == b'{' => format!("dict of {} to {}",
+                SignatureSingle::new_unchecked(&self[2..3]).describe(),
+                SignatureSingle::new_unchecked(&self[3..self.len()-1]).describe()),
+            b'a' => format!("array of {}", SignatureSingle::new_unchecked(&self[1..]).describe()),
+            b'(' => format!("struct of ({})", SignatureMulti::new_unchecked(&self[1..self.len()-1]).describe().join(", ")),
+            c => unreachable!("Unexpected byte in type signature: {}", c),
+        }
+    }
 }
 
 impl Default for &SignatureMulti {
@@ -346,3 +390,37 @@ fn sig_split() {
 
     assert!(s4.single().is_none());
 }
+
+#[test]
+fn sig_iter_singles() {
+    let s = SignatureMulti::new("a{sv}i(ss)").unwrap();
+    let v: Vec<&str> = s.iter_singles().map(|x| &**x).collect();
+    assert_eq!(v, vec!["a{sv}", "i", "(ss)"]);
+    assert_eq!(SignatureMulti::new("").unwrap().iter_singles().count(), 0);
+}
+
+#[test]
+fn hash_lookup() {
+    use std::collections::HashMap;
+    let mut map: HashMap<SignatureSingleBuf, usize> = HashMap::new();
+    map.insert(SignatureSingle::new_owned("a{sv}").unwrap(), 1);
+    map.insert(SignatureSingle::new_owned("u").unwrap(), 2);
+    assert_eq!(map.get(SignatureSingle::new("a{sv}").unwrap()), Some(&1));
+    assert_eq!(map.get(SignatureSingle::new("s").unwrap()), None);
+
+    let mut map: HashMap<MemberNameBuf, usize> = HashMap::new();
+    map.insert(MemberName::new_owned("Hello").unwrap(), 3);
+    assert_eq!(map.get(MemberName::new("Hello").unwrap()), Some(&3));
+}
+
+#[test]
+fn sig_describe() {
+    let d = |s| SignatureSingle::new(s).unwrap().describe();
+    assert_eq!(d("ai"), "array of int32");
+    assert_eq!(d("a{s(ii)}"), "dict of string to struct of (int32, int32)");
+    assert_eq!(d("v"), "variant");
+    assert_eq!(d("a{sv}"), "dict of string to variant");
+    assert_eq!(d("(oaay)"), "struct of (object path, array of array of byte)");
+    let m = SignatureMulti::new("ha{ub}").unwrap();
+    assert_eq!(m.describe(), vec!["unix fd", "dict of uint32 to boolean"]);
+}
@@ -683,3 +683,55 @@ fn test_sync_prop() {
    }
    assert_eq!(count.load(Ordering::SeqCst), 5);
 }
+
+#[test]
+fn test_set_prop_emits() {
+    use crate::{Factory, Access, EmitsChangedSignal};
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    let value = Rc::new(Cell::new(0u8));
+    let f = Factory::new_fnmut::<()>();
+    let prop = |name: &'static str, emits, auto_emit| {
+        let (v1, v2) = (value.clone(), value.clone());
+        f.property::<u8,_>(name, ())
+            .access(Access::ReadWrite)
+            .emits_changed(emits)
+            .auto_emit_on_set(auto_emit)
+            .on_get(move |i, _| { i.append(v1.get()); Ok(()) })
+            .on_set(move |i, _| { v2.set(i.get().unwrap()); Ok(()) })
+    };
+    let tree = f.tree(()).add(f.object_path("/example", ())
+        .add(f.interface("com.example.dbus.rs", ())
+            .add_p(prop("changed", EmitsChangedSignal::True, true))
+            .add_p(prop("invalidated", EmitsChangedSignal::Invalidates, true))
+            .add_p(prop("silent", EmitsChangedSignal::True, false))
+        )
+    );
+    let set = |name: &str, v: u8| {
+        let mut msg = Message::new_method_call("com.example.dbus.rs", "/example", "org.freedesktop.DBus.Properties", "Set").unwrap()
+            .append3("com.example.dbus.rs", name, arg::Variant(v));
+        msg.set_serial(40);
+        tree.handle(&msg).unwrap()
+    };
+
+    let r = set("changed", 3);
+    assert_eq!(value.get(), 3);
+    assert_eq!(r.len(), 2);
+    let (s, d, i): (&str, arg::PropMap, Vec<String>) = r[0].read3().unwrap();
+    assert_eq!(s, "com.example.dbus.rs");
+    assert_eq!(d["changed"].0.as_u64(), Some(3));
+    assert!(i.is_empty());
+
+    let r = set("invalidated", 4);
+    assert_eq!(value.get(), 4);
+    assert_eq!(r.len(), 2);
+    let (_, d, i): (&str, arg::PropMap, Vec<String>) = r[0].read3().unwrap();
+    assert!(d.is_empty());
+    assert_eq!(i, vec!["invalidated".to_string()]);
+
+    let r = set("silent", 5);
+    assert_eq!(value.get(), 5);
+    assert_eq!(r.len(), 1);
+    assert_eq!(r[0].msg_type(), dbus::message::MessageType::MethodReturn);
+}
@@ -6,7 +6,7 @@ use std::sync::Arc;
 // use std::rc::Rc;
 use std::collections::HashMap;
 
-use super::{Iter, IterAppend, ArgType};
+use super::{Iter, IterAppend, ArgType, Dict};
 
 /// Types that can represent a D-Bus message argument implement this trait.
 ///
@@ -158,6 +158,31 @@ pub fn prop_cast<'a, T: 'static>(map: &'a PropMap, key: &str) -> Option<&'a T> {
     map.get(key).and_then(|v| cast(&v.0))
 }
 
+/// Reads a dictionary of properties, as returned by e g "Properties::get_all", and lets you
+/// get each property by name, as its actual type.
+///
+/// This is useful for filling in a struct with one field per property.
+#[derive(Clone)]
+pub struct PropReader<'a>(HashMap<String, Iter<'a>>);
+
+impl<'a> PropReader<'a> {
+    /// Reads an "a{sv}" argument.
+    pub fn new(i: &mut Iter<'a>) -> Result<Self, TypeMismatchError> {
+        let d: Dict<'a, String, Variant<Iter<'a>>, Iter<'a>> = i.read()?;
+        Ok(PropReader(d.map(|(k, v)| (k, v.0)).collect()))
+    }
+
+    /// Gets the value of a property.
+    ///
+    /// Returns Ok(None) if the property does not exist, and an error if it is of a different type.
+    pub fn get<T: Arg + Get<'a>>(&self, name: &str) -> Result<Option<T>, TypeMismatchError> {
+        match self.0.get(name) {
+            None => Ok(None),
+            Some(i) => i.clone().read().map(Some),
+        }
+    }
+}
+
 /// If a type implements this trait, it means the size and alignment is the same
 /// as in D-Bus. This means that you can quickly append and get slices of this type.
 ///
@@ -589,4 +614,29 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn prop_reader() {
+        use crate::arg::PropReader;
+        #[derive(Debug, PartialEq)]
+        struct Props { name: String, count: u32, missing: Option<i64> }
+
+        let mut m = Message::new_method_call("com.example.hello", "/mooh", "com.example.hello", "Hello").unwrap();
+        let mut d: HashMap<&str, Variant<Box<dyn RefArg>>> = HashMap::new();
+        d.insert("Name", Variant(Box::new(String::from("Hello"))));
+        d.insert("Count", Variant(Box::new(5u32)));
+        m = m.append1(d);
+
+        let p = PropReader::new(&mut m.iter_init()).unwrap();
+        let props = Props {
+            name: p.get("Name").unwrap().unwrap(),
+            count: p.get("Count").unwrap().unwrap(),
+            missing: p.get("Missing").unwrap(),
+        };
+        assert_eq!(props, Props { name: "Hello".into(), count: 5, missing: None });
+        let e = p.get::<i32>("Name").unwrap_err();
+        assert_eq!((e.expected_arg_type(), e.found_arg_type()), (ArgType::Int32, ArgType::String));
+
+        assert!(PropReader::new(&mut Message::new_signal("/a", "a.b", "C").unwrap().append1(5u8).iter_init()).is_err());
+    }
 }